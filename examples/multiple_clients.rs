@@ -1,13 +1,17 @@
 use rust_vpn::error::Result;
 use rust_vpn::{
-    error::VpnError, protocol::VpnPacket, vpn_client::VpnClient, vpn_service::VpnConfig,
-    vpn_service::VpnService,
+    crypto::SecretKey, error::VpnError, protocol::VpnPacket, vpn_client::VpnClient,
+    vpn_service::VpnConfig, vpn_service::VpnService,
 };
 //use std::net::SocketAddr;
-use std::thread;
 use std::time::Duration;
 
-fn run_server(bind_addr: &str, encryption_key: [u8; 32], config: VpnConfig) -> Result<VpnService> {
+async fn run_server(
+    bind_addr: &str,
+    private_key: SecretKey,
+    allowed_peers: &[[u8; 32]],
+    config: VpnConfig,
+) -> Result<VpnService> {
     println!("\n=== SERVER STARTING ===");
     println!("Binding to address: {}", bind_addr);
     println!(
@@ -17,20 +21,21 @@ fn run_server(bind_addr: &str, encryption_key: [u8; 32], config: VpnConfig) -> R
         config.reconnect_attempts
     );
 
-    let mut vpn = VpnService::new(bind_addr, encryption_key, Some(config))?;
+    let mut vpn = VpnService::new(bind_addr, private_key, allowed_peers, Some(config)).await?;
 
     vpn.start()?;
 
     Ok(vpn)
 }
 
-fn run_client(
+async fn run_client(
     server_addr: &str,
-    encryption_key: [u8; 32],
+    private_key: SecretKey,
+    server_public_key: [u8; 32],
     config: VpnConfig,
     id: i32,
 ) -> Result<()> {
-    match std::net::TcpStream::connect(server_addr) {
+    match tokio::net::TcpStream::connect(server_addr).await {
         Ok(_) => println!("Client: Test connection successful"),
         Err(e) => {
             eprintln!("Client: Test connection failed: {}", e);
@@ -38,16 +43,17 @@ fn run_client(
         }
     }
 
-    let mut client = match VpnClient::new(server_addr, encryption_key, Some(config)) {
-        Ok(client) => {
-            println!("Client: VPN client created successfully");
-            client
-        }
-        Err(e) => {
-            eprintln!("Client: Failed to create VPN client: {:?}", e);
-            return Err(e);
-        }
-    };
+    let mut client =
+        match VpnClient::new(server_addr, private_key, server_public_key, Some(config)).await {
+            Ok(client) => {
+                println!("Client: VPN client created successfully");
+                client
+            }
+            Err(e) => {
+                eprintln!("Client: Failed to create VPN client: {:?}", e);
+                return Err(e);
+            }
+        };
 
     // Test packet
     let test_packet = VpnPacket::new_data(
@@ -56,7 +62,7 @@ fn run_client(
         b"Hello, VPN Server!".to_vec(),
     );
 
-    let res1 = match client.send_packet(test_packet) {
+    let res1 = match client.send_packet(test_packet).await {
         Ok(response) => {
             println!("Client: Received response:");
             println!("  Type: {:?}", response.packet_type);
@@ -71,14 +77,21 @@ fn run_client(
         }
     };
 
-    let res2 = client.disconnect();
+    let res2 = client.disconnect().await;
     res1.and(res2)
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let server_addr = "127.0.0.1:8080";
-    let encryption_key = [1u8; 32]; // Using a simple key for testing
+
+    // Static identities; generate and store these securely in production
+    let server_private_key = SecretKey::generate();
+    let client_private_keys: Vec<SecretKey> = (0..3).map(|_| SecretKey::generate()).collect();
+    let client_public_keys: Vec<[u8; 32]> = client_private_keys
+        .iter()
+        .map(SecretKey::public_key)
+        .collect();
 
     // Create VPN configuration
     let config = VpnConfig {
@@ -87,28 +100,53 @@ async fn main() -> Result<()> {
         reconnect_attempts: 3,
     };
 
-    // Start server in a separate thread
-    let server_addr = server_addr;
+    // Start the server, it accepts in the background
     let server_config = config.clone();
-    let mut vpn = run_server(&server_addr.to_string(), encryption_key, server_config)?;
+    let mut vpn = run_server(
+        server_addr,
+        server_private_key,
+        &client_public_keys,
+        server_config,
+    )
+    .await?;
 
     println!("Waiting for server to start...");
-    thread::sleep(Duration::from_secs(2));
-
-    // Run client
-    println!("Starting client...");
-    for i in 0..3 {
-        match run_client(server_addr, encryption_key, config.clone(), i) {
-            Ok(_) => println!("Client test {} completed successfully!", i),
-            Err(e) => eprintln!("Client error: {:?}", e),
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    // Run the clients side by side, a task each
+    println!("Starting clients...");
+    let server_public_key = vpn.public_key();
+    let clients: Vec<_> = client_private_keys
+        .into_iter()
+        .enumerate()
+        .map(|(i, client_private_key)| {
+            let config = config.clone();
+            tokio::spawn(async move {
+                let result = run_client(
+                    server_addr,
+                    client_private_key,
+                    server_public_key,
+                    config,
+                    i as i32,
+                )
+                .await;
+                (i, result)
+            })
+        })
+        .collect();
+    for client in clients {
+        match client.await {
+            Ok((i, Ok(_))) => println!("Client test {} completed successfully!", i),
+            Ok((_, Err(e))) => eprintln!("Client error: {:?}", e),
+            Err(e) => eprintln!("Client task failed: {:?}", e),
         }
     }
 
     // Wait a bit before shutting down
-    thread::sleep(Duration::from_secs(2));
+    tokio::time::sleep(Duration::from_secs(2)).await;
     println!("Shutdown");
-    vpn.shutdown()?;
-    println!("Server thread joined, exiting");
+    vpn.shutdown().await?;
+    println!("Server tasks joined, exiting");
 
     Ok(())
 }
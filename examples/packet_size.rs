@@ -1,12 +1,17 @@
 use rust_vpn::error::Result;
 use rust_vpn::{
-    protocol::VpnPacket, vpn_client::VpnClient, vpn_service::VpnConfig, vpn_service::VpnService,
+    crypto::SecretKey, protocol::VpnPacket, vpn_client::VpnClient, vpn_service::VpnConfig,
+    vpn_service::VpnService,
 };
 //use std::net::SocketAddr;
-use std::thread;
 use std::time::Duration;
 
-fn run_server(bind_addr: &str, encryption_key: [u8; 32], config: VpnConfig) -> Result<VpnService> {
+async fn run_server(
+    bind_addr: &str,
+    private_key: SecretKey,
+    allowed_peers: &[[u8; 32]],
+    config: VpnConfig,
+) -> Result<VpnService> {
     println!("\n=== SERVER STARTING ===");
     println!("Binding to address: {}", bind_addr);
     println!(
@@ -16,7 +21,7 @@ fn run_server(bind_addr: &str, encryption_key: [u8; 32], config: VpnConfig) -> R
         config.reconnect_attempts
     );
 
-    let mut vpn = VpnService::new(bind_addr, encryption_key, Some(config))?;
+    let mut vpn = VpnService::new(bind_addr, private_key, allowed_peers, Some(config)).await?;
     println!("VPN service created successfully");
 
     println!("Starting VPN service");
@@ -25,11 +30,17 @@ fn run_server(bind_addr: &str, encryption_key: [u8; 32], config: VpnConfig) -> R
     Ok(vpn)
 }
 
-fn run_client(server_addr: &str, encryption_key: [u8; 32], config: VpnConfig) -> Result<()> {
+async fn run_client(
+    server_addr: &str,
+    private_key: SecretKey,
+    server_public_key: [u8; 32],
+    config: VpnConfig,
+) -> Result<()> {
     println!("\n=== CLIENT STARTING ===");
     println!("Connecting to server: {}", server_addr);
 
-    let mut client = VpnClient::new(server_addr, encryption_key, Some(config))?;
+    let mut client =
+        VpnClient::new(server_addr, private_key, server_public_key, Some(config)).await?;
     println!("VPN client created successfully");
 
     // Test each packet size
@@ -41,7 +52,7 @@ fn run_client(server_addr: &str, encryption_key: [u8; 32], config: VpnConfig) ->
             &packet.payload[..20.min(packet.payload.len())]
         );
 
-        match (client.send_packet(packet), should_suceed) {
+        match (client.send_packet(packet).await, should_suceed) {
             (Ok(response), true) => {
                 println!("\nReceived {} response:", size_desc);
                 println!("Response size: {} bytes", response.payload.len());
@@ -70,7 +81,7 @@ fn run_client(server_addr: &str, encryption_key: [u8; 32], config: VpnConfig) ->
         }
 
         // Short delay between packets
-        thread::sleep(Duration::from_millis(500));
+        tokio::time::sleep(Duration::from_millis(500)).await;
     }
 
     println!("\nAll packet tests completed");
@@ -105,16 +116,21 @@ fn create_test_packets() -> Vec<(String, VpnPacket, bool)> {
             VpnPacket::new_data(
                 [192, 168, 1, 1],
                 [192, 168, 1, 2],
-                vec![b'L'; 64 * 1024], // 64KB of 'L' characters
+                vec![b'L'; 64 * 1024], // 64KB of 'L' characters, sent in fragments
             ),
-            false,
+            true,
         ),
     ]
 }
 
-fn main() -> Result<()> {
+#[tokio::main]
+async fn main() -> Result<()> {
     let server_addr = "127.0.0.1:8080";
-    let encryption_key = [1u8; 32]; // Using a simple key for testing
+
+    // Static identities; generate and store these securely in production
+    let server_private_key = SecretKey::generate();
+    let client_private_key = SecretKey::generate();
+    let client_public_key = client_private_key.public_key();
 
     // Create VPN configuration
     let config = VpnConfig {
@@ -123,26 +139,31 @@ fn main() -> Result<()> {
         reconnect_attempts: 3,
     };
 
-    // Start server in a separate thread
-    let server_addr = server_addr;
+    // Start the server, it accepts in the background
     let server_config = config.clone();
-    let mut vpn = run_server(&server_addr.to_string(), encryption_key, server_config)?;
+    let mut vpn = run_server(
+        server_addr,
+        server_private_key,
+        &[client_public_key],
+        server_config,
+    )
+    .await?;
 
     // Give the server time to start
     println!("Waiting for server to initialize (2s)...");
-    thread::sleep(Duration::from_secs(2));
+    tokio::time::sleep(Duration::from_secs(2)).await;
 
     // Run client test
     println!("Starting client test...");
-    match run_client(server_addr, encryption_key, config) {
+    match run_client(server_addr, client_private_key, vpn.public_key(), config).await {
         Ok(_) => println!("\nAll packet size tests completed successfully!"),
         Err(e) => eprintln!("\nPacket size tests failed: {:?}", e),
     }
 
     // Clean up
     println!("\nTest complete, cleaning up...");
-    vpn.shutdown()?;
-    println!("Server thread joined, exiting");
+    vpn.shutdown().await?;
+    println!("Server tasks joined, exiting");
 
     Ok(())
 }
@@ -0,0 +1,52 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_vpn::protocol::{
+    ConfigRequest, ControlPayload, ControlType, Disconnect, ErrorMessage, HandshakeOffer,
+    HandshakeReply, MtuProbe, MtuProbeAck, MtuUpdate, Ping, Pong, Rekey, VpnPacket,
+};
+use rust_vpn::vpn_service::{RouteUpdate, RouteUpdateAck, VpnConfig};
+use std::fmt::Debug;
+
+// Whatever decodes must survive encoding and decoding again unchanged
+fn round_trip<T: ControlPayload + PartialEq + Debug>(bytes: &[u8]) {
+    if let Ok(message) = T::decode(bytes) {
+        let encoded = message.encode().expect("Decoded message encodes");
+        assert_eq!(T::decode(&encoded).unwrap(), message);
+    }
+}
+
+// Payloads that arrive from peers, all of which must reject garbage cleanly.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(offer) = HandshakeOffer::from_bytes(data) {
+        assert_eq!(
+            HandshakeOffer::from_bytes(&offer.to_bytes().expect("Decoded offer encodes")).unwrap(),
+            offer
+        );
+    }
+    if let Ok(reply) = HandshakeReply::from_bytes(data) {
+        assert_eq!(
+            HandshakeReply::from_bytes(&reply.to_bytes().expect("Decoded reply encodes")).unwrap(),
+            reply
+        );
+    }
+
+    round_trip::<ConfigRequest>(data);
+    round_trip::<RouteUpdate>(data);
+    round_trip::<RouteUpdateAck>(data);
+    round_trip::<Disconnect>(data);
+    round_trip::<Rekey>(data);
+    round_trip::<MtuProbe>(data);
+    round_trip::<MtuProbeAck>(data);
+    round_trip::<MtuUpdate>(data);
+    round_trip::<Ping>(data);
+    round_trip::<Pong>(data);
+    // Not comparable, only decoding matters
+    let _ = VpnConfig::decode(data);
+    // Reasons beyond what encoding keeps are cut short
+    let _ = ErrorMessage::decode(data);
+
+    let mut packet = VpnPacket::new_control(ControlType::MtuUpdate);
+    packet.set_payload(data.to_vec());
+    let _ = packet.transaction_id();
+});
@@ -0,0 +1,20 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_vpn::protocol::{PacketCodec, PacketView, VpnPacket};
+
+// Decoding must never panic, and whatever decodes must encode back to the
+// exact same bytes.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(view) = PacketView::decode_from(data) {
+        let mut encoded = Vec::new();
+        view.encode_into(&mut encoded)
+            .expect("Decoded packet must encode");
+        assert_eq!(encoded, data);
+
+        let owned = VpnPacket::decode_owned(data.to_vec()).expect("Same bytes decode");
+        assert_eq!(owned, view.to_packet());
+    }
+
+    let _ = VpnPacket::from_bytes(data);
+});
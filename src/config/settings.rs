@@ -1,9 +1,12 @@
+use crate::crypto::{keys, SecretKey};
 use crate::error::Result;
 use crate::VpnError;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
+use zeroize::Zeroize;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct VpnConfig {
@@ -16,12 +19,59 @@ pub struct VpnConfig {
     pub reconnect_attempts: u8,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Serialize, Deserialize)]
 pub struct PeerConfig {
     pub public_key: String,
     pub allowed_ips: Vec<String>,
-    pub endpoint: SocketAddr,
+    // Servers don't know where their clients connect from
+    #[serde(default)]
+    pub endpoint: Option<SocketAddr>,
     pub persistent_keepalive: Option<u64>,
+    /// Optional base64 pre-shared key mixed into the handshake.
+    #[serde(default)]
+    pub preshared_key: Option<String>,
+}
+
+impl PeerConfig {
+    /// Loads a JSON list of peers.
+    pub fn load_all(path: impl AsRef<Path>) -> Result<Vec<Self>> {
+        let file = std::fs::File::open(path)?;
+        let peers = serde_json::from_reader(file).map_err(|e| VpnError::Config(e.to_string()))?;
+        Ok(peers)
+    }
+
+    /// Decodes the base64 `public_key` into the raw static key used by the handshake.
+    pub fn public_key_bytes(&self) -> Result<[u8; 32]> {
+        keys::decode_key(&self.public_key)
+    }
+
+    pub fn preshared_key_bytes(&self) -> Result<Option<SecretKey>> {
+        self.preshared_key
+            .as_deref()
+            .map(keys::decode_secret_key)
+            .transpose()
+    }
+}
+
+impl fmt::Debug for PeerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeerConfig")
+            .field("public_key", &self.public_key)
+            .field("allowed_ips", &self.allowed_ips)
+            .field("endpoint", &self.endpoint)
+            .field("persistent_keepalive", &self.persistent_keepalive)
+            .field(
+                "preshared_key",
+                &self.preshared_key.as_ref().map(|_| "[REDACTED]"),
+            )
+            .finish()
+    }
+}
+
+impl Drop for PeerConfig {
+    fn drop(&mut self) {
+        self.preshared_key.zeroize();
+    }
 }
 
 impl VpnConfig {
@@ -36,3 +86,24 @@ impl VpnConfig {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_debug_redacts_preshared_key() {
+        let peer = PeerConfig {
+            public_key: keys::encode_key(&[1u8; 32]),
+            allowed_ips: vec![],
+            endpoint: None,
+            persistent_keepalive: None,
+            preshared_key: Some(keys::encode_key(&[2u8; 32])),
+        };
+
+        let debug = format!("{:?}", peer);
+        assert!(debug.contains("[REDACTED]"));
+        assert!(!debug.contains(peer.preshared_key.as_deref().unwrap()));
+        assert!(peer.preshared_key_bytes().unwrap().is_some());
+    }
+}
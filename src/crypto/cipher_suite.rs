@@ -0,0 +1,94 @@
+use crate::error::VpnError;
+use std::convert::TryFrom;
+
+/// AEAD used for transport packets, negotiated during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CipherSuite {
+    Aes256Gcm = 1,
+    ChaCha20Poly1305 = 2,
+    XChaCha20Poly1305 = 3,
+}
+
+impl TryFrom<u8> for CipherSuite {
+    type Error = VpnError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(CipherSuite::Aes256Gcm),
+            2 => Ok(CipherSuite::ChaCha20Poly1305),
+            3 => Ok(CipherSuite::XChaCha20Poly1305),
+            _ => Err(VpnError::Protocol(format!(
+                "Invalid cipher suite: {}",
+                value
+            ))),
+        }
+    }
+}
+
+impl CipherSuite {
+    pub const ALL: [CipherSuite; 3] = [
+        CipherSuite::Aes256Gcm,
+        CipherSuite::ChaCha20Poly1305,
+        CipherSuite::XChaCha20Poly1305,
+    ];
+
+    /// Bytes encryption adds to a payload: the 16 byte tag every suite
+    /// appends, and for XChaCha20 the random nonce prefix it sends in front.
+    pub fn overhead(self) -> usize {
+        match self {
+            CipherSuite::XChaCha20Poly1305 => 32,
+            CipherSuite::Aes256Gcm | CipherSuite::ChaCha20Poly1305 => 16,
+        }
+    }
+
+    /// All suites in the order this machine runs them fastest.
+    ///
+    /// AES-GCM is only preferred when the CPU has AES instructions.
+    pub fn preferred() -> Vec<CipherSuite> {
+        if has_aes_instructions() {
+            Self::ALL.to_vec()
+        } else {
+            vec![
+                CipherSuite::ChaCha20Poly1305,
+                CipherSuite::XChaCha20Poly1305,
+                CipherSuite::Aes256Gcm,
+            ]
+        }
+    }
+
+    /// Picks the first suite in the peer's preference list that we allow.
+    pub fn negotiate(offered: &[CipherSuite], allowed: &[CipherSuite]) -> Option<CipherSuite> {
+        offered
+            .iter()
+            .copied()
+            .find(|suite| allowed.contains(suite))
+    }
+
+    pub fn encode_list(suites: &[CipherSuite]) -> Vec<u8> {
+        suites.iter().map(|suite| *suite as u8).collect()
+    }
+
+    /// Decodes an offer, skipping suites this build doesn't know.
+    pub fn decode_list(bytes: &[u8]) -> Vec<CipherSuite> {
+        bytes
+            .iter()
+            .filter_map(|byte| CipherSuite::try_from(*byte).ok())
+            .collect()
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn has_aes_instructions() -> bool {
+    std::arch::is_x86_feature_detected!("aes") && std::arch::is_x86_feature_detected!("pclmulqdq")
+}
+
+#[cfg(target_arch = "aarch64")]
+fn has_aes_instructions() -> bool {
+    std::arch::is_aarch64_feature_detected!("aes")
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn has_aes_instructions() -> bool {
+    false
+}
@@ -1,62 +1,210 @@
+use crate::crypto::replay::{ReplayWindow, REJECT_AFTER_MESSAGES};
+use crate::crypto::CipherSuite;
 use crate::error::VpnError;
 
 use aes_gcm::{
-    aead::{Aead, KeyInit},
-    Aes256Gcm, Nonce,
+    aead::{generic_array::GenericArray, Aead, AeadCore, KeyInit, Payload},
+    Aes256Gcm,
 };
+use chacha20poly1305::{ChaCha20Poly1305, XChaCha20Poly1305};
 use rand::Rng;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+// Random prefix XChaCha20 adds in front of the counter in its 24-byte nonce
+const EXTENDED_NONCE_PREFIX_LEN: usize = 16;
+
+// The AEAD types wipe their key schedules on drop
+enum Cipher {
+    // Boxed: the expanded AES key schedule dwarfs the ChaCha keys
+    Aes256Gcm(Box<Aes256Gcm>),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+    XChaCha20Poly1305(XChaCha20Poly1305),
+}
 
+// Clones share the cipher, counter and replay window, so a session never
+// reuses a nonce or copies its key no matter how many threads hold one.
 #[derive(Clone)]
 pub struct EncryptionManager {
-    cipher: Aes256Gcm,
+    cipher: Arc<Cipher>,
+    send_counter: Arc<AtomicU64>,
+    replay_window: Arc<Mutex<ReplayWindow>>,
 }
 
 impl EncryptionManager {
-    pub fn new(key: &[u8; 32]) -> Self {
-        let cipher = Aes256Gcm::new_from_slice(key).expect("Invalid key length");
-        Self { cipher }
+    pub fn new(suite: CipherSuite, key: &[u8; 32]) -> Self {
+        let cipher = match suite {
+            CipherSuite::Aes256Gcm => Cipher::Aes256Gcm(Box::new(Aes256Gcm::new(key.into()))),
+            CipherSuite::ChaCha20Poly1305 => {
+                Cipher::ChaCha20Poly1305(ChaCha20Poly1305::new(key.into()))
+            }
+            CipherSuite::XChaCha20Poly1305 => {
+                Cipher::XChaCha20Poly1305(XChaCha20Poly1305::new(key.into()))
+            }
+        };
+
+        Self {
+            cipher: Arc::new(cipher),
+            send_counter: Arc::new(AtomicU64::new(0)),
+            replay_window: Arc::new(Mutex::new(ReplayWindow::new())),
+        }
     }
 
-    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, VpnError> {
-        let mut rng = rand::thread_rng();
-        let mut nonce_bytes = [0u8; 12];
-        rng.fill(&mut nonce_bytes);
-        let nonce = Nonce::from_slice(&nonce_bytes);
+    /// Reserves the next send counter, which doubles as the packet nonce.
+    pub fn next_counter(&self) -> Result<u64, VpnError> {
+        let counter = self.send_counter.fetch_add(1, Ordering::Relaxed);
+        if counter >= REJECT_AFTER_MESSAGES {
+            return Err(VpnError::Encryption("Send counter exhausted".into()));
+        }
+        Ok(counter)
+    }
 
-        let ciphertext = self
-            .cipher
-            .encrypt(nonce, data)
-            .map_err(|e| VpnError::Encryption(e.to_string()))?;
+    /// Encrypts `data` under `counter`, authenticating `aad` alongside it.
+    pub fn encrypt(&self, counter: u64, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, VpnError> {
+        let payload = Payload { msg: data, aad };
 
-        // Prepend nonce to ciphertext
-        let mut result = Vec::with_capacity(nonce_bytes.len() + ciphertext.len());
-        result.extend_from_slice(&nonce_bytes);
-        result.extend_from_slice(&ciphertext);
+        match self.cipher.as_ref() {
+            Cipher::Aes256Gcm(cipher) => seal(cipher.as_ref(), &counter_nonce(counter), payload),
+            Cipher::ChaCha20Poly1305(cipher) => seal(cipher, &counter_nonce(counter), payload),
+            Cipher::XChaCha20Poly1305(cipher) => {
+                // Prepend the random nonce prefix to the ciphertext
+                let mut prefix = [0u8; EXTENDED_NONCE_PREFIX_LEN];
+                rand::thread_rng().fill(&mut prefix);
 
-        Ok(result)
+                let mut result = prefix.to_vec();
+                result.extend_from_slice(&seal(
+                    cipher,
+                    &extended_nonce(&prefix, counter),
+                    payload,
+                )?);
+                Ok(result)
+            }
+        }
     }
 
-    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, VpnError> {
-        println!("Decrypting data of length: {}", data.len());
-        if data.len() < 12 {
-            return Err("Data too short".into());
+    pub fn decrypt(&self, counter: u64, aad: &[u8], data: &[u8]) -> Result<Vec<u8>, VpnError> {
+        // Hold the window across decryption so concurrent duplicates can't both pass
+        let mut replay_window = self.replay_window.lock().expect("Replay window in use");
+        let fresh = replay_window.check(counter);
+
+        // Authenticate even a stale counter, so only genuine replays count as such
+        let plaintext = match self.cipher.as_ref() {
+            Cipher::Aes256Gcm(cipher) => open(
+                cipher.as_ref(),
+                &counter_nonce(counter),
+                Payload { msg: data, aad },
+            )?,
+            Cipher::ChaCha20Poly1305(cipher) => {
+                open(cipher, &counter_nonce(counter), Payload { msg: data, aad })?
+            }
+            Cipher::XChaCha20Poly1305(cipher) => {
+                if data.len() < EXTENDED_NONCE_PREFIX_LEN {
+                    return Err("Data too short".into());
+                }
+                let (prefix, ciphertext) = data.split_at(EXTENDED_NONCE_PREFIX_LEN);
+                open(
+                    cipher,
+                    &extended_nonce(prefix, counter),
+                    Payload {
+                        msg: ciphertext,
+                        aad,
+                    },
+                )?
+            }
+        };
+
+        if !fresh {
+            return Err(VpnError::ReplayedPacket(counter));
         }
+        // Only authenticated packets may advance the window
+        replay_window.update(counter);
 
-        let (nonce_bytes, ciphertext) = data.split_at(12);
-        let nonce = Nonce::from_slice(nonce_bytes);
+        Ok(plaintext)
+    }
+}
 
-        let plaintext = self
-            .cipher
-            .decrypt(nonce, ciphertext)
-            .map_err(|e| VpnError::Encryption(e.to_string()))?;
+// 32 zero bits followed by the little-endian counter
+fn counter_nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
 
-        if !plaintext.is_empty() {
-            println!(
-                "First few bytes of plaintext: {:02x?}",
-                &plaintext[..std::cmp::min(4, plaintext.len())]
+fn extended_nonce(prefix: &[u8], counter: u64) -> [u8; 24] {
+    let mut nonce = [0u8; 24];
+    nonce[..EXTENDED_NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[EXTENDED_NONCE_PREFIX_LEN..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+fn seal<A: Aead + AeadCore>(
+    cipher: &A,
+    nonce: &[u8],
+    payload: Payload,
+) -> Result<Vec<u8>, VpnError> {
+    cipher
+        .encrypt(GenericArray::from_slice(nonce), payload)
+        .map_err(|e| VpnError::Encryption(e.to_string()))
+}
+
+fn open<A: Aead + AeadCore>(
+    cipher: &A,
+    nonce: &[u8],
+    payload: Payload,
+) -> Result<Vec<u8>, VpnError> {
+    cipher
+        .decrypt(GenericArray::from_slice(nonce), payload)
+        .map_err(|e| VpnError::Encryption(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_suite_round_trips() {
+        for suite in CipherSuite::ALL {
+            let sender = EncryptionManager::new(suite, &[7; 32]);
+            let receiver = EncryptionManager::new(suite, &[7; 32]);
+
+            let counter = sender.next_counter().unwrap();
+            let encrypted = sender.encrypt(counter, b"header", b"payload").unwrap();
+            assert_eq!(encrypted.len(), b"payload".len() + suite.overhead());
+            assert_eq!(
+                receiver.decrypt(counter, b"header", &encrypted).unwrap(),
+                b"payload"
             );
+            assert!(matches!(
+                receiver.decrypt(counter, b"header", &encrypted),
+                Err(VpnError::ReplayedPacket(0))
+            ));
         }
+    }
 
-        Ok(plaintext)
+    #[test]
+    fn test_suites_do_not_interoperate() {
+        let sender = EncryptionManager::new(CipherSuite::Aes256Gcm, &[7; 32]);
+        let receiver = EncryptionManager::new(CipherSuite::ChaCha20Poly1305, &[7; 32]);
+
+        let encrypted = sender.encrypt(0, &[], b"payload").unwrap();
+        assert!(receiver.decrypt(0, &[], &encrypted).is_err());
+    }
+
+    #[test]
+    fn test_tampered_aad_rejected() {
+        let sender = EncryptionManager::new(CipherSuite::Aes256Gcm, &[7; 32]);
+        let receiver = EncryptionManager::new(CipherSuite::Aes256Gcm, &[7; 32]);
+
+        let encrypted = sender.encrypt(0, b"header", b"payload").unwrap();
+        assert!(receiver.decrypt(0, b"HEADER", &encrypted).is_err());
+        // A forged packet must not burn the counter
+        assert!(receiver.decrypt(0, b"header", &encrypted).is_ok());
+        // Nor pass for a replay once it is burnt
+        assert!(matches!(
+            receiver.decrypt(0, b"HEADER", &encrypted),
+            Err(VpnError::Encryption(_))
+        ));
     }
 }
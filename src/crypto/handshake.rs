@@ -0,0 +1,473 @@
+// Noise IKpsk2 handshake (Noise_IKpsk2_25519_AESGCM_SHA256)
+//
+//   <- s
+//   ...
+//   -> e, es, s, ss
+//   <- e, ee, se, psk
+//
+// Peers without a pre-shared key use 32 zero bytes, as WireGuard does. The
+// initiation payload starts with a TAI64N timestamp, so a responder can tell
+// a replayed initiation from a fresh one.
+use crate::crypto::{KeyExchange, SecretKey, SessionKeys};
+use crate::error::{Result, VpnError};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zeroize::{Zeroize, Zeroizing};
+
+const PROTOCOL_NAME: &[u8] = b"Noise_IKpsk2_25519_AESGCM_SHA256";
+const PROLOGUE: &[u8] = b"rust_vpn v1";
+const REKEY_LABEL: &[u8] = b"rust_vpn rekey";
+
+const KEY_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+/// Size of a TAI64N timestamp: seconds since 1970 offset by 2^62, then nanoseconds.
+pub const TIMESTAMP_LEN: usize = 12;
+const TAI64_EPOCH: u64 = 1 << 62;
+
+/// Size of the initiation message without payload: e, encrypted s and the
+/// timestamp.
+pub const INITIATION_LEN: usize = KEY_LEN + KEY_LEN + TAG_LEN + TIMESTAMP_LEN;
+/// Size of the response message without payload: e.
+pub const RESPONSE_LEN: usize = KEY_LEN;
+
+pub struct Handshake {
+    chaining_key: [u8; 32],
+    hash: [u8; 32],
+    cipher_key: Option<[u8; 32]>,
+    nonce: u64,
+    initiator: bool,
+    local_static: KeyExchange,
+    local_ephemeral: Option<KeyExchange>,
+    remote_static: Option<[u8; 32]>,
+    remote_ephemeral: Option<[u8; 32]>,
+    remote_timestamp: Option<[u8; TIMESTAMP_LEN]>,
+    preshared_key: [u8; 32],
+}
+
+impl Handshake {
+    /// Starts a handshake towards a responder whose static key is known.
+    pub fn new_initiator(local_static: KeyExchange, remote_static: [u8; 32]) -> Self {
+        let mut handshake = Self::initialize(local_static, true);
+        handshake.mix_hash(&remote_static);
+        handshake.remote_static = Some(remote_static);
+        handshake
+    }
+
+    /// Prepares to answer an initiation sent to `local_static`.
+    pub fn new_responder(local_static: KeyExchange) -> Self {
+        let mut handshake = Self::initialize(local_static, false);
+        let own_public = handshake.local_static.public_key_bytes();
+        handshake.mix_hash(&own_public);
+        handshake
+    }
+
+    fn initialize(local_static: KeyExchange, initiator: bool) -> Self {
+        // Protocol names up to 32 bytes are used directly, zero padded
+        let mut hash = [0u8; 32];
+        hash[..PROTOCOL_NAME.len()].copy_from_slice(PROTOCOL_NAME);
+
+        let mut handshake = Self {
+            chaining_key: hash,
+            hash,
+            cipher_key: None,
+            nonce: 0,
+            initiator,
+            local_static,
+            local_ephemeral: None,
+            remote_static: None,
+            remote_ephemeral: None,
+            remote_timestamp: None,
+            preshared_key: [0u8; 32],
+        };
+        handshake.mix_hash(PROLOGUE);
+        handshake
+    }
+
+    /// Mixes `preshared_key` into the session keys. Both sides must set the
+    /// same key before the response is written or read.
+    pub fn set_preshared_key(&mut self, preshared_key: &SecretKey) {
+        self.preshared_key = *preshared_key.as_bytes();
+    }
+
+    /// Static public key of the peer, known once the initiation is read.
+    pub fn remote_static(&self) -> Option<[u8; 32]> {
+        self.remote_static
+    }
+
+    /// When the peer wrote its initiation, known once the initiation is read.
+    /// Timestamps compare in time order, so a responder should refuse any not
+    /// newer than the last one it accepted from the same static key.
+    pub fn remote_timestamp(&self) -> Option<[u8; TIMESTAMP_LEN]> {
+        self.remote_timestamp
+    }
+
+    pub fn write_initiation(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        let remote_static = self.expect_remote_static()?;
+        let mut message = Vec::with_capacity(INITIATION_LEN + payload.len() + TAG_LEN);
+        let mut plaintext = Vec::with_capacity(TIMESTAMP_LEN + payload.len());
+        plaintext.extend_from_slice(&timestamp_now());
+        plaintext.extend_from_slice(payload);
+
+        // e
+        let ephemeral = KeyExchange::new();
+        let ephemeral_public = ephemeral.public_key_bytes();
+        self.mix_hash(&ephemeral_public);
+        message.extend_from_slice(&ephemeral_public);
+
+        // es
+        self.mix_key(&*ephemeral.diffie_hellman(&remote_static)?)?;
+        self.local_ephemeral = Some(ephemeral);
+
+        // s
+        let static_public = self.local_static.public_key_bytes();
+        message.extend_from_slice(&self.encrypt_and_hash(&static_public)?);
+
+        // ss
+        self.mix_key(&*self.local_static.diffie_hellman(&remote_static)?)?;
+
+        message.extend_from_slice(&self.encrypt_and_hash(&plaintext)?);
+        Ok(message)
+    }
+
+    pub fn read_initiation(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+        if self.initiator {
+            return Err(VpnError::KeyExchange(
+                "Initiator cannot read an initiation".into(),
+            ));
+        }
+        if message.len() < INITIATION_LEN + TAG_LEN {
+            return Err(VpnError::KeyExchange(
+                "Handshake initiation too short".into(),
+            ));
+        }
+
+        // e
+        let remote_ephemeral = read_key(&message[..KEY_LEN]);
+        self.mix_hash(&remote_ephemeral);
+        self.remote_ephemeral = Some(remote_ephemeral);
+
+        // es
+        self.mix_key(&*self.local_static.diffie_hellman(&remote_ephemeral)?)?;
+
+        // s
+        let static_end = KEY_LEN + KEY_LEN + TAG_LEN;
+        let remote_static = read_key(&self.decrypt_and_hash(&message[KEY_LEN..static_end])?);
+        self.remote_static = Some(remote_static);
+
+        // ss
+        self.mix_key(&*self.local_static.diffie_hellman(&remote_static)?)?;
+
+        let mut payload = self.decrypt_and_hash(&message[static_end..])?;
+        let mut timestamp = [0u8; TIMESTAMP_LEN];
+        timestamp.copy_from_slice(&payload[..TIMESTAMP_LEN]);
+        self.remote_timestamp = Some(timestamp);
+        Ok(payload.split_off(TIMESTAMP_LEN))
+    }
+
+    pub fn write_response(&mut self, payload: &[u8]) -> Result<Vec<u8>> {
+        let remote_ephemeral = self.remote_ephemeral.ok_or_else(|| {
+            VpnError::KeyExchange("Response written before initiation was read".into())
+        })?;
+        let remote_static = self.expect_remote_static()?;
+        let mut message = Vec::with_capacity(RESPONSE_LEN + payload.len() + TAG_LEN);
+
+        // e
+        let ephemeral = KeyExchange::new();
+        let ephemeral_public = ephemeral.public_key_bytes();
+        self.mix_hash(&ephemeral_public);
+        message.extend_from_slice(&ephemeral_public);
+
+        // ee
+        self.mix_key(&*ephemeral.diffie_hellman(&remote_ephemeral)?)?;
+
+        // se
+        self.mix_key(&*ephemeral.diffie_hellman(&remote_static)?)?;
+        self.local_ephemeral = Some(ephemeral);
+
+        // psk
+        self.mix_preshared_key()?;
+
+        message.extend_from_slice(&self.encrypt_and_hash(payload)?);
+        Ok(message)
+    }
+
+    pub fn read_response(&mut self, message: &[u8]) -> Result<Vec<u8>> {
+        let ephemeral = self.local_ephemeral.take().ok_or_else(|| {
+            VpnError::KeyExchange("Response read before initiation was written".into())
+        })?;
+        if message.len() < RESPONSE_LEN + TAG_LEN {
+            return Err(VpnError::KeyExchange("Handshake response too short".into()));
+        }
+
+        // e
+        let remote_ephemeral = read_key(&message[..KEY_LEN]);
+        self.mix_hash(&remote_ephemeral);
+        self.remote_ephemeral = Some(remote_ephemeral);
+
+        // ee
+        self.mix_key(&*ephemeral.diffie_hellman(&remote_ephemeral)?)?;
+
+        // se
+        self.mix_key(&*self.local_static.diffie_hellman(&remote_ephemeral)?)?;
+
+        // psk
+        self.mix_preshared_key()?;
+
+        // A mismatched pre-shared key surfaces here, as a failed response
+        self.decrypt_and_hash(&message[RESPONSE_LEN..])
+            .map_err(|_| {
+                VpnError::KeyExchange(
+                    "Handshake response failed to authenticate, check the pre-shared key".into(),
+                )
+            })
+    }
+
+    /// Mixes the ML-KEM secret of a hybrid handshake into the chaining key.
+    /// Both sides call this once the response is done, before splitting.
+    pub fn mix_kem_secret(&mut self, shared_secret: &[u8; 32]) -> Result<()> {
+        self.mix_key(shared_secret)
+    }
+
+    /// Splits the final chaining key into the transport keys.
+    pub fn into_session_keys(self) -> Result<SessionKeys> {
+        let (initiator_key, responder_key) = hkdf(&self.chaining_key, &[])?;
+        let (rekey_secret, _) = hkdf(&self.chaining_key, REKEY_LABEL)?;
+
+        Ok(SessionKeys::from_split(
+            initiator_key,
+            responder_key,
+            rekey_secret,
+            self.initiator,
+        ))
+    }
+
+    fn expect_remote_static(&self) -> Result<[u8; 32]> {
+        self.remote_static
+            .ok_or_else(|| VpnError::KeyExchange("Remote static key unknown".into()))
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        let mut hasher = Sha256::new();
+        hasher.update(self.hash);
+        hasher.update(data);
+        self.hash = hasher.finalize().into();
+    }
+
+    fn mix_key(&mut self, input_key_material: &[u8; 32]) -> Result<()> {
+        let (chaining_key, cipher_key) = hkdf(&self.chaining_key, input_key_material)?;
+        self.chaining_key = chaining_key;
+        self.cipher_key = Some(cipher_key);
+        self.nonce = 0;
+        Ok(())
+    }
+
+    fn mix_preshared_key(&mut self) -> Result<()> {
+        let mut output = Zeroizing::new([0u8; 96]);
+        Hkdf::<Sha256>::new(Some(&self.chaining_key), &self.preshared_key)
+            .expand(&[], output.as_mut())
+            .map_err(|e| VpnError::KeyExchange(e.to_string()))?;
+
+        self.chaining_key = read_key(&output[..32]);
+        self.mix_hash(&output[32..64]);
+        self.cipher_key = Some(read_key(&output[64..]));
+        self.nonce = 0;
+        Ok(())
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let cipher = self.handshake_cipher()?;
+        let ciphertext = cipher
+            .encrypt(
+                &self.next_nonce(),
+                Payload {
+                    msg: plaintext,
+                    aad: &self.hash,
+                },
+            )
+            .map_err(|_| VpnError::KeyExchange("Handshake encryption failed".into()))?;
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        let cipher = self.handshake_cipher()?;
+        let plaintext = cipher
+            .decrypt(
+                &self.next_nonce(),
+                Payload {
+                    msg: ciphertext,
+                    aad: &self.hash,
+                },
+            )
+            .map_err(|_| VpnError::KeyExchange("Handshake authentication failed".into()))?;
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    fn handshake_cipher(&self) -> Result<Aes256Gcm> {
+        let key = self
+            .cipher_key
+            .ok_or_else(|| VpnError::KeyExchange("Handshake cipher key not set".into()))?;
+        Aes256Gcm::new_from_slice(&key).map_err(|e| VpnError::KeyExchange(e.to_string()))
+    }
+
+    // Noise AESGCM nonce: 32 zero bits followed by a big-endian counter
+    fn next_nonce(&mut self) -> Nonce<<Aes256Gcm as aes_gcm::AeadCore>::NonceSize> {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&self.nonce.to_be_bytes());
+        self.nonce += 1;
+        *Nonce::from_slice(&nonce)
+    }
+}
+
+// Static keys wipe themselves, the symmetric handshake state is ours to clear
+impl Drop for Handshake {
+    fn drop(&mut self) {
+        self.chaining_key.zeroize();
+        self.cipher_key.zeroize();
+        self.preshared_key.zeroize();
+    }
+}
+
+fn hkdf(chaining_key: &[u8; 32], input_key_material: &[u8]) -> Result<([u8; 32], [u8; 32])> {
+    let mut output = Zeroizing::new([0u8; 64]);
+    Hkdf::<Sha256>::new(Some(chaining_key), input_key_material)
+        .expand(&[], output.as_mut())
+        .map_err(|e| VpnError::KeyExchange(e.to_string()))?;
+
+    Ok((read_key(&output[..32]), read_key(&output[32..])))
+}
+
+fn timestamp_now() -> [u8; TIMESTAMP_LEN] {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut timestamp = [0u8; TIMESTAMP_LEN];
+    timestamp[..8].copy_from_slice(&(TAI64_EPOCH + now.as_secs()).to_be_bytes());
+    timestamp[8..].copy_from_slice(&now.subsec_nanos().to_be_bytes());
+    timestamp
+}
+
+fn read_key(bytes: &[u8]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    key.copy_from_slice(bytes);
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_handshake(
+        client: KeyExchange,
+        server: KeyExchange,
+        server_public: [u8; 32],
+    ) -> Result<(Handshake, Handshake)> {
+        let mut initiator = Handshake::new_initiator(client, server_public);
+        let mut responder = Handshake::new_responder(server);
+
+        let initiation = initiator.write_initiation(b"hello")?;
+        assert_eq!(responder.read_initiation(&initiation)?, b"hello");
+        let response = responder.write_response(b"world")?;
+        assert_eq!(initiator.read_response(&response)?, b"world");
+
+        Ok((initiator, responder))
+    }
+
+    #[test]
+    fn test_handshake_derives_mirrored_keys() {
+        let client = KeyExchange::new();
+        let client_public = client.public_key_bytes();
+        let server = KeyExchange::new();
+        let server_public = server.public_key_bytes();
+
+        let (initiator, responder) = run_handshake(client, server, server_public).unwrap();
+        assert_eq!(responder.remote_static(), Some(client_public));
+
+        let client_keys = initiator.into_session_keys().unwrap();
+        let server_keys = responder.into_session_keys().unwrap();
+        assert_eq!(client_keys.sending, server_keys.receiving);
+        assert_eq!(client_keys.receiving, server_keys.sending);
+        assert_ne!(client_keys.sending, client_keys.receiving);
+    }
+
+    #[test]
+    fn test_preshared_key_mismatch_rejected() {
+        let server = KeyExchange::new();
+        let mut initiator = Handshake::new_initiator(KeyExchange::new(), server.public_key_bytes());
+        let mut responder = Handshake::new_responder(server);
+        initiator.set_preshared_key(&SecretKey::from_bytes([1; 32]));
+        responder.set_preshared_key(&SecretKey::from_bytes([2; 32]));
+
+        let initiation = initiator.write_initiation(&[]).unwrap();
+        responder.read_initiation(&initiation).unwrap();
+        let response = responder.write_response(&[]).unwrap();
+        assert!(matches!(
+            initiator.read_response(&response),
+            Err(VpnError::KeyExchange(_))
+        ));
+    }
+
+    #[test]
+    fn test_matching_preshared_key_accepted() {
+        let server = KeyExchange::new();
+        let mut initiator = Handshake::new_initiator(KeyExchange::new(), server.public_key_bytes());
+        let mut responder = Handshake::new_responder(server);
+        initiator.set_preshared_key(&SecretKey::from_bytes([1; 32]));
+        responder.set_preshared_key(&SecretKey::from_bytes([1; 32]));
+
+        let initiation = initiator.write_initiation(&[]).unwrap();
+        responder.read_initiation(&initiation).unwrap();
+        let response = responder.write_response(&[]).unwrap();
+        initiator.read_response(&response).unwrap();
+
+        let client_keys = initiator.into_session_keys().unwrap();
+        let server_keys = responder.into_session_keys().unwrap();
+        assert_eq!(client_keys.sending, server_keys.receiving);
+    }
+
+    #[test]
+    fn test_wrong_server_key_rejected() {
+        let impostor_public = KeyExchange::new().public_key_bytes();
+        let result = run_handshake(KeyExchange::new(), KeyExchange::new(), impostor_public);
+        assert!(matches!(result, Err(VpnError::KeyExchange(_))));
+    }
+
+    #[test]
+    fn test_initiation_carries_increasing_timestamp() {
+        let server = KeyExchange::new();
+        let server_public = server.public_key_bytes();
+        let client = KeyExchange::new();
+        let (_, first) = run_handshake(client, server, server_public).unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let server = KeyExchange::new();
+        let server_public = server.public_key_bytes();
+        let (_, second) = run_handshake(KeyExchange::new(), server, server_public).unwrap();
+
+        assert!(second.remote_timestamp().unwrap() > first.remote_timestamp().unwrap());
+    }
+
+    #[test]
+    fn test_tampered_response_rejected() {
+        let server = KeyExchange::new();
+        let mut initiator = Handshake::new_initiator(KeyExchange::new(), server.public_key_bytes());
+        let mut responder = Handshake::new_responder(server);
+
+        let initiation = initiator.write_initiation(&[]).unwrap();
+        responder.read_initiation(&initiation).unwrap();
+        let mut response = responder.write_response(&[]).unwrap();
+        response[RESPONSE_LEN] ^= 1;
+
+        assert!(matches!(
+            initiator.read_response(&response),
+            Err(VpnError::KeyExchange(_))
+        ));
+    }
+}
@@ -1,6 +1,178 @@
-use crate::error::Result;
+use crate::crypto::keys::SecretKey;
+use crate::error::{Result, VpnError};
+use hkdf::Hkdf;
+use ml_kem::kem::{Decapsulate, Encapsulate};
+use ml_kem::{EncodedSizeUser, KemCore, MlKem768};
 use rand::rngs::OsRng;
+use sha2::Sha256;
 use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/// Size of an ML-KEM-768 encapsulation key.
+pub const KEM_ENCAPSULATION_KEY_LEN: usize = 1184;
+/// Size of an ML-KEM-768 ciphertext.
+pub const KEM_CIPHERTEXT_LEN: usize = 1088;
+
+/// How the handshake establishes its shared secrets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum KeyExchangeMode {
+    /// Classic Noise IK over X25519 only.
+    X25519 = 1,
+    /// X25519 plus an ML-KEM-768 encapsulation, secure if either holds.
+    HybridMlKem768 = 2,
+}
+
+impl TryFrom<u8> for KeyExchangeMode {
+    type Error = VpnError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            1 => Ok(KeyExchangeMode::X25519),
+            2 => Ok(KeyExchangeMode::HybridMlKem768),
+            _ => Err(VpnError::KeyExchange(format!(
+                "Unknown key exchange mode {}",
+                value
+            ))),
+        }
+    }
+}
+
+impl KeyExchangeMode {
+    /// Every mode, strongest first.
+    pub const ALL: [KeyExchangeMode; 2] =
+        [KeyExchangeMode::HybridMlKem768, KeyExchangeMode::X25519];
+
+    /// Picks the first mode in the peer's preference list that we allow.
+    pub fn negotiate(
+        offered: &[KeyExchangeMode],
+        allowed: &[KeyExchangeMode],
+    ) -> Option<KeyExchangeMode> {
+        offered.iter().copied().find(|mode| allowed.contains(mode))
+    }
+
+    pub fn encode_list(modes: &[KeyExchangeMode]) -> Vec<u8> {
+        modes.iter().map(|mode| *mode as u8).collect()
+    }
+
+    /// Decodes an offer, skipping modes this build doesn't know.
+    pub fn decode_list(bytes: &[u8]) -> Vec<KeyExchangeMode> {
+        bytes
+            .iter()
+            .filter_map(|byte| KeyExchangeMode::try_from(*byte).ok())
+            .collect()
+    }
+}
+
+/// Ephemeral ML-KEM-768 keypair held by the initiator of a hybrid handshake.
+pub struct KemKeypair {
+    decapsulation_key: <MlKem768 as KemCore>::DecapsulationKey,
+    encapsulation_key: Vec<u8>,
+}
+
+impl KemKeypair {
+    pub fn new() -> Self {
+        let (decapsulation_key, encapsulation_key) = MlKem768::generate(&mut OsRng);
+
+        Self {
+            decapsulation_key,
+            encapsulation_key: encapsulation_key.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn encapsulation_key(&self) -> &[u8] {
+        &self.encapsulation_key
+    }
+
+    /// Recovers the secret the responder encapsulated into `ciphertext`.
+    pub fn decapsulate(&self, ciphertext: &[u8]) -> Result<Zeroizing<[u8; 32]>> {
+        let ciphertext = ciphertext
+            .try_into()
+            .map_err(|_| VpnError::KeyExchange("Invalid ML-KEM ciphertext length".into()))?;
+        let shared_secret = self
+            .decapsulation_key
+            .decapsulate(&ciphertext)
+            .map_err(|_| VpnError::KeyExchange("ML-KEM decapsulation failed".into()))?;
+
+        Ok(Zeroizing::new(shared_secret.into()))
+    }
+}
+
+impl Default for KemKeypair {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Encapsulates a fresh secret to the initiator's `encapsulation_key`,
+/// returning the ciphertext to send back and the shared secret.
+pub fn kem_encapsulate(encapsulation_key: &[u8]) -> Result<(Vec<u8>, Zeroizing<[u8; 32]>)> {
+    let encoded = encapsulation_key
+        .try_into()
+        .map_err(|_| VpnError::KeyExchange("Invalid ML-KEM encapsulation key length".into()))?;
+    let encapsulation_key = <MlKem768 as KemCore>::EncapsulationKey::from_bytes(&encoded);
+    let (ciphertext, shared_secret) = encapsulation_key
+        .encapsulate(&mut OsRng)
+        .map_err(|_| VpnError::KeyExchange("ML-KEM encapsulation failed".into()))?;
+
+    Ok((ciphertext.to_vec(), Zeroizing::new(shared_secret.into())))
+}
+
+/// Per-direction transport keys for one session, wiped on drop.
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub struct SessionKeys {
+    pub sending: [u8; 32],
+    pub receiving: [u8; 32],
+    // Chains each rekey to the keys it replaces
+    pub(crate) rekey_secret: [u8; 32],
+}
+
+impl SessionKeys {
+    pub(crate) fn from_split(
+        initiator_key: [u8; 32],
+        responder_key: [u8; 32],
+        rekey_secret: [u8; 32],
+        initiator: bool,
+    ) -> Self {
+        let (sending, receiving) = if initiator {
+            (initiator_key, responder_key)
+        } else {
+            (responder_key, initiator_key)
+        };
+
+        Self {
+            sending,
+            receiving,
+            rekey_secret,
+        }
+    }
+
+    /// Derives the next generation of keys from the current rekey secret
+    /// and a fresh ephemeral shared secret.
+    pub fn next_generation(
+        rekey_secret: &[u8; 32],
+        shared_secret: &[u8; 32],
+        initiator: bool,
+    ) -> Result<Self> {
+        let mut output = Zeroizing::new([0u8; 96]);
+        Hkdf::<Sha256>::new(Some(rekey_secret), shared_secret)
+            .expand(&[], output.as_mut())
+            .map_err(|e| VpnError::KeyExchange(e.to_string()))?;
+
+        let mut keys = Zeroizing::new([[0u8; 32]; 3]);
+        for (key, chunk) in keys.iter_mut().zip(output.chunks_exact(32)) {
+            key.copy_from_slice(chunk);
+        }
+        let [initiator_key, responder_key, next_secret] = *keys;
+
+        Ok(Self::from_split(
+            initiator_key,
+            responder_key,
+            next_secret,
+            initiator,
+        ))
+    }
+}
 
 pub struct KeyExchange {
     private_key: StaticSecret,
@@ -9,7 +181,14 @@ pub struct KeyExchange {
 
 impl KeyExchange {
     pub fn new() -> Self {
-        let private_key = StaticSecret::new(OsRng);
+        Self::from_secret(StaticSecret::random_from_rng(OsRng))
+    }
+
+    pub fn from_private_key(private_key: &SecretKey) -> Self {
+        Self::from_secret(StaticSecret::from(*private_key.as_bytes()))
+    }
+
+    fn from_secret(private_key: StaticSecret) -> Self {
         let public_key = PublicKey::from(&private_key);
 
         Self {
@@ -18,12 +197,64 @@ impl KeyExchange {
         }
     }
 
-    pub fn generate_shared_secret(&self, peer_public: &PublicKey) -> [u8; 32] {
+    pub fn generate_shared_secret(&self, peer_public: &PublicKey) -> Zeroizing<[u8; 32]> {
         let shared_secret = self.private_key.diffie_hellman(peer_public);
-        *shared_secret.as_bytes()
+        Zeroizing::new(*shared_secret.as_bytes())
+    }
+
+    /// X25519 with `peer_public`, rejecting low-order points.
+    pub fn diffie_hellman(&self, peer_public: &[u8; 32]) -> Result<Zeroizing<[u8; 32]>> {
+        let shared_secret = self.generate_shared_secret(&PublicKey::from(*peer_public));
+        if *shared_secret == [0u8; 32] {
+            return Err(VpnError::KeyExchange(
+                "Peer public key produced an all-zero shared secret".into(),
+            ));
+        }
+        Ok(shared_secret)
     }
 
     pub fn public_key_bytes(&self) -> [u8; 32] {
         *self.public_key.as_bytes()
     }
 }
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kem_shared_secrets_match() {
+        let keypair = KemKeypair::new();
+        assert_eq!(keypair.encapsulation_key().len(), KEM_ENCAPSULATION_KEY_LEN);
+
+        let (ciphertext, sent) = kem_encapsulate(keypair.encapsulation_key()).unwrap();
+        assert_eq!(ciphertext.len(), KEM_CIPHERTEXT_LEN);
+        assert_eq!(*keypair.decapsulate(&ciphertext).unwrap(), *sent);
+    }
+
+    #[test]
+    fn test_key_exchange_mode_negotiation() {
+        let offered = KeyExchangeMode::decode_list(&[9, 2, 1]);
+        assert_eq!(
+            offered,
+            vec![KeyExchangeMode::HybridMlKem768, KeyExchangeMode::X25519]
+        );
+        assert_eq!(
+            KeyExchangeMode::negotiate(&offered, &[KeyExchangeMode::X25519]),
+            Some(KeyExchangeMode::X25519)
+        );
+        assert_eq!(
+            KeyExchangeMode::negotiate(
+                &[KeyExchangeMode::X25519],
+                &[KeyExchangeMode::HybridMlKem768]
+            ),
+            None
+        );
+    }
+}
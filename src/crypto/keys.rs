@@ -0,0 +1,223 @@
+use crate::error::{Result, VpnError};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use rand::rngs::OsRng;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+// Key files hold a single base64 line, the same format `wg genkey` writes.
+
+/// A private or pre-shared key, wiped from memory when dropped.
+///
+/// Deliberately not `Clone` and redacted in `Debug`; share it behind an `Arc`.
+#[derive(Zeroize, ZeroizeOnDrop)]
+pub struct SecretKey([u8; 32]);
+
+impl SecretKey {
+    pub fn generate() -> Self {
+        Self(StaticSecret::random_from_rng(OsRng).to_bytes())
+    }
+
+    /// Takes ownership of raw key bytes; wiping the caller's copy is up to them.
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Derives the public key belonging to this private key.
+    pub fn public_key(&self) -> [u8; 32] {
+        PublicKey::from(&StaticSecret::from(self.0)).to_bytes()
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SecretKey([REDACTED])")
+    }
+}
+
+/// Generates a new static identity, returned as `(private_key, public_key)`.
+pub fn generate_keypair() -> (SecretKey, [u8; 32]) {
+    let private_key = SecretKey::generate();
+    let public_key = private_key.public_key();
+    (private_key, public_key)
+}
+
+pub fn encode_key(key: &[u8; 32]) -> String {
+    STANDARD.encode(key)
+}
+
+pub fn decode_key(encoded: &str) -> Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    parse_key(encoded, &mut key).map_err(VpnError::Config)?;
+    Ok(key)
+}
+
+/// Decodes a base64 private or pre-shared key without leaving an unwiped copy behind.
+pub fn decode_secret_key(encoded: &str) -> Result<SecretKey> {
+    let mut key = SecretKey([0u8; 32]);
+    parse_key(encoded, &mut key.0).map_err(VpnError::Config)?;
+    Ok(key)
+}
+
+fn parse_key(encoded: &str, key: &mut [u8; 32]) -> std::result::Result<(), String> {
+    let decoded = Zeroizing::new(
+        STANDARD
+            .decode(encoded.trim())
+            .map_err(|e| format!("Invalid base64 key: {}", e))?,
+    );
+    if decoded.len() != key.len() {
+        return Err("Key must be 32 bytes".to_string());
+    }
+
+    key.copy_from_slice(&decoded);
+    Ok(())
+}
+
+/// Reads a private or pre-shared key, refusing files other users can access.
+pub fn read_private_key(path: impl AsRef<Path>) -> Result<SecretKey> {
+    let path = path.as_ref();
+    check_private_permissions(path)?;
+
+    let mut private_key = SecretKey([0u8; 32]);
+    read_key(path, &mut private_key.0)?;
+    Ok(private_key)
+}
+
+/// Writes a private key to a file only the owner can read.
+pub fn write_private_key(path: impl AsRef<Path>, private_key: &SecretKey) -> Result<()> {
+    write_key(path.as_ref(), private_key.as_bytes(), 0o600)
+}
+
+pub fn read_public_key(path: impl AsRef<Path>) -> Result<[u8; 32]> {
+    let mut public_key = [0u8; 32];
+    read_key(path.as_ref(), &mut public_key)?;
+    Ok(public_key)
+}
+
+pub fn write_public_key(path: impl AsRef<Path>, public_key: &[u8; 32]) -> Result<()> {
+    write_key(path.as_ref(), public_key, 0o644)
+}
+
+/// Loads a private key file and derives its public key.
+pub fn public_key_from_file(private_key_path: impl AsRef<Path>) -> Result<[u8; 32]> {
+    Ok(read_private_key(private_key_path)?.public_key())
+}
+
+fn read_key(path: &Path, key: &mut [u8; 32]) -> Result<()> {
+    let contents = Zeroizing::new(fs::read_to_string(path)?);
+    parse_key(&contents, key).map_err(|e| VpnError::Config(format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(unix)]
+fn write_key(path: &Path, key: &[u8; 32], mode: u32) -> Result<()> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)?;
+    // The mode only applies on creation, tighten existing files too
+    file.set_permissions(fs::Permissions::from_mode(mode))?;
+    writeln!(file, "{}", *Zeroizing::new(encode_key(key)))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_key(path: &Path, key: &[u8; 32], _mode: u32) -> Result<()> {
+    let mut file = fs::File::create(path)?;
+    writeln!(file, "{}", *Zeroizing::new(encode_key(key)))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn check_private_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(VpnError::Config(format!(
+            "Private key {} is accessible by other users (mode {:o}), expected 600",
+            path.display(),
+            mode & 0o777
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_private_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rust_vpn_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_key_files_round_trip() {
+        let (private_key, public) = generate_keypair();
+        let private_path = temp_path("roundtrip.key");
+        let public_path = temp_path("roundtrip.pub");
+
+        write_private_key(&private_path, &private_key).unwrap();
+        write_public_key(&public_path, &public).unwrap();
+
+        assert_eq!(
+            read_private_key(&private_path).unwrap().as_bytes(),
+            private_key.as_bytes()
+        );
+        assert_eq!(read_public_key(&public_path).unwrap(), public);
+        assert_eq!(public_key_from_file(&private_path).unwrap(), public);
+
+        fs::remove_file(private_path).unwrap();
+        fs::remove_file(public_path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_world_readable_private_key_rejected() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (private_key, _) = generate_keypair();
+        let path = temp_path("loose.key");
+        write_private_key(&path, &private_key).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        assert!(matches!(read_private_key(&path), Err(VpnError::Config(_))));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_secret_key_debug_is_redacted() {
+        let private_key = SecretKey::from_bytes([0x41; 32]);
+        assert_eq!(format!("{:?}", private_key), "SecretKey([REDACTED])");
+    }
+
+    #[test]
+    fn test_decode_rejects_wrong_length() {
+        assert!(decode_key(&STANDARD.encode([0u8; 16])).is_err());
+        assert!(decode_key("not base64!").is_err());
+        assert!(decode_secret_key(&STANDARD.encode([0u8; 16])).is_err());
+    }
+
+    #[test]
+    fn test_decode_secret_key_round_trip() {
+        let private_key = SecretKey::generate();
+        let decoded = decode_secret_key(&encode_key(private_key.as_bytes())).unwrap();
+        assert_eq!(decoded.as_bytes(), private_key.as_bytes());
+    }
+}
@@ -1,3 +1,12 @@
+pub mod cipher_suite; // Negotiable transport AEADs
 mod encryption; // Encryption implementation
+pub mod handshake; // Noise IK handshake with static identity keys
+pub mod key_exchange; // X25519 key agreement
+pub mod keys; // Key generation and key files
+pub mod replay; // Anti-replay sliding window
 
+pub use cipher_suite::CipherSuite;
 pub use encryption::EncryptionManager;
+pub use handshake::Handshake;
+pub use key_exchange::{KemKeypair, KeyExchange, KeyExchangeMode, SessionKeys};
+pub use keys::SecretKey;
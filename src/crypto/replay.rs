@@ -0,0 +1,130 @@
+// Sliding window replay protection (RFC 6479)
+//
+// The window is a ring of 64-bit words. Advancing the window only clears the
+// words that slide out of it instead of shifting the whole bitmap.
+
+const WORD_BITS: u64 = u64::BITS as u64;
+const RING_WORDS: usize = 32;
+/// Number of counters behind the newest one that are still accepted.
+pub const WINDOW_SIZE: u64 = (RING_WORDS as u64 - 1) * WORD_BITS;
+/// Counters at or above this value are never accepted.
+pub const REJECT_AFTER_MESSAGES: u64 = u64::MAX - WINDOW_SIZE - 1;
+
+pub struct ReplayWindow {
+    bitmap: [u64; RING_WORDS],
+    // One past the greatest counter accepted so far
+    next: u64,
+}
+
+impl ReplayWindow {
+    pub fn new() -> Self {
+        Self {
+            bitmap: [0; RING_WORDS],
+            next: 0,
+        }
+    }
+
+    /// Returns true if `counter` has not been seen and is not too old.
+    pub fn check(&self, counter: u64) -> bool {
+        if counter >= REJECT_AFTER_MESSAGES {
+            return false;
+        }
+
+        let counter = counter + 1;
+        if counter + WINDOW_SIZE < self.next {
+            return false;
+        }
+        if counter > self.next {
+            return true;
+        }
+
+        let (word, bit) = Self::position(counter);
+        self.bitmap[word] & bit == 0
+    }
+
+    /// Records `counter` as received, sliding the window forward if needed.
+    pub fn update(&mut self, counter: u64) {
+        let counter = counter + 1;
+        let index = counter / WORD_BITS;
+
+        if counter > self.next {
+            let current = self.next / WORD_BITS;
+            let stale = (index - current).min(RING_WORDS as u64);
+            for i in 1..=stale {
+                self.bitmap[((current + i) % RING_WORDS as u64) as usize] = 0;
+            }
+            self.next = counter;
+        }
+
+        let (word, bit) = Self::position(counter);
+        self.bitmap[word] |= bit;
+    }
+
+    fn position(counter: u64) -> (usize, u64) {
+        let word = ((counter / WORD_BITS) % RING_WORDS as u64) as usize;
+        (word, 1 << (counter % WORD_BITS))
+    }
+}
+
+impl Default for ReplayWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accept(window: &mut ReplayWindow, counter: u64) -> bool {
+        let fresh = window.check(counter);
+        if fresh {
+            window.update(counter);
+        }
+        fresh
+    }
+
+    #[test]
+    fn test_duplicates_rejected() {
+        let mut window = ReplayWindow::new();
+        assert!(accept(&mut window, 0));
+        assert!(accept(&mut window, 1));
+        assert!(!accept(&mut window, 0));
+        assert!(!accept(&mut window, 1));
+    }
+
+    #[test]
+    fn test_out_of_order_within_window() {
+        let mut window = ReplayWindow::new();
+        assert!(accept(&mut window, 10));
+        assert!(accept(&mut window, 5));
+        assert!(accept(&mut window, 11));
+        assert!(!accept(&mut window, 5));
+    }
+
+    #[test]
+    fn test_too_old_rejected() {
+        let mut window = ReplayWindow::new();
+        assert!(accept(&mut window, WINDOW_SIZE + 100));
+        assert!(!accept(&mut window, 50));
+        assert!(accept(&mut window, 100));
+    }
+
+    #[test]
+    fn test_large_jump_clears_window() {
+        let mut window = ReplayWindow::new();
+        for counter in 0..200 {
+            assert!(accept(&mut window, counter));
+        }
+        let jump = 200 + 10 * WINDOW_SIZE;
+        assert!(accept(&mut window, jump));
+        assert!(accept(&mut window, jump - 1));
+        assert!(!accept(&mut window, jump));
+    }
+
+    #[test]
+    fn test_exhausted_counter_rejected() {
+        let window = ReplayWindow::new();
+        assert!(!window.check(REJECT_AFTER_MESSAGES));
+    }
+}
@@ -1,3 +1,5 @@
+use crate::protocol::ErrorCode;
+
 #[derive(Debug)]
 pub enum VpnError {
     Io(std::io::Error),
@@ -6,8 +8,11 @@ pub enum VpnError {
     Config(String),
     Network(String),
     KeyExchange(String),
+    ReplayedPacket(u64),
     GenericError(String),
     ClientNotFound,
+    /// The server ended the session, saying why.
+    Remote(ErrorCode, String),
 }
 
 impl From<&str> for VpnError {
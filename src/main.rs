@@ -1,22 +1,31 @@
 use rust_vpn::error::Result;
 use rust_vpn::{
-    error::VpnError, protocol::VpnPacket, vpn_client::VpnClient, vpn_service::VpnConfig,
-    vpn_service::VpnService,
+    crypto::SecretKey, error::VpnError, protocol::VpnPacket, vpn_client::VpnClient,
+    vpn_service::VpnConfig, vpn_service::VpnService,
 };
 //use std::net::SocketAddr;
-use std::thread;
 use std::time::Duration;
 
-fn run_server(bind_addr: &str, encryption_key: [u8; 32], config: VpnConfig) -> Result<VpnService> {
-    let mut vpn = VpnService::new(bind_addr, encryption_key, Some(config))?;
+async fn run_server(
+    bind_addr: &str,
+    private_key: SecretKey,
+    allowed_peers: &[[u8; 32]],
+    config: VpnConfig,
+) -> Result<VpnService> {
+    let mut vpn = VpnService::new(bind_addr, private_key, allowed_peers, Some(config)).await?;
 
     vpn.start()?;
 
     Ok(vpn)
 }
 
-fn run_client(server_addr: &str, encryption_key: [u8; 32], config: VpnConfig) -> Result<()> {
-    match std::net::TcpStream::connect(server_addr) {
+async fn run_client(
+    server_addr: &str,
+    private_key: SecretKey,
+    server_public_key: [u8; 32],
+    config: VpnConfig,
+) -> Result<()> {
+    match tokio::net::TcpStream::connect(server_addr).await {
         Ok(_) => println!("Client: Test connection successful"),
         Err(e) => {
             eprintln!("Client: Test connection failed: {}", e);
@@ -24,16 +33,17 @@ fn run_client(server_addr: &str, encryption_key: [u8; 32], config: VpnConfig) ->
         }
     }
 
-    let mut client = match VpnClient::new(server_addr, encryption_key, Some(config)) {
-        Ok(client) => {
-            println!("Client: VPN client created successfully");
-            client
-        }
-        Err(e) => {
-            eprintln!("Client: Failed to create VPN client: {:?}", e);
-            return Err(e);
-        }
-    };
+    let mut client =
+        match VpnClient::new(server_addr, private_key, server_public_key, Some(config)).await {
+            Ok(client) => {
+                println!("Client: VPN client created successfully");
+                client
+            }
+            Err(e) => {
+                eprintln!("Client: Failed to create VPN client: {:?}", e);
+                return Err(e);
+            }
+        };
 
     // Test packet
     let test_packet = VpnPacket::new_data(
@@ -42,7 +52,7 @@ fn run_client(server_addr: &str, encryption_key: [u8; 32], config: VpnConfig) ->
         b"Hello, VPN Server!".to_vec(),
     );
 
-    match client.send_packet(test_packet) {
+    match client.send_packet(test_packet).await {
         Ok(response) => {
             println!("Client: Received response:");
             println!("  Type: {:?}", response.packet_type);
@@ -61,30 +71,40 @@ fn run_client(server_addr: &str, encryption_key: [u8; 32], config: VpnConfig) ->
 #[tokio::main]
 async fn main() -> Result<()> {
     let server_addr = "127.0.0.1:8080";
+
+    // Static identities; generate and store these securely in production
+    let server_private_key = SecretKey::generate();
+    let client_private_key = SecretKey::generate();
+    let client_public_key = client_private_key.public_key();
     let _peer_addr = "10.0.0.2:51820";
-    let encryption_key = [0u8; 32]; // Generate proper key in production
     let config = VpnConfig {
         mtu: 1500,
         keepalive_interval: Duration::from_secs(30),
         reconnect_attempts: 3,
     };
 
-    let mut vpn = run_server(&server_addr.to_string(), encryption_key, config.clone())?;
+    let mut vpn = run_server(
+        server_addr,
+        server_private_key,
+        &[client_public_key],
+        config.clone(),
+    )
+    .await?;
 
     println!("Waiting for server to start...");
-    thread::sleep(Duration::from_secs(2));
+    tokio::time::sleep(Duration::from_secs(2)).await;
 
     // Run client
     println!("Starting client...");
-    match run_client(server_addr, encryption_key, config) {
+    match run_client(server_addr, client_private_key, vpn.public_key(), config).await {
         Ok(_) => println!("Client test completed successfully!"),
         Err(e) => eprintln!("Client error: {:?}", e),
     }
 
     // Wait a bit before shutting down
-    thread::sleep(Duration::from_secs(2));
+    tokio::time::sleep(Duration::from_secs(2)).await;
 
-    vpn.shutdown()?;
+    vpn.shutdown().await?;
 
     Ok(())
 }
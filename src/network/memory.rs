@@ -0,0 +1,194 @@
+// Both ends in one process, frames handed over through channels. Listeners
+// are found by the name they were bound to, which clients connect to in place
+// of an address. Useful for tests and for embedding a server and its clients.
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock,
+    },
+};
+
+use async_trait::async_trait;
+use tokio::sync::{
+    mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    Mutex,
+};
+
+use crate::{
+    error::VpnError,
+    network::transport::{Connect, Listener, Transport},
+};
+
+// Listeners currently bound, by name
+static LISTENERS: LazyLock<std::sync::Mutex<HashMap<String, UnboundedSender<MemoryTransport>>>> =
+    LazyLock::new(Default::default);
+// Numbers the peers of every listener, so ids are never reused
+static NEXT_PEER: AtomicU64 = AtomicU64::new(1);
+
+pub struct MemoryListener {
+    name: String,
+    incoming: Mutex<UnboundedReceiver<MemoryTransport>>,
+    // To tell our registration from a later listener's of the same name
+    registered: UnboundedSender<MemoryTransport>,
+}
+
+impl MemoryListener {
+    pub fn bind(name: &str) -> Result<Self, VpnError> {
+        let mut listeners = LISTENERS.lock().unwrap();
+        if listeners.contains_key(name) {
+            return Err(VpnError::Io(std::io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("{} is already bound", name),
+            )));
+        }
+        let (registered, incoming) = unbounded_channel();
+        listeners.insert(name.to_string(), registered.clone());
+
+        Ok(Self {
+            name: name.to_string(),
+            incoming: Mutex::new(incoming),
+            registered,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Drop for MemoryListener {
+    fn drop(&mut self) {
+        let mut listeners = LISTENERS.lock().unwrap();
+        if listeners
+            .get(&self.name)
+            .is_some_and(|sender| sender.same_channel(&self.registered))
+        {
+            listeners.remove(&self.name);
+        }
+    }
+}
+
+#[async_trait]
+impl Listener for MemoryListener {
+    type Link = MemoryTransport;
+
+    // Frames are handed over whole, whatever their size
+    const MAX_FRAME_LEN: usize = usize::MAX;
+
+    async fn accept(&self) -> Result<MemoryTransport, VpnError> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| VpnError::Network(format!("{} is no longer bound", self.name)))
+    }
+}
+
+/// Either end of a link to a `MemoryListener`.
+pub struct MemoryTransport {
+    peer: String,
+    // Taken on close, which the other end reads as hanging up
+    outgoing: std::sync::Mutex<Option<UnboundedSender<Vec<u8>>>>,
+    incoming: Mutex<UnboundedReceiver<Vec<u8>>>,
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    fn peer(&self) -> String {
+        self.peer.clone()
+    }
+
+    async fn send_frame(&self, frame: &[u8]) -> Result<(), VpnError> {
+        self.outgoing
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|sender| sender.send(frame.to_vec()).ok())
+            .ok_or_else(|| VpnError::Io(ErrorKind::BrokenPipe.into()))
+    }
+
+    async fn receive_frame(&self) -> Result<Vec<u8>, VpnError> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| VpnError::Io(ErrorKind::UnexpectedEof.into()))
+    }
+
+    async fn close(&self) -> Result<(), VpnError> {
+        self.outgoing.lock().unwrap().take();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Connect for MemoryTransport {
+    /// Connects to the listener bound to the name `addr`.
+    async fn connect(addr: &str) -> Result<Self, VpnError> {
+        let refused = || {
+            VpnError::Io(std::io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("Nothing bound to {}", addr),
+            ))
+        };
+        let listener = LISTENERS
+            .lock()
+            .unwrap()
+            .get(addr)
+            .cloned()
+            .ok_or_else(refused)?;
+
+        let (to_listener, from_client) = unbounded_channel();
+        let (to_client, from_listener) = unbounded_channel();
+        let accepted = MemoryTransport {
+            peer: format!("{}#{}", addr, NEXT_PEER.fetch_add(1, Ordering::Relaxed)),
+            outgoing: std::sync::Mutex::new(Some(to_client)),
+            incoming: Mutex::new(from_client),
+        };
+        listener.send(accepted).map_err(|_| refused())?;
+
+        Ok(MemoryTransport {
+            peer: addr.to_string(),
+            outgoing: std::sync::Mutex::new(Some(to_listener)),
+            incoming: Mutex::new(from_listener),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_frames_cross_both_ways() {
+        let listener = MemoryListener::bind("memory-test-frames").unwrap();
+        assert!(MemoryListener::bind("memory-test-frames").is_err());
+
+        let client = MemoryTransport::connect("memory-test-frames")
+            .await
+            .unwrap();
+        let accepted = listener.accept().await.unwrap();
+        assert_ne!(accepted.peer(), client.peer());
+
+        client.send_frame(b"hello").await.unwrap();
+        assert_eq!(accepted.receive_frame().await.unwrap(), b"hello");
+        accepted.send_frame(b"world").await.unwrap();
+        assert_eq!(client.receive_frame().await.unwrap(), b"world");
+
+        // Frames sent before hanging up are still read, then the link is gone
+        client.send_frame(b"bye").await.unwrap();
+        client.close().await.unwrap();
+        assert!(client.send_frame(b"late").await.is_err());
+        assert_eq!(accepted.receive_frame().await.unwrap(), b"bye");
+        assert!(accepted.receive_frame().await.is_err());
+
+        drop(listener);
+        assert!(MemoryTransport::connect("memory-test-frames")
+            .await
+            .is_err());
+    }
+}
@@ -1,3 +1,6 @@
-pub mod connection;
+pub mod memory;
 pub mod tcp_client;
 pub mod tcp_server;
+pub mod transport;
+pub mod udp_client;
+pub mod udp_server;
@@ -1,66 +1,170 @@
-use crate::error::VpnError;
+use crate::{
+    error::VpnError,
+    network::transport::{Connect, Transport},
+};
 
-use std::{
-    io::{Read, Write},
-    net::TcpStream as StdTcpStream,
-    time::Duration,
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::Mutex,
 };
 
+/// Largest frame a length prefix may announce.
+pub const MAX_FRAME_LEN: usize = 65535;
+
+// Length prefix in front of every frame
+const PREFIX_LEN: usize = 4;
+
+/// Length-prefixed frames over a TCP stream, the client's link to the server
+/// and the server's link to each client alike.
 pub struct TcpClient {
-    stream: StdTcpStream,
+    reader: Mutex<FrameReader>,
+    writer: Mutex<OwnedWriteHalf>,
+    peer: SocketAddr,
+}
+
+// Bytes read stay buffered until they make a whole frame, so a read that is
+// cancelled halfway loses nothing
+struct FrameReader {
+    stream: OwnedReadHalf,
+    buffer: Vec<u8>,
+}
+
+impl FrameReader {
+    async fn read_frame(&mut self) -> Result<Vec<u8>, VpnError> {
+        loop {
+            if let Some(frame) = self.take_frame()? {
+                return Ok(frame);
+            }
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Err(VpnError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+    }
+
+    fn take_frame(&mut self) -> Result<Option<Vec<u8>>, VpnError> {
+        let Some(prefix) = self.buffer.first_chunk::<PREFIX_LEN>() else {
+            return Ok(None);
+        };
+        let packet_len = u32::from_be_bytes(*prefix) as usize;
+        if packet_len > MAX_FRAME_LEN {
+            return Err(VpnError::Protocol(format!(
+                "Packet too large: {} bytes (max: {})",
+                packet_len, MAX_FRAME_LEN
+            )));
+        }
+
+        let frame_end = PREFIX_LEN + packet_len;
+        if self.buffer.len() < frame_end {
+            self.buffer.reserve(frame_end - self.buffer.len());
+            return Ok(None);
+        }
+        let frame = self.buffer[PREFIX_LEN..frame_end].to_vec();
+        self.buffer.drain(..frame_end);
+        Ok(Some(frame))
+    }
 }
 
 impl TcpClient {
-    pub fn connect(addr: &str) -> Result<Self, VpnError> {
-        let stream = StdTcpStream::connect(addr)?;
+    pub async fn connect(addr: &str) -> Result<Self, VpnError> {
+        let stream = TcpStream::connect(addr).await?;
+        Self::from_stream(stream)
+    }
+
+    pub(crate) fn from_stream(stream: TcpStream) -> Result<Self, VpnError> {
         stream.set_nodelay(true)?;
-        stream.set_read_timeout(Some(Duration::from_secs(45)))?;
-        stream.set_write_timeout(Some(Duration::from_secs(45)))?;
+        let peer = stream.peer_addr()?;
+        let (reader, writer) = stream.into_split();
 
-        Ok(Self { stream })
+        Ok(Self {
+            reader: Mutex::new(FrameReader {
+                stream: reader,
+                buffer: Vec::new(),
+            }),
+            writer: Mutex::new(writer),
+            peer,
+        })
     }
 
-    pub fn client_read_packet(&mut self) -> Result<Vec<u8>, VpnError> {
-        // Read length prefix (4 bytes for length)
-        let mut len_bytes = [0u8; 4];
-        self.stream.read_exact(&mut len_bytes)?;
-        let packet_len = u32::from_be_bytes(len_bytes) as usize;
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
 
-        // Validate packet length
-        if packet_len > 65535 {
-            return Err(VpnError::Protocol(format!(
-                "Packet too large: {} bytes (max: {})",
-                packet_len, 65535
+#[async_trait]
+impl Transport for TcpClient {
+    fn peer(&self) -> String {
+        self.peer.to_string()
+    }
+
+    async fn send_frame(&self, frame: &[u8]) -> Result<(), VpnError> {
+        // The peer would drop the connection, or worse misread the prefix
+        if frame.len() > MAX_FRAME_LEN {
+            return Err(VpnError::Network(format!(
+                "Frame too large: {} bytes (max: {})",
+                frame.len(),
+                MAX_FRAME_LEN
             )));
         }
 
-        // Read packet data
-        let mut buffer = vec![0u8; packet_len];
-        self.stream.read_exact(&mut buffer)?;
+        // One write per frame, so concurrent senders never interleave
+        let mut framed = Vec::with_capacity(PREFIX_LEN + frame.len());
+        framed.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        framed.extend_from_slice(frame);
 
-        Ok(buffer)
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&framed).await?;
+        writer.flush().await?;
+        Ok(())
     }
 
-    pub fn write_packet(&mut self, packet: &[u8]) -> Result<(), VpnError> {
-        let len_bytes = (packet.len() as u32).to_be_bytes();
-        self.stream.write_all(&len_bytes)?;
-        self.stream.write_all(packet)?;
-        self.stream.flush()?;
+    async fn receive_frame(&self) -> Result<Vec<u8>, VpnError> {
+        self.reader.lock().await.read_frame().await
+    }
 
+    async fn close(&self) -> Result<(), VpnError> {
+        self.writer.lock().await.shutdown().await?;
         Ok(())
     }
+}
 
-    pub fn try_clone(&self) -> Result<Self, VpnError> {
-        Ok(Self {
-            stream: self.stream.try_clone()?,
-        })
+#[async_trait]
+impl Connect for TcpClient {
+    async fn connect(addr: &str) -> Result<Self, VpnError> {
+        TcpClient::connect(addr).await
     }
 }
 
-impl Clone for TcpClient {
-    fn clone(&self) -> Self {
-        Self {
-            stream: self.stream.try_clone().unwrap(),
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{tcp_server::TcpServer, transport::Listener};
+
+    #[tokio::test]
+    async fn test_oversized_frame_not_sent() {
+        let server = TcpServer::new("127.0.0.1:0").await.unwrap();
+        let client = TcpClient::connect(&server.bind_addr().to_string())
+            .await
+            .unwrap();
+        let link = server.accept().await.unwrap();
+
+        let oversized = vec![1; MAX_FRAME_LEN + 1];
+        assert!(matches!(
+            client.send_frame(&oversized).await,
+            Err(VpnError::Network(_))
+        ));
+        assert!(matches!(
+            link.send_frame(&oversized).await,
+            Err(VpnError::Network(_))
+        ));
+
+        // Nothing was written, so the stream still lines up
+        client.send_frame(&oversized[1..]).await.unwrap();
+        assert_eq!(link.receive_frame().await.unwrap().len(), MAX_FRAME_LEN);
     }
 }
@@ -0,0 +1,126 @@
+// What the tunnel needs from the network, so the packet processing doesn't
+// care which transport carries its frames. A listener hands out one link per
+// peer; a client opens a single link to its server. Links are used through
+// shared references so one task can read while others write.
+use crate::error::VpnError;
+
+use async_trait::async_trait;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+/// How long a link may stay silent before the server drops it.
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// One end of a link carrying whole frames.
+#[async_trait]
+pub trait Transport: Send + Sync + 'static {
+    /// Whether frames may be lost on the way, as datagrams are. Senders then
+    /// resend what must arrive.
+    const LOSES_FRAMES: bool = false;
+
+    /// Who is at the other end: the peer's id on a listener, the server on a
+    /// client.
+    fn peer(&self) -> String;
+
+    async fn send_frame(&self, frame: &[u8]) -> Result<(), VpnError>;
+
+    /// Waits for the next frame, failing once the other end hung up.
+    /// Cancelling the wait loses nothing, a later call picks up where it left.
+    async fn receive_frame(&self) -> Result<Vec<u8>, VpnError>;
+
+    /// Ends the link; the other end sees it hang up.
+    async fn close(&self) -> Result<(), VpnError>;
+}
+
+/// A transport a client can open to a listener.
+#[async_trait]
+pub trait Connect: Transport + Sized {
+    async fn connect(addr: &str) -> Result<Self, VpnError>;
+}
+
+/// The server end: accepts peers, each on a link of its own.
+#[async_trait]
+pub trait Listener: Send + Sync + 'static {
+    type Link: Transport;
+
+    /// Largest frame the transport can carry.
+    const MAX_FRAME_LEN: usize;
+
+    /// Waits for the next peer. Cancelling the wait loses no peer.
+    async fn accept(&self) -> Result<Self::Link, VpnError>;
+}
+
+/// Whether sending failed for a frame larger than the path MTU, which a
+/// datagram transport reports once it no longer lets frames be fragmented.
+pub fn exceeds_path_mtu(error: &VpnError) -> bool {
+    #[cfg(unix)]
+    {
+        matches!(error, VpnError::Io(e) if e.raw_os_error() == Some(libc::EMSGSIZE))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = error;
+        false
+    }
+}
+
+// A link with what tells the task serving it to stop
+type Entry<T> = (Arc<T>, Arc<Notify>);
+
+/// The links a listener accepted, by peer id, so any task can send to a peer
+/// or drop it.
+pub(crate) struct Links<T> {
+    links: Mutex<HashMap<String, Entry<T>>>,
+}
+
+impl<T: Transport> Links<T> {
+    pub fn new() -> Self {
+        Self {
+            links: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds `link`, returning what is notified once it is dropped.
+    pub fn insert(&self, link: Arc<T>) -> Arc<Notify> {
+        let closed = Arc::new(Notify::new());
+        self.links
+            .lock()
+            .expect("Links in use")
+            .insert(link.peer(), (link, closed.clone()));
+        closed
+    }
+
+    pub async fn send_frame(&self, peer: &str, frame: &[u8]) -> Result<(), VpnError> {
+        let link = self
+            .links
+            .lock()
+            .expect("Links in use")
+            .get(peer)
+            .map(|(link, _)| link.clone())
+            .ok_or(VpnError::ClientNotFound)?;
+        link.send_frame(frame).await
+    }
+
+    /// Drops `peer`; the task serving it stops and closes the link.
+    pub fn close(&self, peer: &str) {
+        let removed = self.links.lock().expect("Links in use").remove(peer);
+        if let Some((_, closed)) = removed {
+            closed.notify_one();
+        }
+    }
+
+    /// Forgets `link` once its task ended, unless a newer link took its id.
+    pub fn remove(&self, link: &Arc<T>) {
+        let mut links = self.links.lock().expect("Links in use");
+        if links
+            .get(&link.peer())
+            .is_some_and(|(current, _)| Arc::ptr_eq(current, link))
+        {
+            links.remove(&link.peer());
+        }
+    }
+}
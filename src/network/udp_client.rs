@@ -0,0 +1,132 @@
+use crate::error::VpnError;
+use crate::network::{
+    transport::{Connect, Transport},
+    udp_server::MAX_DATAGRAM_LEN,
+};
+
+use async_trait::async_trait;
+use std::{
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    sync::Mutex,
+};
+
+/// One encrypted packet per datagram, to and from a single server.
+pub struct UdpClient {
+    socket: UdpSocket,
+    // Fits any datagram, kept rather than allocated per receive
+    buffer: Mutex<Vec<u8>>,
+    // Nothing tells the server, but sends fail from then on as over TCP
+    closed: AtomicBool,
+}
+
+impl UdpClient {
+    pub async fn connect(addr: &str) -> Result<Self, VpnError> {
+        let server_addr = lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| VpnError::Network(format!("No address for {}", addr)))?;
+        let local_addr: SocketAddr = match server_addr {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse()?,
+            SocketAddr::V6(_) => "[::]:0".parse()?,
+        };
+
+        let socket = UdpSocket::bind(local_addr).await?;
+        socket.connect(server_addr).await?;
+        #[cfg(target_os = "linux")]
+        disable_fragmentation(&socket, server_addr.is_ipv6())?;
+
+        Ok(Self {
+            socket,
+            buffer: Mutex::new(vec![0; MAX_DATAGRAM_LEN]),
+            closed: AtomicBool::new(false),
+        })
+    }
+}
+
+// Sets don't fragment on everything sent, so a frame larger than the path MTU
+// is lost rather than split up on the way and path MTU probes mean something.
+// Unlike the default, the kernel's own path MTU estimate doesn't cap sends.
+#[cfg(target_os = "linux")]
+fn disable_fragmentation(socket: &UdpSocket, ipv6: bool) -> Result<(), VpnError> {
+    use std::os::fd::AsRawFd;
+
+    let (level, option, value) = if ipv6 {
+        (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_PROBE,
+        )
+    } else {
+        (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_PROBE,
+        )
+    };
+    // SAFETY: the descriptor belongs to `socket`, and the option value is a
+    // c_int that outlives the call, as its length says
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+#[async_trait]
+impl Transport for UdpClient {
+    const LOSES_FRAMES: bool = true;
+
+    fn peer(&self) -> String {
+        self.socket
+            .peer_addr()
+            .map_or_else(|_| "disconnected".to_string(), |addr| addr.to_string())
+    }
+
+    async fn send_frame(&self, frame: &[u8]) -> Result<(), VpnError> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(VpnError::Io(ErrorKind::NotConnected.into()));
+        }
+        let sent = self.socket.send(frame).await?;
+        if sent != frame.len() {
+            return Err(VpnError::Network(format!(
+                "Sent {} of {} bytes",
+                sent,
+                frame.len()
+            )));
+        }
+
+        Ok(())
+    }
+
+    async fn receive_frame(&self) -> Result<Vec<u8>, VpnError> {
+        let mut buffer = self.buffer.lock().await;
+        let len = self.socket.recv(&mut buffer).await?;
+
+        Ok(buffer[..len].to_vec())
+    }
+
+    async fn close(&self) -> Result<(), VpnError> {
+        self.closed.store(true, Ordering::Release);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Connect for UdpClient {
+    async fn connect(addr: &str) -> Result<Self, VpnError> {
+        UdpClient::connect(addr).await
+    }
+}
@@ -0,0 +1,606 @@
+// Every client sends to the one socket, each encrypted packet in a datagram
+// of its own. A receive task sorts the datagrams into a queue per source
+// address and hands out a link for each new address; the session id in each
+// packet's header, not the address, decides which session a packet belongs
+// to. Source addresses are easily forged, so only a handshake initiation or a
+// packet of a session the server answered opens a link, and links the server
+// never answered are let go of soon, or as soon as the service drops them for
+// failing to authenticate. Data is never resent, the tunnelled protocols
+// recover from loss themselves; clients resend their handshake and requests
+// until answered.
+use std::{
+    collections::HashMap,
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use tokio::{
+    net::UdpSocket,
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Mutex,
+    },
+    task::JoinHandle,
+};
+
+use crate::{
+    crypto::handshake::INITIATION_LEN,
+    error::VpnError,
+    network::transport::{Listener, Transport, IDLE_TIMEOUT},
+    protocol::{MessageType, PacketHeader, HEADER_LEN},
+};
+
+/// Largest payload a UDP datagram can carry over IPv4.
+pub const MAX_DATAGRAM_LEN: usize = 65507;
+// Datagrams waiting per address; more are dropped as a full link would
+const MAX_QUEUED: usize = 256;
+// Addresses tracked at once, datagrams from further ones are dropped
+const MAX_CLIENTS: usize = 4096;
+// New addresses waiting to be accepted
+const MAX_PENDING: usize = 64;
+// Addresses the server hasn't answered yet; datagrams opening further links
+// are dropped
+const MAX_HALF_OPEN: usize = 64;
+// How long the server may leave an address unanswered before its link is
+// dropped, far longer than a handshake takes
+const HALF_OPEN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Sessions the server sent to, by when it last did
+type Sessions = Arc<std::sync::Mutex<HashMap<u32, Instant>>>;
+
+// An address's queue of datagrams, and whether the server answered it yet
+struct Peer {
+    datagrams: mpsc::Sender<Vec<u8>>,
+    answered: Arc<AtomicBool>,
+    opened: Instant,
+}
+
+pub struct UdpServer {
+    bind_addr: SocketAddr,
+    incoming: Mutex<mpsc::Receiver<UdpLink>>,
+    receiver_task: JoinHandle<()>,
+}
+
+/// The server's link to one client address.
+pub struct UdpLink {
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+    datagrams: Mutex<mpsc::Receiver<Vec<u8>>>,
+    closed: AtomicBool,
+    answered: Arc<AtomicBool>,
+    sessions: Sessions,
+}
+
+impl UdpServer {
+    pub async fn new(bind_addr: &str) -> Result<Self, VpnError> {
+        let addr: SocketAddr = bind_addr
+            .parse()
+            .map_err(|e| VpnError::Protocol(format!("Invalid address: {}", e)))?;
+
+        let socket = match UdpSocket::bind(addr).await {
+            Ok(socket) => {
+                println!("listening on {} (UDP)", addr);
+                socket
+            }
+            Err(e) => {
+                eprintln!("Server: Bind failed: {}", e);
+                return Err(VpnError::Io(e));
+            }
+        };
+
+        let (pending, incoming) = mpsc::channel(MAX_PENDING);
+        Ok(Self {
+            bind_addr: socket.local_addr()?,
+            incoming: Mutex::new(incoming),
+            receiver_task: tokio::spawn(Self::receive_loop(
+                Arc::new(socket),
+                pending,
+                Sessions::default(),
+            )),
+        })
+    }
+
+    pub fn bind_addr(&self) -> SocketAddr {
+        self.bind_addr
+    }
+
+    async fn receive_loop(
+        socket: Arc<UdpSocket>,
+        pending: mpsc::Sender<UdpLink>,
+        sessions: Sessions,
+    ) {
+        let mut peers: HashMap<SocketAddr, Peer> = HashMap::new();
+        let mut buf = vec![0; MAX_DATAGRAM_LEN];
+        loop {
+            let (len, addr) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // Some platforms report an earlier send's ICMP error here
+                Err(e) => {
+                    eprintln!("Receive error: {}", e);
+                    continue;
+                }
+            };
+            let mut datagram = buf[..len].to_vec();
+
+            if let Some(peer) = peers.get(&addr) {
+                match peer.datagrams.try_send(datagram) {
+                    Ok(()) | Err(TrySendError::Full(_)) => continue,
+                    // The link was dropped, the address starts over on a new one
+                    Err(TrySendError::Closed(returned)) => {
+                        peers.remove(&addr);
+                        datagram = returned;
+                    }
+                }
+            }
+            if !Self::opens_link(&datagram, &sessions) {
+                continue;
+            }
+
+            if peers.len() >= MAX_CLIENTS {
+                peers.retain(|_, peer| !peer.datagrams.is_closed());
+                if peers.len() >= MAX_CLIENTS {
+                    continue;
+                }
+            }
+            // Dropping the queue ends the link of an address left unanswered
+            peers.retain(|_, peer| {
+                !peer.datagrams.is_closed()
+                    && (peer.answered.load(Ordering::Acquire)
+                        || peer.opened.elapsed() < HALF_OPEN_TIMEOUT)
+            });
+            let half_open = peers
+                .values()
+                .filter(|peer| !peer.answered.load(Ordering::Acquire))
+                .count();
+            if half_open >= MAX_HALF_OPEN {
+                continue;
+            }
+
+            let (sender, datagrams) = mpsc::channel(MAX_QUEUED);
+            let _ = sender.try_send(datagram);
+            let answered = Arc::new(AtomicBool::new(false));
+            let link = UdpLink {
+                socket: socket.clone(),
+                addr,
+                datagrams: Mutex::new(datagrams),
+                closed: AtomicBool::new(false),
+                answered: answered.clone(),
+                sessions: sessions.clone(),
+            };
+            if pending.try_send(link).is_ok() {
+                let peer = Peer {
+                    datagrams: sender,
+                    answered,
+                    opened: Instant::now(),
+                };
+                peers.insert(addr, peer);
+            }
+        }
+    }
+
+    // Whether a datagram from a new address may open a link: a handshake
+    // initiation, or a packet of a session the server recently sent to, which
+    // may have moved to the address
+    fn opens_link(datagram: &[u8], sessions: &Sessions) -> bool {
+        let Ok(header) = PacketHeader::from_bytes(datagram) else {
+            return false;
+        };
+        match header.message_type {
+            MessageType::HandshakeInitiation => {
+                header.session_id == 0 && datagram.len() >= HEADER_LEN + INITIATION_LEN
+            }
+            MessageType::Transport => sessions
+                .lock()
+                .unwrap()
+                .get(&header.session_id)
+                .is_some_and(|sent| sent.elapsed() < IDLE_TIMEOUT),
+            MessageType::HandshakeResponse => false,
+        }
+    }
+}
+
+impl Drop for UdpServer {
+    fn drop(&mut self) {
+        self.receiver_task.abort();
+    }
+}
+
+#[async_trait]
+impl Listener for UdpServer {
+    type Link = UdpLink;
+
+    const MAX_FRAME_LEN: usize = MAX_DATAGRAM_LEN;
+
+    async fn accept(&self) -> Result<UdpLink, VpnError> {
+        self.incoming
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| VpnError::Network("Receive task ended".into()))
+    }
+}
+
+#[async_trait]
+impl Transport for UdpLink {
+    const LOSES_FRAMES: bool = true;
+
+    fn peer(&self) -> String {
+        self.addr.to_string()
+    }
+
+    async fn send_frame(&self, frame: &[u8]) -> Result<(), VpnError> {
+        if self.closed.load(Ordering::Acquire) {
+            return Err(VpnError::Io(ErrorKind::NotConnected.into()));
+        }
+        let sent = self.socket.send_to(frame, self.addr).await?;
+        if sent != frame.len() {
+            return Err(VpnError::Network(format!(
+                "Sent {} of {} bytes to {}",
+                sent,
+                frame.len(),
+                self.addr
+            )));
+        }
+
+        // Whatever the server sends is for a session it set up, unless it
+        // refused the handshake
+        if let Ok(header) = PacketHeader::from_bytes(frame) {
+            if header.session_id != 0 {
+                let mut sessions = self.sessions.lock().unwrap();
+                if sessions.len() >= MAX_CLIENTS && !sessions.contains_key(&header.session_id) {
+                    sessions.retain(|_, sent| sent.elapsed() < IDLE_TIMEOUT);
+                }
+                sessions.insert(header.session_id, Instant::now());
+            }
+        }
+        self.answered.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    async fn receive_frame(&self) -> Result<Vec<u8>, VpnError> {
+        self.datagrams
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| VpnError::Io(ErrorKind::UnexpectedEof.into()))
+    }
+
+    // Nothing tells the client, its session learns from the error message or
+    // goes quiet
+    async fn close(&self) -> Result<(), VpnError> {
+        self.closed.store(true, Ordering::Release);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::SecretKey;
+    use crate::network::udp_client::UdpClient;
+    use crate::protocol::{RouteEntry, VpnPacket, PROTOCOL_VERSION};
+    use crate::vpn_client::{HandshakeOptions, VpnClient};
+    use crate::vpn_service::VpnService;
+
+    const NOT_ACCEPTED: Duration = Duration::from_millis(100);
+
+    // A service on a loopback port and what its clients need to connect
+    async fn service(clients: &[&SecretKey]) -> (VpnService<UdpServer>, SocketAddr, [u8; 32]) {
+        let server = UdpServer::new("127.0.0.1:0").await.unwrap();
+        let addr = server.bind_addr();
+        let server_key = SecretKey::generate();
+        let server_public_key = server_key.public_key();
+        let allowed: Vec<_> = clients.iter().map(|key| key.public_key()).collect();
+        let mut service = VpnService::with_listener(server, server_key, &allowed, None).unwrap();
+        service.start().unwrap();
+        (service, addr, server_public_key)
+    }
+
+    // Relays datagrams between a client and `server`, losing those of the
+    // client's that `lose` picks by number and length
+    async fn lossy_relay(
+        server: SocketAddr,
+        lose: impl Fn(usize, usize) -> bool + Send + 'static,
+    ) -> SocketAddr {
+        relay(server, lose, false).await
+    }
+
+    // Like `lossy_relay`, and when `noisy` hands the client every datagram of
+    // the server's twice with junk in between
+    async fn relay(
+        server: SocketAddr,
+        lose: impl Fn(usize, usize) -> bool + Send + 'static,
+        noisy: bool,
+    ) -> SocketAddr {
+        let outside = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let inside = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        inside.connect(server).await.unwrap();
+        let addr = outside.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut up, mut down) = (vec![0; MAX_DATAGRAM_LEN], vec![0; MAX_DATAGRAM_LEN]);
+            let (mut client, mut count) = (None, 0);
+            loop {
+                tokio::select! {
+                    Ok((len, from)) = outside.recv_from(&mut up) => {
+                        client = Some(from);
+                        count += 1;
+                        if !lose(count, len) {
+                            let _ = inside.send(&up[..len]).await;
+                        }
+                    }
+                    Ok(len) = inside.recv(&mut down) => {
+                        if let Some(client) = client {
+                            let _ = outside.send_to(&down[..len], client).await;
+                            if noisy {
+                                let _ = outside.send_to(b"not a packet", client).await;
+                                let _ = outside.send_to(&down[..len], client).await;
+                            }
+                        }
+                    }
+                }
+            }
+        });
+        addr
+    }
+
+    fn initiation() -> Vec<u8> {
+        PacketHeader::handshake(MessageType::HandshakeInitiation, 0).frame(&[0; INITIATION_LEN])
+    }
+
+    fn transport(session_id: u32) -> Vec<u8> {
+        PacketHeader::new(PROTOCOL_VERSION, MessageType::Transport, 0, session_id, 0)
+            .frame(&[0; 32])
+    }
+
+    async fn accepted(server: &UdpServer) -> Option<UdpLink> {
+        tokio::time::timeout(NOT_ACCEPTED, server.accept())
+            .await
+            .ok()
+            .map(Result::unwrap)
+    }
+
+    #[tokio::test]
+    async fn test_sessions_over_udp() {
+        let keys = [SecretKey::generate(), SecretKey::generate()];
+        let (mut service, addr, server_public_key) = service(&[&keys[0], &keys[1]]).await;
+
+        let mut clients = Vec::new();
+        for key in keys {
+            let client = VpnClient::<UdpClient>::connect(
+                &addr.to_string(),
+                key,
+                server_public_key,
+                HandshakeOptions::default(),
+                None,
+            )
+            .await
+            .unwrap();
+            clients.push(client);
+        }
+
+        // Larger than the MTU, so sent in fragments both ways; each session
+        // gets its own echo back on the shared socket
+        for (i, client) in clients.iter_mut().enumerate() {
+            let payload = vec![i as u8; 4000];
+            let packet = VpnPacket::new_data([10, 0, 0, 1], [10, 0, 0, 2], payload.clone());
+            assert_eq!(client.send_packet(packet).await.unwrap().payload, payload);
+        }
+        let mut session_ids = service.session_ids();
+        session_ids.sort();
+        let mut expected: Vec<_> = clients.iter().map(|client| client.session_id()).collect();
+        expected.sort();
+        assert_eq!(session_ids, expected);
+
+        for client in &mut clients {
+            client.disconnect().await.unwrap();
+        }
+        service.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lost_handshake_and_request_resent() {
+        let key = SecretKey::generate();
+        let (_service, addr, server_public_key) = service(&[&key]).await;
+        // The first initiation and the first config request
+        let relay = lossy_relay(addr, |count, _| [1, 3].contains(&count)).await;
+
+        let mut client = VpnClient::<UdpClient>::connect(
+            &relay.to_string(),
+            key,
+            server_public_key,
+            HandshakeOptions::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        client.ping().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_and_junk_datagrams_dropped() {
+        let key = SecretKey::generate();
+        let (_service, addr, server_public_key) = service(&[&key]).await;
+        let relay = relay(addr, |_, _| false, true).await;
+
+        // Every response is followed by junk and a replay of itself, which
+        // arrive while the client waits on the next one
+        let mut client = VpnClient::<UdpClient>::connect(
+            &relay.to_string(),
+            key,
+            server_public_key,
+            HandshakeOptions::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        client.ping().await.unwrap();
+        let payload = vec![7; 4000];
+        let packet = VpnPacket::new_data([10, 0, 0, 1], [10, 0, 0, 2], payload.clone());
+        assert_eq!(client.send_packet(packet).await.unwrap().payload, payload);
+        client.update_routes(&[]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_only_handshakes_and_known_sessions_open_links() {
+        let server = UdpServer::new("127.0.0.1:0").await.unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server.bind_addr()).await.unwrap();
+
+        socket.send(b"not a packet").await.unwrap();
+        socket.send(&transport(5)).await.unwrap();
+        assert!(accepted(&server).await.is_none());
+
+        socket.send(&initiation()).await.unwrap();
+        let link = accepted(&server).await.unwrap();
+        // Once the server sent for a session, its packets may come from elsewhere
+        link.send_frame(&transport(5)).await.unwrap();
+        let roamed = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        roamed.connect(server.bind_addr()).await.unwrap();
+        roamed.send(&transport(6)).await.unwrap();
+        assert!(accepted(&server).await.is_none());
+        roamed.send(&transport(5)).await.unwrap();
+        assert!(accepted(&server).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_half_open_links_limited() {
+        let server = UdpServer::new("127.0.0.1:0").await.unwrap();
+        let mut links = Vec::new();
+        let mut sockets = Vec::new();
+        for _ in 0..=MAX_HALF_OPEN {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.connect(server.bind_addr()).await.unwrap();
+            socket.send(&initiation()).await.unwrap();
+            links.extend(accepted(&server).await);
+            sockets.push(socket);
+        }
+        assert_eq!(links.len(), MAX_HALF_OPEN);
+
+        // Answering one makes room for another
+        links[0].send_frame(&[0; HEADER_LEN]).await.unwrap();
+        sockets[MAX_HALF_OPEN].send(&initiation()).await.unwrap();
+        assert!(accepted(&server).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rejected_links_free_half_open_slots() {
+        let keys = [SecretKey::generate(), SecretKey::generate()];
+        let (_service, addr, server_public_key) = service(&[&keys[0], &keys[1]]).await;
+        let [first, second] = keys;
+        let client = VpnClient::<UdpClient>::connect(
+            &addr.to_string(),
+            first,
+            server_public_key,
+            HandshakeOptions::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        // Initiations that fail to authenticate, and packets forged for a
+        // session the server answered, from more addresses than may be half open
+        let mut sockets = Vec::new();
+        for i in 0..=MAX_HALF_OPEN {
+            let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            socket.connect(addr).await.unwrap();
+            let forged = if i % 2 == 0 {
+                initiation()
+            } else {
+                transport(client.session_id())
+            };
+            socket.send(&forged).await.unwrap();
+            sockets.push(socket);
+        }
+        tokio::time::sleep(NOT_ACCEPTED).await;
+
+        // The service let go of them long before they would time out
+        tokio::time::timeout(
+            HALF_OPEN_TIMEOUT / 5,
+            VpnClient::<UdpClient>::connect(
+                &addr.to_string(),
+                second,
+                server_public_key,
+                HandshakeOptions::default(),
+                None,
+            ),
+        )
+        .await
+        .expect("Half open slots still taken")
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_lost_probes_lower_path_mtu() {
+        let key = SecretKey::generate();
+        let (service, addr, server_public_key) = service(&[&key]).await;
+        // A path that loses what doesn't fit 1200 bytes
+        let relay = lossy_relay(addr, |_, len| len > 1200).await;
+
+        let mut client = VpnClient::<UdpClient>::connect(
+            &relay.to_string(),
+            key,
+            server_public_key,
+            HandshakeOptions::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(client.path_mtu(), 1200);
+        assert_eq!(service.path_mtu(client.session_id()), Some(1200));
+
+        let packet = VpnPacket::new_data([10, 0, 0, 1], [10, 0, 0, 2], vec![7; 4000]);
+        assert_eq!(
+            client.send_packet(packet).await.unwrap().payload,
+            vec![7; 4000]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_session_roams_to_new_address() {
+        let key = SecretKey::generate();
+        let (service, addr, server_public_key) = service(&[&key]).await;
+        let mut client = VpnClient::<UdpClient>::connect(
+            &addr.to_string(),
+            key,
+            server_public_key,
+            HandshakeOptions::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        let session_id = client.session_id();
+        let route = RouteEntry::new(
+            "192.168.7.0".parse().unwrap(),
+            24,
+            "10.0.0.2".parse().unwrap(),
+            1,
+        )
+        .unwrap();
+        client
+            .update_routes(std::slice::from_ref(&route))
+            .await
+            .unwrap();
+
+        // From a socket of its own, so another source port
+        client.reconnect().await.unwrap();
+
+        let packet = VpnPacket::new_data([10, 0, 0, 1], [10, 0, 0, 2], vec![7; 100]);
+        assert_eq!(
+            client.send_packet(packet).await.unwrap().payload,
+            vec![7; 100]
+        );
+        assert_eq!(service.session_ids(), vec![session_id]);
+        assert_eq!(
+            service.route_for(session_id, "192.168.7.9".parse().unwrap()),
+            Some(route)
+        );
+    }
+}
@@ -0,0 +1,208 @@
+// Inner layout of every packet, shared by `ProtocolHandler` and the
+// standalone `VpnPacket::to_bytes`:
+//
+//   source address | dest address | packet type | control type | payload
+//
+// Addresses follow `write_ip`. The control type byte is always present and
+// must be zero unless the packet is a control packet, so each packet has
+// exactly one encoding.
+use crate::error::VpnError;
+use crate::protocol::packet::{read_ip, write_ip};
+use crate::protocol::{ControlType, PacketType, VpnPacket};
+use std::net::IpAddr;
+
+/// Encoding into a caller-provided buffer and decoding from a borrowed slice.
+pub trait PacketCodec<'a>: Sized {
+    /// Appends the encoding to `buf`.
+    fn encode_into(&self, buf: &mut Vec<u8>) -> Result<(), VpnError>;
+
+    /// Decodes a complete encoding.
+    fn decode_from(bytes: &'a [u8]) -> Result<Self, VpnError>;
+}
+
+/// A packet whose payload still points into the buffer it was decoded from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketView<'a> {
+    pub source_ip: IpAddr,
+    pub dest_ip: IpAddr,
+    pub packet_type: PacketType,
+    pub control_type: Option<ControlType>,
+    pub payload: &'a [u8],
+}
+
+impl PacketView<'_> {
+    pub fn to_packet(&self) -> VpnPacket {
+        VpnPacket {
+            source_ip: self.source_ip,
+            dest_ip: self.dest_ip,
+            packet_type: self.packet_type,
+            control_type: self.control_type,
+            payload: self.payload.to_vec(),
+        }
+    }
+}
+
+impl<'a> PacketCodec<'a> for PacketView<'a> {
+    fn encode_into(&self, buf: &mut Vec<u8>) -> Result<(), VpnError> {
+        let control_type = match (self.packet_type, self.control_type) {
+            (PacketType::Control, Some(control_type)) => control_type as u8,
+            (PacketType::Control, None) => {
+                return Err(VpnError::Protocol(
+                    "Control packet without a control type".into(),
+                ))
+            }
+            (_, Some(_)) => {
+                return Err(VpnError::Protocol(
+                    "Control type on a non-control packet".into(),
+                ))
+            }
+            (_, None) => 0,
+        };
+
+        write_ip(buf, self.source_ip);
+        write_ip(buf, self.dest_ip);
+        buf.push(self.packet_type as u8);
+        buf.push(control_type);
+        buf.extend_from_slice(self.payload);
+        Ok(())
+    }
+
+    fn decode_from(bytes: &'a [u8]) -> Result<Self, VpnError> {
+        let (source_ip, rest) = read_ip(bytes)?;
+        let (dest_ip, rest) = read_ip(rest)?;
+        let [packet_type, control_type, payload @ ..] = rest else {
+            return Err(VpnError::Protocol("Packet too short".into()));
+        };
+
+        let packet_type = PacketType::try_from(*packet_type)?;
+        let control_type = match (packet_type, *control_type) {
+            (PacketType::Control, control_type) => Some(ControlType::try_from(control_type)?),
+            (_, 0) => None,
+            (_, control_type) => {
+                return Err(VpnError::Protocol(format!(
+                    "Control type {} on a non-control packet",
+                    control_type
+                )))
+            }
+        };
+
+        Ok(Self {
+            source_ip,
+            dest_ip,
+            packet_type,
+            control_type,
+            payload,
+        })
+    }
+}
+
+impl<'a> PacketCodec<'a> for VpnPacket {
+    fn encode_into(&self, buf: &mut Vec<u8>) -> Result<(), VpnError> {
+        self.view().encode_into(buf)
+    }
+
+    fn decode_from(bytes: &'a [u8]) -> Result<Self, VpnError> {
+        PacketView::decode_from(bytes).map(|view| view.to_packet())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::net::Ipv4Addr;
+
+    fn any_ip() -> impl Strategy<Value = IpAddr> {
+        prop_oneof![
+            any::<[u8; 4]>().prop_map(IpAddr::from),
+            any::<[u8; 16]>().prop_map(IpAddr::from),
+        ]
+    }
+
+    // Every type the wire format knows, so new ones are covered as they are added
+    fn every<T: TryFrom<u8>>() -> Vec<T> {
+        (0..=u8::MAX)
+            .filter_map(|value| T::try_from(value).ok())
+            .collect()
+    }
+
+    fn any_packet() -> impl Strategy<Value = VpnPacket> {
+        let kind = prop_oneof![
+            proptest::sample::select(every::<PacketType>())
+                .prop_filter("control packets need a control type", |packet_type| {
+                    *packet_type != PacketType::Control
+                })
+                .prop_map(|packet_type| (packet_type, None)),
+            proptest::sample::select(every::<ControlType>())
+                .prop_map(|control_type| (PacketType::Control, Some(control_type))),
+        ];
+        (
+            any_ip(),
+            any_ip(),
+            kind,
+            proptest::collection::vec(any::<u8>(), 0..256),
+        )
+            .prop_map(
+                |(source_ip, dest_ip, (packet_type, control_type), payload)| VpnPacket {
+                    source_ip,
+                    dest_ip,
+                    packet_type,
+                    control_type,
+                    payload,
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn prop_round_trip(packet in any_packet()) {
+            let mut bytes = Vec::new();
+            packet.encode_into(&mut bytes).unwrap();
+            prop_assert_eq!(VpnPacket::decode_from(&bytes).unwrap(), packet);
+        }
+
+        #[test]
+        fn prop_decode_is_canonical(bytes in proptest::collection::vec(any::<u8>(), 0..64)) {
+            // Whatever decodes must encode back to exactly the same bytes
+            if let Ok(view) = PacketView::decode_from(&bytes) {
+                let mut encoded = Vec::new();
+                view.encode_into(&mut encoded).unwrap();
+                prop_assert_eq!(encoded, bytes);
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_type_is_generated() {
+        let packet_types = every::<PacketType>();
+        for packet_type in [
+            PacketType::Fragment,
+            PacketType::Compressed,
+            PacketType::Padded,
+        ] {
+            assert!(packet_types.contains(&packet_type));
+        }
+        let control_types = every::<ControlType>();
+        for control_type in [ControlType::Ping, ControlType::Pong, ControlType::Error] {
+            assert!(control_types.contains(&control_type));
+        }
+    }
+
+    #[test]
+    fn test_control_type_must_match_packet_type() {
+        let mut missing = VpnPacket::new_control(ControlType::Disconnect);
+        missing.control_type = None;
+        assert!(missing.encode_into(&mut Vec::new()).is_err());
+
+        let mut stray = VpnPacket::new_keepalive();
+        stray.control_type = Some(ControlType::ConfigRequest);
+        assert!(stray.encode_into(&mut Vec::new()).is_err());
+
+        let unspecified = Ipv4Addr::UNSPECIFIED.into();
+        let mut bytes = Vec::new();
+        write_ip(&mut bytes, unspecified);
+        write_ip(&mut bytes, unspecified);
+        bytes.extend_from_slice(&[PacketType::Data as u8, 3]);
+        assert!(PacketView::decode_from(&bytes).is_err());
+    }
+}
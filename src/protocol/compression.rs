@@ -0,0 +1,258 @@
+// Data packets whose payload shrinks when compressed are sent as packets of
+// type `Compressed`, keeping their addresses, with the payload replaced by
+//
+//   algorithm (1) | compressed payload
+//
+// A payload that doesn't get smaller goes out unchanged, so compression costs
+// at most the attempt. The decompressed size is bounded before anything is
+// allocated for it.
+use crate::error::VpnError;
+use crate::protocol::{Capabilities, PacketType, VpnPacket};
+
+// Shorter payloads rarely shrink by enough to be worth the attempt
+const MIN_COMPRESS_LEN: usize = 64;
+const ZSTD_LEVEL: i32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Compression {
+    Lz4 = 1,
+    Zstd = 2,
+}
+
+impl Compression {
+    /// Algorithm a session compresses with, the better one both peers agreed.
+    pub fn negotiated(capabilities: Capabilities) -> Option<Self> {
+        if capabilities.contains(Capabilities::ZSTD_COMPRESSION) {
+            Some(Compression::Zstd)
+        } else if capabilities.contains(Capabilities::LZ4_COMPRESSION) {
+            Some(Compression::Lz4)
+        } else {
+            None
+        }
+    }
+
+    pub fn capability(self) -> Capabilities {
+        match self {
+            Compression::Lz4 => Capabilities::LZ4_COMPRESSION,
+            Compression::Zstd => Capabilities::ZSTD_COMPRESSION,
+        }
+    }
+}
+
+impl TryFrom<u8> for Compression {
+    type Error = VpnError;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Compression::Lz4),
+            2 => Ok(Compression::Zstd),
+            _ => Err(VpnError::Protocol(format!(
+                "Invalid compression algorithm: {}",
+                value
+            ))),
+        }
+    }
+}
+
+/// Payload bytes of a session's data packets before and after compression.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    compressed_packets: u64,
+    skipped_packets: u64,
+    sent_original_bytes: u64,
+    sent_bytes: u64,
+    received_bytes: u64,
+    received_original_bytes: u64,
+}
+
+impl CompressionStats {
+    /// Packets sent compressed.
+    pub fn compressed_packets(&self) -> u64 {
+        self.compressed_packets
+    }
+
+    /// Packets sent as they were because compressing didn't shrink them.
+    pub fn skipped_packets(&self) -> u64 {
+        self.skipped_packets
+    }
+
+    pub fn sent_original_bytes(&self) -> u64 {
+        self.sent_original_bytes
+    }
+
+    pub fn sent_bytes(&self) -> u64 {
+        self.sent_bytes
+    }
+
+    pub fn received_bytes(&self) -> u64 {
+        self.received_bytes
+    }
+
+    pub fn received_original_bytes(&self) -> u64 {
+        self.received_original_bytes
+    }
+
+    /// Sent payload size relative to the original, 1.0 until something was
+    /// attempted. Lower is better.
+    pub fn ratio(&self) -> f64 {
+        Self::ratio_of(self.sent_bytes, self.sent_original_bytes)
+    }
+
+    /// Like `ratio`, for compressed packets the peer sent.
+    pub fn received_ratio(&self) -> f64 {
+        Self::ratio_of(self.received_bytes, self.received_original_bytes)
+    }
+
+    fn ratio_of(bytes: u64, original_bytes: u64) -> f64 {
+        if original_bytes == 0 {
+            1.0
+        } else {
+            bytes as f64 / original_bytes as f64
+        }
+    }
+}
+
+/// Compresses a data packet's payload if that makes it smaller.
+pub(crate) fn compress(
+    packet: VpnPacket,
+    algorithm: Compression,
+    stats: &mut CompressionStats,
+) -> VpnPacket {
+    if packet.packet_type != PacketType::Data || packet.payload.len() < MIN_COMPRESS_LEN {
+        return packet;
+    }
+
+    let compressed = match algorithm {
+        Compression::Lz4 => lz4_flex::block::compress_prepend_size(&packet.payload),
+        // Compressing an in-memory buffer only fails on allocation
+        Compression::Zstd => match zstd::bulk::compress(&packet.payload, ZSTD_LEVEL) {
+            Ok(compressed) => compressed,
+            Err(_) => return packet,
+        },
+    };
+
+    stats.sent_original_bytes += packet.payload.len() as u64;
+    if 1 + compressed.len() >= packet.payload.len() {
+        stats.skipped_packets += 1;
+        stats.sent_bytes += packet.payload.len() as u64;
+        return packet;
+    }
+    stats.compressed_packets += 1;
+    stats.sent_bytes += 1 + compressed.len() as u64;
+
+    let mut payload = Vec::with_capacity(1 + compressed.len());
+    payload.push(algorithm as u8);
+    payload.extend_from_slice(&compressed);
+    VpnPacket {
+        packet_type: PacketType::Compressed,
+        payload,
+        ..packet
+    }
+}
+
+/// Restores the data packet of a `Compressed` one, refusing algorithms that
+/// weren't negotiated and payloads that would exceed `max_len`.
+pub(crate) fn decompress(
+    packet: VpnPacket,
+    capabilities: Capabilities,
+    max_len: usize,
+    stats: &mut CompressionStats,
+) -> Result<VpnPacket, VpnError> {
+    let [algorithm, compressed @ ..] = packet.payload.as_slice() else {
+        return Err(VpnError::Protocol("Empty compressed packet".into()));
+    };
+    let algorithm = Compression::try_from(*algorithm)?;
+    if !capabilities.contains(algorithm.capability()) {
+        return Err(VpnError::Protocol(format!(
+            "{:?} compression was not negotiated",
+            algorithm
+        )));
+    }
+
+    let too_large = || {
+        VpnError::Protocol(format!(
+            "Compressed packet expands beyond {} bytes",
+            max_len
+        ))
+    };
+    let payload = match algorithm {
+        Compression::Lz4 => {
+            let (len, compressed) = lz4_flex::block::uncompressed_size(compressed)
+                .map_err(|e| VpnError::Protocol(format!("Invalid LZ4 payload: {}", e)))?;
+            if len > max_len {
+                return Err(too_large());
+            }
+            let payload = lz4_flex::block::decompress(compressed, len)
+                .map_err(|e| VpnError::Protocol(format!("Invalid LZ4 payload: {}", e)))?;
+            if payload.len() != len {
+                return Err(VpnError::Protocol("Invalid LZ4 payload length".into()));
+            }
+            payload
+        }
+        Compression::Zstd => zstd::bulk::decompress(compressed, max_len)
+            .map_err(|e| VpnError::Protocol(format!("Invalid zstd payload: {}", e)))?,
+    };
+
+    stats.received_bytes += packet.payload.len() as u64;
+    stats.received_original_bytes += payload.len() as u64;
+    Ok(VpnPacket {
+        packet_type: PacketType::Data,
+        payload,
+        ..packet
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(payload: Vec<u8>) -> VpnPacket {
+        VpnPacket::new_data([10, 0, 0, 1], [10, 0, 0, 2], payload)
+    }
+
+    #[test]
+    fn test_round_trip_and_stats() {
+        let log_line = b"level=info msg=\"request served\" status=200 path=/api/v1/items\n";
+        let original = data(log_line.repeat(50));
+
+        for algorithm in [Compression::Lz4, Compression::Zstd] {
+            let mut sent = CompressionStats::default();
+            let compressed = compress(original.clone(), algorithm, &mut sent);
+            assert_eq!(compressed.packet_type, PacketType::Compressed);
+            assert_eq!(compressed.payload[0], algorithm as u8);
+            assert_eq!(sent.compressed_packets(), 1);
+            assert!(sent.ratio() < 0.2);
+
+            let mut received = CompressionStats::default();
+            let restored =
+                decompress(compressed, Capabilities::SUPPORTED, 1 << 20, &mut received).unwrap();
+            assert_eq!(restored, original);
+            assert_eq!(received.received_ratio(), sent.ratio());
+        }
+    }
+
+    #[test]
+    fn test_incompressible_payload_sent_as_is() {
+        let mut noise = vec![0; 1000];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut noise);
+        let mut stats = CompressionStats::default();
+
+        let packet = compress(data(noise.clone()), Compression::Lz4, &mut stats);
+        assert_eq!(packet, data(noise));
+        assert_eq!(stats.skipped_packets(), 1);
+        assert_eq!(stats.ratio(), 1.0);
+    }
+
+    #[test]
+    fn test_decompression_bounded() {
+        let packet = compress(
+            data(vec![0; 10_000]),
+            Compression::Lz4,
+            &mut Default::default(),
+        );
+        let mut stats = CompressionStats::default();
+        assert!(decompress(packet.clone(), Capabilities::SUPPORTED, 9_999, &mut stats).is_err());
+        assert!(decompress(packet, Capabilities::ZSTD_COMPRESSION, 10_000, &mut stats).is_err());
+    }
+}
@@ -0,0 +1,854 @@
+// Every control payload is a sequence of fields
+//
+//   tag (1) | length (2) | value
+//
+// in any order. Fields with tags a message doesn't know are skipped, so
+// later versions can add fields without breaking older peers. A lone zero
+// byte is padding and is skipped as well.
+//
+// Any control message may also carry a transaction id field. A request
+// carries one and its response echoes it, which lets a client keep several
+// requests in flight and tell their responses from messages the server sends
+// on its own, which carry none.
+use crate::error::VpnError;
+use crate::protocol::packet::VpnPacket;
+use crate::protocol::ControlType;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+
+const PAD: u8 = 0;
+// Reserved in every message, never used for a message's own fields
+const TRANSACTION_ID: u8 = 0xff;
+
+/// A typed control message, the payload of packets of `CONTROL_TYPE`.
+pub trait ControlPayload: Sized {
+    const CONTROL_TYPE: ControlType;
+
+    fn encode(&self) -> Result<Vec<u8>, VpnError>;
+
+    fn decode(bytes: &[u8]) -> Result<Self, VpnError>;
+}
+
+impl VpnPacket {
+    /// A control packet carrying `message`.
+    pub fn from_control<T: ControlPayload>(message: &T) -> Result<Self, VpnError> {
+        let mut packet = VpnPacket::new_control(T::CONTROL_TYPE);
+        packet.set_payload(message.encode()?);
+        Ok(packet)
+    }
+
+    /// Decodes the payload as `T`, which must match the packet's control type.
+    pub fn to_control<T: ControlPayload>(&self) -> Result<T, VpnError> {
+        if self.control_type != Some(T::CONTROL_TYPE) {
+            return Err(VpnError::Protocol(format!(
+                "Expected a {:?} packet, got {:?}",
+                T::CONTROL_TYPE,
+                self.control_type
+            )));
+        }
+        T::decode(&self.payload)
+    }
+
+    /// Tags a control request, or the response to one, with `transaction_id`.
+    pub fn with_transaction_id(mut self, transaction_id: u32) -> Result<Self, VpnError> {
+        let field = TlvWriter::default()
+            .u32(TRANSACTION_ID, transaction_id)?
+            .finish();
+        self.payload.extend_from_slice(&field);
+        Ok(self)
+    }
+
+    /// Transaction id of a control packet, none for messages the peer sent
+    /// unprompted.
+    pub fn transaction_id(&self) -> Result<Option<u32>, VpnError> {
+        if !self.is_control() {
+            return Ok(None);
+        }
+        let fields = Fields::parse("Control message", &self.payload)?;
+        if fields.optional(TRANSACTION_ID, "transaction id")?.is_none() {
+            return Ok(None);
+        }
+        fields.u32(TRANSACTION_ID, "transaction id").map(Some)
+    }
+}
+
+/// Builds a TLV payload.
+#[derive(Default)]
+pub(crate) struct TlvWriter {
+    bytes: Vec<u8>,
+}
+
+impl TlvWriter {
+    pub fn field(&mut self, tag: u8, value: &[u8]) -> Result<&mut Self, VpnError> {
+        debug_assert_ne!(tag, PAD, "Tag 0 is padding");
+        let len = u16::try_from(value.len()).map_err(|_| {
+            VpnError::Protocol(format!(
+                "Field {} of {} bytes is too long to encode",
+                tag,
+                value.len()
+            ))
+        })?;
+        self.bytes.push(tag);
+        self.bytes.extend_from_slice(&len.to_be_bytes());
+        self.bytes.extend_from_slice(value);
+        Ok(self)
+    }
+
+    pub fn u32(&mut self, tag: u8, value: u32) -> Result<&mut Self, VpnError> {
+        self.field(tag, &value.to_be_bytes())
+    }
+
+    pub fn u64(&mut self, tag: u8, value: u64) -> Result<&mut Self, VpnError> {
+        self.field(tag, &value.to_be_bytes())
+    }
+
+    /// Addresses are 4 or 16 bytes, the length gives the family.
+    pub fn ip(&mut self, tag: u8, ip: IpAddr) -> Result<&mut Self, VpnError> {
+        match ip {
+            IpAddr::V4(ip) => self.field(tag, &ip.octets()),
+            IpAddr::V6(ip) => self.field(tag, &ip.octets()),
+        }
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+/// The fields of a TLV payload, borrowed from it. Lookups name the field so
+/// decode errors say what was wrong with which message.
+pub(crate) struct Fields<'a> {
+    message: &'static str,
+    fields: Vec<(u8, &'a [u8])>,
+}
+
+impl<'a> Fields<'a> {
+    pub fn parse(message: &'static str, mut bytes: &'a [u8]) -> Result<Self, VpnError> {
+        let mut fields = Vec::new();
+        while let Some((&tag, rest)) = bytes.split_first() {
+            if tag == PAD {
+                bytes = rest;
+                continue;
+            }
+            let Some((len, rest)) = rest.split_first_chunk::<2>() else {
+                return Err(VpnError::Protocol(format!(
+                    "{} field {} is missing its length",
+                    message, tag
+                )));
+            };
+            let len = u16::from_be_bytes(*len) as usize;
+            if rest.len() < len {
+                return Err(VpnError::Protocol(format!(
+                    "{} field {} needs {} bytes but only {} remain",
+                    message,
+                    tag,
+                    len,
+                    rest.len()
+                )));
+            }
+            let (value, rest) = rest.split_at(len);
+            fields.push((tag, value));
+            bytes = rest;
+        }
+        Ok(Self { message, fields })
+    }
+
+    /// Every value of a field that may repeat, in order.
+    pub fn repeated(&self, tag: u8) -> impl Iterator<Item = &'a [u8]> + '_ {
+        self.fields
+            .iter()
+            .filter(move |(field_tag, _)| *field_tag == tag)
+            .map(|(_, value)| *value)
+    }
+
+    pub fn optional(&self, tag: u8, name: &str) -> Result<Option<&'a [u8]>, VpnError> {
+        let mut values = self.repeated(tag);
+        let value = values.next();
+        if values.next().is_some() {
+            return Err(VpnError::Protocol(format!(
+                "{} has more than one {}",
+                self.message, name
+            )));
+        }
+        Ok(value)
+    }
+
+    pub fn required(&self, tag: u8, name: &str) -> Result<&'a [u8], VpnError> {
+        self.optional(tag, name)?
+            .ok_or_else(|| VpnError::Protocol(format!("{} is missing its {}", self.message, name)))
+    }
+
+    pub fn fixed<const N: usize>(&self, tag: u8, name: &str) -> Result<[u8; N], VpnError> {
+        let value = self.required(tag, name)?;
+        value.try_into().map_err(|_| {
+            VpnError::Protocol(format!(
+                "{} of {} must be {} bytes, got {}",
+                name,
+                self.message,
+                N,
+                value.len()
+            ))
+        })
+    }
+
+    pub fn u32(&self, tag: u8, name: &str) -> Result<u32, VpnError> {
+        self.fixed(tag, name).map(u32::from_be_bytes)
+    }
+
+    pub fn u64(&self, tag: u8, name: &str) -> Result<u64, VpnError> {
+        self.fixed(tag, name).map(u64::from_be_bytes)
+    }
+
+    pub fn ip(&self, tag: u8, name: &str) -> Result<IpAddr, VpnError> {
+        let value = self.required(tag, name)?;
+        if let Ok(octets) = <[u8; 4]>::try_from(value) {
+            Ok(Ipv4Addr::from(octets).into())
+        } else if let Ok(octets) = <[u8; 16]>::try_from(value) {
+            Ok(Ipv6Addr::from(octets).into())
+        } else {
+            Err(VpnError::Protocol(format!(
+                "{} of {} must be a 4 or 16 byte address, got {} bytes",
+                name,
+                self.message,
+                value.len()
+            )))
+        }
+    }
+}
+
+/// Asks the server for the session's configuration. No fields yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ConfigRequest;
+
+impl ControlPayload for ConfigRequest {
+    const CONTROL_TYPE: ControlType = ControlType::ConfigRequest;
+
+    fn encode(&self) -> Result<Vec<u8>, VpnError> {
+        Ok(Vec::new())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, VpnError> {
+        Fields::parse("Config request", bytes)?;
+        Ok(ConfigRequest)
+    }
+}
+
+/// Ends the session, sent by the client and echoed by the server. No fields
+/// yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Disconnect;
+
+impl ControlPayload for Disconnect {
+    const CONTROL_TYPE: ControlType = ControlType::Disconnect;
+
+    fn encode(&self) -> Result<Vec<u8>, VpnError> {
+        Ok(Vec::new())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, VpnError> {
+        Fields::parse("Disconnect", bytes)?;
+        Ok(Disconnect)
+    }
+}
+
+/// Ephemeral key of either side of a rekey.
+///
+/// Fields: 1 public key (32 bytes).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rekey {
+    pub public_key: [u8; 32],
+}
+
+impl ControlPayload for Rekey {
+    const CONTROL_TYPE: ControlType = ControlType::Rekey;
+
+    fn encode(&self) -> Result<Vec<u8>, VpnError> {
+        Ok(TlvWriter::default().field(1, &self.public_key)?.finish())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, VpnError> {
+        let public_key = Fields::parse("Rekey", bytes)?.fixed(1, "public key")?;
+        Ok(Self { public_key })
+    }
+}
+
+/// Probe for a candidate MTU. The sender pads the payload with zeros up to
+/// the size being probed; decoding ignores them.
+///
+/// Fields: 1 frame length (u32).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MtuProbe {
+    pub frame_len: u32,
+}
+
+impl ControlPayload for MtuProbe {
+    const CONTROL_TYPE: ControlType = ControlType::MtuProbe;
+
+    fn encode(&self) -> Result<Vec<u8>, VpnError> {
+        Ok(TlvWriter::default().u32(1, self.frame_len)?.finish())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, VpnError> {
+        let frame_len = Fields::parse("MTU probe", bytes)?.u32(1, "frame length")?;
+        Ok(Self { frame_len })
+    }
+}
+
+/// Confirms an `MtuProbe` of `frame_len` arrived.
+///
+/// Fields: 1 frame length (u32).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MtuProbeAck {
+    pub frame_len: u32,
+}
+
+impl ControlPayload for MtuProbeAck {
+    const CONTROL_TYPE: ControlType = ControlType::MtuProbeAck;
+
+    fn encode(&self) -> Result<Vec<u8>, VpnError> {
+        Ok(TlvWriter::default().u32(1, self.frame_len)?.finish())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, VpnError> {
+        let frame_len = Fields::parse("MTU probe ack", bytes)?.u32(1, "frame length")?;
+        Ok(Self { frame_len })
+    }
+}
+
+/// MTU the client asks for, and in the server's answer the one applied.
+///
+/// Fields: 1 MTU (u32).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MtuUpdate {
+    pub mtu: u32,
+}
+
+impl ControlPayload for MtuUpdate {
+    const CONTROL_TYPE: ControlType = ControlType::MtuUpdate;
+
+    fn encode(&self) -> Result<Vec<u8>, VpnError> {
+        Ok(TlvWriter::default().u32(1, self.mtu)?.finish())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, VpnError> {
+        let mtu = Fields::parse("MTU update", bytes)?.u32(1, "MTU")?;
+        Ok(Self { mtu })
+    }
+}
+
+/// Asks the peer for a `Pong`. `timestamp` is microseconds on the sender's
+/// own clock, only ever compared with that clock.
+///
+/// Fields: 1 sequence (u32), 2 timestamp (u64).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ping {
+    pub sequence: u32,
+    pub timestamp: u64,
+}
+
+impl Ping {
+    /// The answer to this ping.
+    pub fn pong(&self) -> Pong {
+        Pong {
+            sequence: self.sequence,
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+/// Echoes the sequence and timestamp of the `Ping` it answers.
+///
+/// Fields as for `Ping`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pong {
+    pub sequence: u32,
+    pub timestamp: u64,
+}
+
+fn encode_echo(sequence: u32, timestamp: u64) -> Result<Vec<u8>, VpnError> {
+    Ok(TlvWriter::default()
+        .u32(1, sequence)?
+        .u64(2, timestamp)?
+        .finish())
+}
+
+fn decode_echo(bytes: &[u8], message: &'static str) -> Result<(u32, u64), VpnError> {
+    let fields = Fields::parse(message, bytes)?;
+    Ok((fields.u32(1, "sequence")?, fields.u64(2, "timestamp")?))
+}
+
+impl ControlPayload for Ping {
+    const CONTROL_TYPE: ControlType = ControlType::Ping;
+
+    fn encode(&self) -> Result<Vec<u8>, VpnError> {
+        encode_echo(self.sequence, self.timestamp)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, VpnError> {
+        let (sequence, timestamp) = decode_echo(bytes, "Ping")?;
+        Ok(Self {
+            sequence,
+            timestamp,
+        })
+    }
+}
+
+impl ControlPayload for Pong {
+    const CONTROL_TYPE: ControlType = ControlType::Pong;
+
+    fn encode(&self) -> Result<Vec<u8>, VpnError> {
+        encode_echo(self.sequence, self.timestamp)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, VpnError> {
+        let (sequence, timestamp) = decode_echo(bytes, "Pong")?;
+        Ok(Self {
+            sequence,
+            timestamp,
+        })
+    }
+}
+
+/// Why a peer is ending the session, as carried by `ErrorMessage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// No protocol version both sides speak.
+    BadVersion,
+    /// The peer could not be authenticated, or its packets could not be.
+    AuthFailed,
+    /// The session went over a limit the server enforces.
+    QuotaExceeded,
+    /// The server is stopping.
+    ShuttingDown,
+    /// The peer sent something malformed or out of place.
+    ProtocolViolation,
+    /// The session is not, or no longer, known.
+    UnknownSession,
+    /// Something went wrong on the sender's side.
+    Internal,
+    /// A code from a later release.
+    Other(u16),
+}
+
+impl From<u16> for ErrorCode {
+    fn from(code: u16) -> Self {
+        match code {
+            1 => ErrorCode::BadVersion,
+            2 => ErrorCode::AuthFailed,
+            3 => ErrorCode::QuotaExceeded,
+            4 => ErrorCode::ShuttingDown,
+            5 => ErrorCode::ProtocolViolation,
+            6 => ErrorCode::UnknownSession,
+            7 => ErrorCode::Internal,
+            code => ErrorCode::Other(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::BadVersion => 1,
+            ErrorCode::AuthFailed => 2,
+            ErrorCode::QuotaExceeded => 3,
+            ErrorCode::ShuttingDown => 4,
+            ErrorCode::ProtocolViolation => 5,
+            ErrorCode::UnknownSession => 6,
+            ErrorCode::Internal => 7,
+            ErrorCode::Other(code) => code,
+        }
+    }
+}
+
+// Longer reasons are cut short, they are for people to read
+const MAX_REASON_LEN: usize = 512;
+
+/// Sent just before the sender drops the connection, saying why. The client
+/// reports it as `VpnError::Remote`.
+///
+/// Fields: 1 code (u16), 2 reason (UTF-8).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    pub reason: String,
+}
+
+impl ErrorMessage {
+    pub fn new(code: ErrorCode, reason: impl Into<String>) -> Self {
+        Self {
+            code,
+            reason: reason.into(),
+        }
+    }
+}
+
+impl ErrorCode {
+    /// A fixed description of the code, for messages that must not say more.
+    pub fn description(self) -> &'static str {
+        match self {
+            ErrorCode::BadVersion => "No protocol version in common",
+            ErrorCode::AuthFailed => "Authentication failed",
+            ErrorCode::QuotaExceeded => "Quota exceeded",
+            ErrorCode::ShuttingDown => "Server shutting down",
+            ErrorCode::ProtocolViolation => "Malformed or unexpected packet",
+            ErrorCode::UnknownSession => "Unknown session",
+            ErrorCode::Internal => "Internal server error",
+            ErrorCode::Other(_) => "Unknown error",
+        }
+    }
+}
+
+// What a server tells the client about an error it drops the connection for.
+// Only the code's description goes out, the details stay in the server's logs
+impl From<&VpnError> for ErrorMessage {
+    fn from(error: &VpnError) -> Self {
+        let code = match error {
+            VpnError::Protocol(_) | VpnError::ReplayedPacket(_) => ErrorCode::ProtocolViolation,
+            VpnError::KeyExchange(_) | VpnError::Encryption(_) => ErrorCode::AuthFailed,
+            VpnError::ClientNotFound => ErrorCode::UnknownSession,
+            VpnError::Remote(code, _) => *code,
+            _ => ErrorCode::Internal,
+        };
+        Self::new(code, code.description())
+    }
+}
+
+impl ControlPayload for ErrorMessage {
+    const CONTROL_TYPE: ControlType = ControlType::Error;
+
+    fn encode(&self) -> Result<Vec<u8>, VpnError> {
+        let mut len = self.reason.len().min(MAX_REASON_LEN);
+        while !self.reason.is_char_boundary(len) {
+            len -= 1;
+        }
+        let code = u16::from(self.code).to_be_bytes();
+        let reason = &self.reason.as_bytes()[..len];
+        Ok(TlvWriter::default()
+            .field(1, &code)?
+            .field(2, reason)?
+            .finish())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, VpnError> {
+        let fields = Fields::parse("Error message", bytes)?;
+        let code = fields.fixed(1, "code")?;
+        let reason = fields.optional(2, "reason")?.unwrap_or_default();
+        Ok(Self::new(
+            u16::from_be_bytes(code).into(),
+            String::from_utf8_lossy(reason),
+        ))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VpnConfig {
+    pub mtu: usize,
+    pub keepalive_interval: Duration,
+    pub reconnect_attempts: u32,
+}
+
+impl Default for VpnConfig {
+    fn default() -> Self {
+        Self {
+            mtu: 1500,
+            keepalive_interval: Duration::from_secs(30),
+            reconnect_attempts: 3,
+        }
+    }
+}
+
+/// A route to `target_network/prefix_len`, IPv4 or IPv6. The next hop may
+/// belong to the other family.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteEntry {
+    pub target_network: IpAddr,
+    pub prefix_len: u8,
+    pub next_hop: IpAddr,
+    pub metric: u32,
+}
+
+/// The session configuration the server answers a config request with.
+///
+/// Fields: 1 MTU (u32), 2 keepalive interval in seconds (u32), 3 reconnect
+/// attempts (u32).
+impl ControlPayload for VpnConfig {
+    const CONTROL_TYPE: ControlType = ControlType::ConfigResponse;
+
+    fn encode(&self) -> Result<Vec<u8>, VpnError> {
+        let mtu = u32::try_from(self.mtu)
+            .map_err(|_| VpnError::Config(format!("MTU {} does not fit a u32", self.mtu)))?;
+        let keepalive = u32::try_from(self.keepalive_interval.as_secs()).map_err(|_| {
+            VpnError::Config(format!(
+                "Keepalive interval of {}s does not fit a u32",
+                self.keepalive_interval.as_secs()
+            ))
+        })?;
+
+        Ok(TlvWriter::default()
+            .u32(1, mtu)?
+            .u32(2, keepalive)?
+            .u32(3, self.reconnect_attempts)?
+            .finish())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, VpnError> {
+        let fields = Fields::parse("Config", bytes)?;
+        Ok(Self {
+            mtu: fields.u32(1, "MTU")? as usize,
+            keepalive_interval: Duration::from_secs(fields.u32(2, "keepalive interval")? as u64),
+            reconnect_attempts: fields.u32(3, "reconnect attempts")?,
+        })
+    }
+}
+
+/// Routes a client announces, replacing the ones it announced before.
+///
+/// Fields: 1 route, repeated, itself made of the fields 1 target network
+/// (address), 2 prefix length (u8), 3 next hop (address) and 4 metric (u32).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RouteUpdate {
+    pub routes: Vec<RouteEntry>,
+}
+
+impl ControlPayload for RouteUpdate {
+    const CONTROL_TYPE: ControlType = ControlType::RouteUpdate;
+
+    fn encode(&self) -> Result<Vec<u8>, VpnError> {
+        let mut update = TlvWriter::default();
+        for route in &self.routes {
+            let route = TlvWriter::default()
+                .ip(1, route.target_network)?
+                .field(2, &[route.prefix_len])?
+                .ip(3, route.next_hop)?
+                .u32(4, route.metric)?
+                .finish();
+            update.field(1, &route)?;
+        }
+        Ok(update.finish())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, VpnError> {
+        let routes = Fields::parse("Route update", bytes)?
+            .repeated(1)
+            .map(|route| {
+                let fields = Fields::parse("Route", route)?;
+                let [prefix_len] = fields.fixed(2, "prefix length")?;
+                RouteEntry::new(
+                    fields.ip(1, "target network")?,
+                    prefix_len,
+                    fields.ip(3, "next hop")?,
+                    fields.u32(4, "metric")?,
+                )
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { routes })
+    }
+}
+
+/// The server's acknowledgement of a `RouteUpdate`. No fields yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RouteUpdateAck;
+
+impl ControlPayload for RouteUpdateAck {
+    const CONTROL_TYPE: ControlType = ControlType::RouteUpdateAck;
+
+    fn encode(&self) -> Result<Vec<u8>, VpnError> {
+        Ok(Vec::new())
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self, VpnError> {
+        Fields::parse("Route update ack", bytes)?;
+        Ok(RouteUpdateAck)
+    }
+}
+
+impl RouteEntry {
+    pub fn new(
+        target_network: IpAddr,
+        prefix_len: u8,
+        next_hop: IpAddr,
+        metric: u32,
+    ) -> Result<Self, VpnError> {
+        if prefix_len > Self::max_prefix_len(target_network) {
+            return Err(VpnError::Config(format!(
+                "Invalid prefix length /{} for {}",
+                prefix_len, target_network
+            )));
+        }
+        Ok(Self {
+            target_network,
+            prefix_len,
+            next_hop,
+            metric,
+        })
+    }
+
+    /// Whether `ip` falls inside this route's prefix.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.target_network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+
+    fn max_prefix_len(ip: IpAddr) -> u8 {
+        match ip {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_fields_and_padding_skipped() {
+        let mut bytes = TlvWriter::default()
+            .field(9, b"from a newer release")
+            .unwrap()
+            .u32(1, 1400)
+            .unwrap()
+            .finish();
+        bytes.extend_from_slice(&[PAD; 3]);
+
+        let update = MtuUpdate::decode(&bytes).unwrap();
+        assert_eq!(update, MtuUpdate { mtu: 1400 });
+        assert_eq!(update.encode().unwrap(), [1, 0, 4, 0, 0, 5, 120]);
+
+        // Probes are padded with zeros up to the size they probe
+        let mut probe = MtuProbe { frame_len: 1500 }.encode().unwrap();
+        probe.resize(100, 0);
+        assert_eq!(MtuProbe::decode(&probe).unwrap().frame_len, 1500);
+    }
+
+    #[test]
+    fn test_decode_errors_name_the_problem() {
+        let error = |bytes: &[u8]| match MtuUpdate::decode(bytes) {
+            Err(VpnError::Protocol(message)) => message,
+            other => panic!("Expected a protocol error, got {:?}", other),
+        };
+
+        assert_eq!(error(&[]), "MTU update is missing its MTU");
+        assert_eq!(
+            error(&[1, 0, 2, 5, 120]),
+            "MTU of MTU update must be 4 bytes, got 2"
+        );
+        assert_eq!(
+            error(&[1, 0, 4, 0, 0]),
+            "MTU update field 1 needs 4 bytes but only 2 remain"
+        );
+        assert_eq!(error(&[1, 0]), "MTU update field 1 is missing its length");
+        assert_eq!(
+            error(&[1, 0, 4, 0, 0, 5, 120, 1, 0, 4, 0, 0, 5, 120]),
+            "MTU update has more than one MTU"
+        );
+    }
+
+    #[test]
+    fn test_transaction_id_ignored_by_messages() {
+        let update = MtuUpdate { mtu: 1400 };
+        let packet = VpnPacket::from_control(&update)
+            .unwrap()
+            .with_transaction_id(7)
+            .unwrap();
+        assert_eq!(packet.transaction_id().unwrap(), Some(7));
+        assert_eq!(packet.to_control::<MtuUpdate>().unwrap(), update);
+
+        // Unsolicited
+        let push = VpnPacket::from_control(&update).unwrap();
+        assert_eq!(push.transaction_id().unwrap(), None);
+    }
+
+    #[test]
+    fn test_error_codes_survive_unknown() {
+        let error = ErrorMessage::new(ErrorCode::QuotaExceeded, "Too many bytes today");
+        let encoded = error.encode().unwrap();
+        assert_eq!(ErrorMessage::decode(&encoded).unwrap(), error);
+
+        let newer = ErrorMessage::new(ErrorCode::Other(42), "é".repeat(MAX_REASON_LEN));
+        let decoded = ErrorMessage::decode(&newer.encode().unwrap()).unwrap();
+        assert_eq!(decoded.code, ErrorCode::Other(42));
+        assert_eq!(decoded.reason, "é".repeat(MAX_REASON_LEN / 2));
+    }
+
+    #[test]
+    fn test_route_update_ack_not_an_update() {
+        let ack = VpnPacket::from_control(&RouteUpdateAck).unwrap();
+        assert_eq!(ack.control_type(), Some(ControlType::RouteUpdateAck));
+        assert!(ack.to_control::<RouteUpdate>().is_err());
+        assert!(ack.to_control::<RouteUpdateAck>().is_ok());
+    }
+
+    #[test]
+    fn test_control_type_checked() {
+        let packet = VpnPacket::from_control(&MtuUpdate { mtu: 1400 }).unwrap();
+        assert_eq!(packet.control_type(), Some(ControlType::MtuUpdate));
+        assert!(packet.to_control::<MtuProbeAck>().is_err());
+        assert_eq!(
+            packet.to_control::<MtuUpdate>().unwrap(),
+            MtuUpdate { mtu: 1400 }
+        );
+    }
+
+    #[test]
+    fn test_dual_stack_routes_round_trip() {
+        let routes = vec![
+            RouteEntry::new(
+                "10.8.0.0".parse().unwrap(),
+                16,
+                "10.8.0.1".parse().unwrap(),
+                5,
+            )
+            .unwrap(),
+            RouteEntry::new(
+                "fd00:8::".parse().unwrap(),
+                48,
+                "fd00:8::1".parse().unwrap(),
+                1,
+            )
+            .unwrap(),
+        ];
+
+        let update = RouteUpdate { routes };
+        let bytes = update.encode().unwrap();
+        assert_eq!(RouteUpdate::decode(&bytes).unwrap(), update);
+        assert!(RouteUpdate::decode(&bytes[..bytes.len() - 1]).is_err());
+
+        let routes = update.routes;
+
+        assert!(routes[0].contains("10.8.200.3".parse().unwrap()));
+        assert!(!routes[0].contains("10.9.0.1".parse().unwrap()));
+        assert!(routes[1].contains("fd00:8:0:1::9".parse().unwrap()));
+        assert!(!routes[1].contains("10.8.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_config_fields_checked() {
+        let config = VpnConfig::default();
+        let bytes = config.encode().unwrap();
+        let decoded = VpnConfig::decode(&bytes).unwrap();
+        assert_eq!(decoded.mtu, config.mtu);
+        assert_eq!(decoded.keepalive_interval, config.keepalive_interval);
+        // Trailing bytes are no longer silently ignored
+        assert!(VpnConfig::decode(&[bytes.as_slice(), &[1]].concat()).is_err());
+
+        let missing_mtu = TlvWriter::default().u32(2, 30).unwrap().finish();
+        assert!(matches!(
+            VpnConfig::decode(&missing_mtu),
+            Err(VpnError::Protocol(message)) if message == "Config is missing its MTU"
+        ));
+    }
+}
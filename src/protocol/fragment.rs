@@ -0,0 +1,247 @@
+// Packets too large for the MTU are encoded once and the encoding is split
+// into fragments, each sent as its own transport packet of type `Fragment`:
+//
+//   packet id (4) | offset (4) | total length (4) | data
+//
+// Fragments are authenticated like any other packet, but the byte ranges are
+// still treated with suspicion: a fragment that overlaps data already
+// received, or disagrees about the total length, discards the whole packet
+// instead of picking a winner.
+use crate::error::VpnError;
+use crate::protocol::{PacketType, VpnPacket};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+pub const FRAGMENT_HEADER_LEN: usize = 12;
+
+/// Bounds on what a peer can make us buffer while reassembling.
+#[derive(Debug, Clone, Copy)]
+pub struct ReassemblyLimits {
+    /// How long an incomplete packet waits for its remaining fragments.
+    pub timeout: Duration,
+    /// Bytes buffered for incomplete packets at once, per session. Also caps
+    /// the size of a single reassembled packet and of a decompressed payload.
+    pub max_pending_bytes: usize,
+}
+
+impl Default for ReassemblyLimits {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            max_pending_bytes: 1 << 20,
+        }
+    }
+}
+
+/// Splits `encoded` into fragment packets carrying at most `max_data` bytes each.
+pub(crate) fn split(encoded: &[u8], packet_id: u32, max_data: usize) -> Vec<VpnPacket> {
+    encoded
+        .chunks(max_data)
+        .enumerate()
+        .map(|(index, data)| {
+            let mut payload = Vec::with_capacity(FRAGMENT_HEADER_LEN + data.len());
+            payload.extend_from_slice(&packet_id.to_be_bytes());
+            payload.extend_from_slice(&((index * max_data) as u32).to_be_bytes());
+            payload.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+            payload.extend_from_slice(data);
+            new_fragment(payload)
+        })
+        .collect()
+}
+
+/// A fragment packet; `payload` starts with the fragment header.
+pub(crate) fn new_fragment(payload: Vec<u8>) -> VpnPacket {
+    VpnPacket {
+        source_ip: Ipv4Addr::UNSPECIFIED.into(),
+        dest_ip: Ipv4Addr::UNSPECIFIED.into(),
+        packet_type: PacketType::Fragment,
+        control_type: None,
+        payload,
+    }
+}
+
+struct PendingPacket {
+    data: Vec<u8>,
+    // Byte ranges received so far, kept sorted by start
+    ranges: Vec<(usize, usize)>,
+    received: usize,
+    started: Instant,
+}
+
+pub(crate) struct Reassembler {
+    pending: HashMap<u32, PendingPacket>,
+    pending_bytes: usize,
+    limits: ReassemblyLimits,
+}
+
+impl Reassembler {
+    pub fn new(limits: ReassemblyLimits) -> Self {
+        Self {
+            pending: HashMap::new(),
+            pending_bytes: 0,
+            limits,
+        }
+    }
+
+    pub fn limits(&self) -> ReassemblyLimits {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: ReassemblyLimits) {
+        self.limits = limits;
+    }
+
+    /// Adds a fragment payload, returning the packet's encoding once every
+    /// byte of it has arrived.
+    pub fn insert(&mut self, fragment: &[u8]) -> Result<Option<Vec<u8>>, VpnError> {
+        self.expire();
+
+        if fragment.len() <= FRAGMENT_HEADER_LEN {
+            return Err(VpnError::Protocol("Truncated fragment".into()));
+        }
+        let field = |at: usize| {
+            u32::from_be_bytes(fragment[at..at + 4].try_into().expect("Length checked"))
+        };
+        let (packet_id, offset, total_len) = (field(0), field(4) as usize, field(8) as usize);
+        let data = &fragment[FRAGMENT_HEADER_LEN..];
+        let end = offset + data.len();
+
+        if end > total_len {
+            self.discard(packet_id);
+            return Err(VpnError::Protocol(format!(
+                "Fragment of packet {} ends past its length",
+                packet_id
+            )));
+        }
+        if total_len > self.limits.max_pending_bytes {
+            return Err(VpnError::Protocol(format!(
+                "Fragmented packet of {} bytes exceeds the reassembly limit",
+                total_len
+            )));
+        }
+
+        if !self.pending.contains_key(&packet_id) {
+            if self.pending_bytes + total_len > self.limits.max_pending_bytes {
+                return Err(VpnError::Protocol(
+                    "Too many incomplete fragmented packets".into(),
+                ));
+            }
+            self.pending_bytes += total_len;
+            self.pending.insert(
+                packet_id,
+                PendingPacket {
+                    data: vec![0; total_len],
+                    ranges: Vec::new(),
+                    received: 0,
+                    started: Instant::now(),
+                },
+            );
+        }
+
+        let pending = self.pending.get_mut(&packet_id).expect("Inserted above");
+        let position = pending.ranges.partition_point(|&(start, _)| start < offset);
+        let overlaps_previous = position > 0 && pending.ranges[position - 1].1 > offset;
+        let overlaps_next = pending
+            .ranges
+            .get(position)
+            .is_some_and(|&(start, _)| start < end);
+        if pending.data.len() != total_len || overlaps_previous || overlaps_next {
+            self.discard(packet_id);
+            return Err(VpnError::Protocol(format!(
+                "Conflicting fragment for packet {}, discarding it",
+                packet_id
+            )));
+        }
+
+        pending.data[offset..end].copy_from_slice(data);
+        pending.ranges.insert(position, (offset, end));
+        pending.received += data.len();
+        if pending.received < total_len {
+            return Ok(None);
+        }
+
+        let complete = self.pending.remove(&packet_id).expect("Looked up above");
+        self.pending_bytes -= total_len;
+        Ok(Some(complete.data))
+    }
+
+    fn discard(&mut self, packet_id: u32) {
+        if let Some(pending) = self.pending.remove(&packet_id) {
+            self.pending_bytes -= pending.data.len();
+        }
+    }
+
+    fn expire(&mut self) {
+        let timeout = self.limits.timeout;
+        let mut expired_bytes = 0;
+        self.pending.retain(|_, pending| {
+            let keep = pending.started.elapsed() < timeout;
+            if !keep {
+                expired_bytes += pending.data.len();
+            }
+            keep
+        });
+        self.pending_bytes -= expired_bytes;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payloads(encoded: &[u8], max_data: usize) -> Vec<Vec<u8>> {
+        split(encoded, 7, max_data)
+            .into_iter()
+            .map(|fragment| fragment.payload)
+            .collect()
+    }
+
+    #[test]
+    fn test_reassembles_out_of_order() {
+        let encoded: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        let mut fragments = payloads(&encoded, 300);
+        assert_eq!(fragments.len(), 4);
+        fragments.swap(0, 3);
+
+        let mut reassembler = Reassembler::new(ReassemblyLimits::default());
+        let last = fragments.pop().unwrap();
+        for fragment in &fragments {
+            assert_eq!(reassembler.insert(fragment).unwrap(), None);
+        }
+        assert_eq!(reassembler.insert(&last).unwrap(), Some(encoded));
+        assert_eq!(reassembler.pending_bytes, 0);
+    }
+
+    #[test]
+    fn test_overlapping_fragment_discards_packet() {
+        let encoded = vec![1u8; 100];
+        let fragments = payloads(&encoded, 40);
+        let mut reassembler = Reassembler::new(ReassemblyLimits::default());
+        reassembler.insert(&fragments[0]).unwrap();
+
+        // Same packet id and length, but starting inside the first fragment
+        let mut overlapping = fragments[1].clone();
+        overlapping[4..8].copy_from_slice(&20u32.to_be_bytes());
+        assert!(reassembler.insert(&overlapping).is_err());
+        assert!(reassembler.pending.is_empty());
+        assert_eq!(reassembler.pending_bytes, 0);
+    }
+
+    #[test]
+    fn test_limits_enforced() {
+        let mut reassembler = Reassembler::new(ReassemblyLimits {
+            timeout: Duration::ZERO,
+            max_pending_bytes: 150,
+        });
+        assert!(reassembler.insert(&payloads(&[0; 200], 50)[0]).is_err());
+
+        // Incomplete packets time out and free their memory
+        reassembler.insert(&payloads(&[0; 100], 50)[0]).unwrap();
+        reassembler
+            .insert(&split(&[0; 100], 8, 50)[0].payload)
+            .unwrap();
+        assert_eq!(reassembler.pending.len(), 1);
+        assert_eq!(reassembler.pending_bytes, 100);
+    }
+}
@@ -1,63 +1,761 @@
+use crate::crypto::{CipherSuite, SessionKeys};
 use crate::error::VpnError;
-use crate::protocol::ControlType;
+use crate::protocol::compression::{self, Compression, CompressionStats};
+use crate::protocol::fragment::{self, Reassembler, ReassemblyLimits, FRAGMENT_HEADER_LEN};
+use crate::protocol::header::{MessageType, PacketHeader, HEADER_LEN, PROTOCOL_VERSION};
+use crate::protocol::padding::{self, PaddingPolicy, PADDING_HEADER_LEN};
+use crate::protocol::Capabilities;
+use crate::protocol::MtuProbe;
+use crate::protocol::PacketCodec;
 use crate::protocol::PacketType;
 use crate::protocol::VpnPacket;
 use crate::EncryptionManager;
 
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use zeroize::Zeroize;
+
+/// Smallest MTU a session accepts, the IPv4 minimum.
+pub const MIN_MTU: usize = 576;
+/// Largest frame the length-prefixed transport carries.
+pub const MAX_MTU: usize = 65535;
+const DEFAULT_MTU: usize = 1500;
+
+/// Limits after which a session switches to fresh keys.
+#[derive(Clone, Copy, Debug)]
+pub struct RekeyPolicy {
+    pub after_time: Duration,
+    pub after_packets: u64,
+    pub after_bytes: u64,
+    /// How long the previous keys still decrypt in-flight packets.
+    pub grace_period: Duration,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        Self {
+            after_time: Duration::from_secs(120),
+            after_packets: 1 << 32,
+            after_bytes: 1 << 36,
+            grace_period: Duration::from_secs(10),
+        }
+    }
+}
+
+struct Keypair {
+    id: u8,
+    sending: EncryptionManager,
+    receiving: EncryptionManager,
+    rekey_secret: [u8; 32],
+    created: Instant,
+    packets: u64,
+    bytes: u64,
+}
+
+impl Keypair {
+    fn new(id: u8, suite: CipherSuite, keys: &SessionKeys) -> Self {
+        Self {
+            id,
+            sending: EncryptionManager::new(suite, &keys.sending),
+            receiving: EncryptionManager::new(suite, &keys.receiving),
+            rekey_secret: keys.rekey_secret,
+            created: Instant::now(),
+            packets: 0,
+            bytes: 0,
+        }
+    }
+
+    fn record_usage(&mut self, bytes: usize) {
+        self.packets += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+impl Drop for Keypair {
+    fn drop(&mut self) {
+        self.rekey_secret.zeroize();
+    }
+}
+
+struct KeyState {
+    suite: CipherSuite,
+    current: Keypair,
+    // Retired keys and the moment they stop being accepted
+    previous: Option<(Keypair, Instant)>,
+    // Keys the peer asked to switch to, used once it sends under them
+    next: Option<Keypair>,
+    policy: RekeyPolicy,
+}
+
+impl KeyState {
+    fn switch_to(&mut self, next: Keypair) {
+        let retired = std::mem::replace(&mut self.current, next);
+        let expires = Instant::now() + self.policy.grace_period;
+        self.previous = Some((retired, expires));
+        self.next = None;
+    }
+}
+
+struct FragmentState {
+    // Largest frame we send, header and tag included
+    mtu: usize,
+    next_packet_id: u32,
+    reassembler: Reassembler,
+}
+
+// Clones share key state, so a rekey is seen by every holder of the session.
 #[derive(Clone)]
 pub struct ProtocolHandler {
-    encryption: EncryptionManager,
+    session_id: u32,
+    version: u8,
+    capabilities: Capabilities,
+    keys: Arc<Mutex<KeyState>>,
+    fragments: Arc<Mutex<FragmentState>>,
+    compression: Arc<Mutex<CompressionStats>>,
+    padding: Arc<Mutex<PaddingPolicy>>,
 }
 
 impl ProtocolHandler {
-    pub fn new(encryption: EncryptionManager) -> Self {
-        Self { encryption }
+    pub fn from_session_keys(suite: CipherSuite, session_id: u32, keys: &SessionKeys) -> Self {
+        Self {
+            session_id,
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::DEFAULT,
+            keys: Arc::new(Mutex::new(KeyState {
+                suite,
+                current: Keypair::new(0, suite, keys),
+                previous: None,
+                next: None,
+                policy: RekeyPolicy::default(),
+            })),
+            fragments: Arc::new(Mutex::new(FragmentState {
+                mtu: DEFAULT_MTU,
+                next_packet_id: 0,
+                reassembler: Reassembler::new(ReassemblyLimits::default()),
+            })),
+            compression: Arc::new(Mutex::new(CompressionStats::default())),
+            padding: Arc::new(Mutex::new(PaddingPolicy::default())),
+        }
     }
 
+    /// Uses the protocol version and capabilities agreed in the handshake
+    /// instead of this build's own.
+    pub fn with_negotiated(mut self, version: u8, capabilities: Capabilities) -> Self {
+        self.version = version;
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Protocol version stamped on, and required of, every packet.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Session id the server assigned during the handshake.
+    pub fn session_id(&self) -> u32 {
+        self.session_id
+    }
+
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.keys.lock().expect("Keys in use").suite
+    }
+
+    pub fn set_rekey_policy(&self, policy: RekeyPolicy) {
+        self.keys.lock().expect("Keys in use").policy = policy;
+    }
+
+    /// Largest frame this session sends.
+    pub fn mtu(&self) -> usize {
+        self.fragments.lock().expect("Fragments in use").mtu
+    }
+
+    pub fn set_mtu(&self, mtu: usize) -> Result<(), VpnError> {
+        if !(MIN_MTU..=MAX_MTU).contains(&mtu) {
+            return Err(VpnError::Config(format!(
+                "MTU {} is outside {}-{}",
+                mtu, MIN_MTU, MAX_MTU
+            )));
+        }
+        self.fragments.lock().expect("Fragments in use").mtu = mtu;
+        Ok(())
+    }
+
+    pub fn set_reassembly_limits(&self, limits: ReassemblyLimits) {
+        self.fragments
+            .lock()
+            .expect("Fragments in use")
+            .reassembler
+            .set_limits(limits);
+    }
+
+    /// Pads every frame sent from now on. Has no effect unless the peer
+    /// agreed to strip padding.
+    pub fn set_padding_policy(&self, policy: PaddingPolicy) -> Result<(), VpnError> {
+        policy.check()?;
+        *self.padding.lock().expect("Padding in use") = policy;
+        Ok(())
+    }
+
+    /// How well the session's data packets compressed so far.
+    pub fn compression_stats(&self) -> CompressionStats {
+        *self.compression.lock().expect("Compression stats in use")
+    }
+
+    /// Whether the current keys have reached any limit of the rekey policy.
+    pub fn needs_rekey(&self) -> bool {
+        let keys = self.keys.lock().expect("Keys in use");
+        let current = &keys.current;
+
+        current.created.elapsed() >= keys.policy.after_time
+            || current.packets >= keys.policy.after_packets
+            || current.bytes >= keys.policy.after_bytes
+    }
+
+    /// Switches to the next key generation derived from `shared_secret`.
+    ///
+    /// The initiator switches at once, having heard back from its peer. The
+    /// responder keeps using the current keys until the first packet under the
+    /// new ones arrives, so a lost reply can be asked for again; a later rekey
+    /// before then replaces the new keys. The replaced keys keep decrypting
+    /// for the policy's grace period.
+    pub fn rekey(&self, shared_secret: &[u8; 32], initiator: bool) -> Result<(), VpnError> {
+        let mut keys = self.keys.lock().expect("Keys in use");
+        let next =
+            SessionKeys::next_generation(&keys.current.rekey_secret, shared_secret, initiator)?;
+
+        let next = Keypair::new(keys.current.id.wrapping_add(1), keys.suite, &next);
+        if initiator {
+            keys.switch_to(next);
+        } else {
+            keys.next = Some(next);
+        }
+        Ok(())
+    }
+
+    /// Packs a packet that must fit the MTU in a single frame.
     pub fn pack(&self, packet: VpnPacket) -> Result<Vec<u8>, VpnError> {
+        let packet = self.compress(packet);
         let mut data = Vec::new();
-        data.extend_from_slice(&packet.source_ip);
-        data.extend_from_slice(&packet.dest_ip);
-        data.push(packet.packet_type as u8);
-        data.push(packet.control_type.unwrap_or(ControlType::ConfigRequest) as u8);
-        data.extend_from_slice(&packet.payload);
+        packet.encode_into(&mut data)?;
 
-        self.encryption.encrypt(&data)
+        let mtu = self.mtu();
+        let padding = self.padding();
+        if self.overhead(padding.as_ref())? + data.len() > mtu {
+            return Err(VpnError::Protocol(format!(
+                "Packet of {} bytes exceeds the MTU of {}",
+                data.len(),
+                mtu
+            )));
+        }
+        self.seal_padded(&data, padding.as_ref(), mtu)
     }
 
-    pub fn unpack(&self, data: &[u8]) -> Result<VpnPacket, VpnError> {
-        let decrypted = self.encryption.decrypt(data)?;
+    /// Packs a packet of any size, splitting it into fragments when it
+    /// doesn't fit the MTU and the peer agreed to reassemble them.
+    pub fn pack_fragments(&self, packet: VpnPacket) -> Result<Vec<Vec<u8>>, VpnError> {
+        let packet = self.compress(packet);
+        let mut data = Vec::new();
+        packet.encode_into(&mut data)?;
 
-        if decrypted.len() < 8 {
-            return Err("Invalid packet size".into());
+        let (mtu, packet_id) = {
+            let mut fragments = self.fragments.lock().expect("Fragments in use");
+            let packet_id = fragments.next_packet_id;
+            fragments.next_packet_id = packet_id.wrapping_add(1);
+            (fragments.mtu, packet_id)
+        };
+        let padding = self.padding();
+        let overhead = self.overhead(padding.as_ref())?;
+        if overhead + data.len() <= mtu {
+            return Ok(vec![self.seal_padded(&data, padding.as_ref(), mtu)?]);
+        }
+        if !self.capabilities.contains(Capabilities::FRAGMENTATION) {
+            return Err(VpnError::Protocol(format!(
+                "Packet of {} bytes exceeds the MTU of {} and the peer can't reassemble fragments",
+                data.len(),
+                mtu
+            )));
         }
 
-        let mut source_ip = [0u8; 4];
-        let mut dest_ip = [0u8; 4];
-        source_ip.copy_from_slice(&decrypted[0..4]);
-        dest_ip.copy_from_slice(&decrypted[4..8]);
-        let payload = decrypted[10..].to_vec();
+        // Room left for fragment data once every layer has taken its share
+        let mut empty_fragment = Vec::new();
+        fragment::new_fragment(Vec::new()).encode_into(&mut empty_fragment)?;
+        let max_data = mtu - overhead - empty_fragment.len() - FRAGMENT_HEADER_LEN;
 
-        // Extract and validate packet type
-        let packet_type = PacketType::try_from(decrypted[8])
-            .map_err(|_| VpnError::Protocol("Invalid packet type".into()))?;
+        fragment::split(&data, packet_id, max_data)
+            .into_iter()
+            .map(|fragment| {
+                let mut data = Vec::new();
+                fragment.encode_into(&mut data)?;
+                self.seal_padded(&data, padding.as_ref(), mtu)
+            })
+            .collect()
+    }
 
-        // Extract and validate control type if present
-        let control_type = if packet_type == PacketType::Control {
-            Some(
-                ControlType::try_from(decrypted[9])
-                    .map_err(|_| VpnError::Protocol("Invalid control type".into()))?,
-            )
-        } else {
-            None
+    /// Packs an MTU probe whose frame is exactly `frame_len` bytes. It may
+    /// exceed the current MTU, which is the point of probing.
+    ///
+    /// The payload names `frame_len` so the ack can name the probe, and
+    /// carries `transaction_id` like any other request.
+    pub fn pack_probe(&self, frame_len: usize, transaction_id: u32) -> Result<Vec<u8>, VpnError> {
+        let message = MtuProbe {
+            frame_len: frame_len as u32,
+        };
+        let mut probe = VpnPacket::from_control(&message)?.with_transaction_id(transaction_id)?;
+        let mut unpadded = Vec::new();
+        probe.encode_into(&mut unpadded)?;
+        let unpadded_len = HEADER_LEN + unpadded.len() + self.cipher_suite().overhead();
+        if !(unpadded_len..=MAX_MTU).contains(&frame_len) {
+            return Err(VpnError::Protocol(format!(
+                "Cannot probe an MTU of {}",
+                frame_len
+            )));
+        }
+
+        // Zeros decode as nothing
+        let padded_len = probe.payload.len() + frame_len - unpadded_len;
+        probe.payload.resize(padded_len, 0);
+
+        let mut data = Vec::new();
+        probe.encode_into(&mut data)?;
+        self.seal(&data)
+    }
+
+    // Padding in effect, none unless the peer strips it
+    fn padding(&self) -> Option<PaddingPolicy> {
+        let policy = self.padding.lock().expect("Padding in use").clone();
+        (policy != PaddingPolicy::None && self.capabilities.contains(Capabilities::PADDING))
+            .then_some(policy)
+    }
+
+    // Bytes a frame adds to the packet encoding it carries
+    fn overhead(&self, padding: Option<&PaddingPolicy>) -> Result<usize, VpnError> {
+        let mut overhead = HEADER_LEN + self.cipher_suite().overhead();
+        if padding.is_some() {
+            let mut empty_padded = Vec::new();
+            padding::new_padded(Vec::new()).encode_into(&mut empty_padded)?;
+            overhead += empty_padded.len() + PADDING_HEADER_LEN;
+        }
+        Ok(overhead)
+    }
+
+    fn seal_padded(
+        &self,
+        data: &[u8],
+        padding: Option<&PaddingPolicy>,
+        mtu: usize,
+    ) -> Result<Vec<u8>, VpnError> {
+        let Some(policy) = padding else {
+            return self.seal(data);
+        };
+        let len = self.overhead(padding)? + data.len();
+        let padded = padding::pad(data, policy.target_len(len, mtu) - len);
+
+        let mut data = Vec::new();
+        padded.encode_into(&mut data)?;
+        self.seal(&data)
+    }
+
+    fn compress(&self, packet: VpnPacket) -> VpnPacket {
+        match Compression::negotiated(self.capabilities) {
+            Some(algorithm) => compression::compress(
+                packet,
+                algorithm,
+                &mut self.compression.lock().expect("Compression stats in use"),
+            ),
+            None => packet,
+        }
+    }
+
+    fn seal(&self, data: &[u8]) -> Result<Vec<u8>, VpnError> {
+        let mut keys = self.keys.lock().expect("Keys in use");
+        keys.current.record_usage(data.len());
+
+        // Key id tells the receiver which generation to decrypt with
+        let counter = keys.current.sending.next_counter()?;
+        let header = PacketHeader::new(
+            self.version,
+            MessageType::Transport,
+            keys.current.id,
+            self.session_id,
+            counter,
+        )
+        .to_bytes();
+
+        let mut packed = header.to_vec();
+        packed.extend_from_slice(&keys.current.sending.encrypt(counter, &header, data)?);
+        Ok(packed)
+    }
+
+    /// Unpacks a frame, holding fragments back until their packet is whole
+    /// and decompressing what was compressed.
+    pub fn receive(&self, data: &[u8]) -> Result<Option<VpnPacket>, VpnError> {
+        let packet = self.unpad(self.unpack(data)?)?;
+        if packet.packet_type != PacketType::Fragment {
+            return self.decompress(packet).map(Some);
+        }
+        if !self.capabilities.contains(Capabilities::FRAGMENTATION) {
+            return Err(VpnError::Protocol(
+                "Fragmentation was not negotiated".into(),
+            ));
+        }
+
+        let reassembled = self
+            .fragments
+            .lock()
+            .expect("Fragments in use")
+            .reassembler
+            .insert(&packet.payload)?;
+        match reassembled {
+            Some(encoded) => {
+                let packet = VpnPacket::decode_owned(encoded)?;
+                if matches!(
+                    packet.packet_type,
+                    PacketType::Fragment | PacketType::Padded
+                ) {
+                    return Err(VpnError::Protocol(format!(
+                        "{:?} packet inside a fragment",
+                        packet.packet_type
+                    )));
+                }
+                self.decompress(packet).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn unpad(&self, packet: VpnPacket) -> Result<VpnPacket, VpnError> {
+        if packet.packet_type != PacketType::Padded {
+            return Ok(packet);
+        }
+        if !self.capabilities.contains(Capabilities::PADDING) {
+            return Err(VpnError::Protocol("Padding was not negotiated".into()));
+        }
+
+        let packet = VpnPacket::decode_from(padding::strip(&packet.payload)?)?;
+        if packet.packet_type == PacketType::Padded {
+            return Err(VpnError::Protocol("Nested padding".into()));
+        }
+        Ok(packet)
+    }
+
+    fn decompress(&self, packet: VpnPacket) -> Result<VpnPacket, VpnError> {
+        if packet.packet_type != PacketType::Compressed {
+            return Ok(packet);
+        }
+        let max_len = self
+            .fragments
+            .lock()
+            .expect("Fragments in use")
+            .reassembler
+            .limits()
+            .max_pending_bytes
+            .max(MAX_MTU);
+        compression::decompress(
+            packet,
+            self.capabilities,
+            max_len,
+            &mut self.compression.lock().expect("Compression stats in use"),
+        )
+    }
+
+    pub fn unpack(&self, data: &[u8]) -> Result<VpnPacket, VpnError> {
+        let header = PacketHeader::from_bytes(data)?;
+        if header.message_type != MessageType::Transport {
+            return Err(VpnError::Protocol("Expected transport packet".into()));
+        }
+        if header.session_id != self.session_id {
+            return Err(VpnError::Protocol(format!(
+                "Packet for session {} received on session {}",
+                header.session_id, self.session_id
+            )));
+        }
+        if header.version != self.version {
+            return Err(VpnError::Protocol(format!(
+                "Packet uses protocol version {} but session {} negotiated {}",
+                header.version, self.session_id, self.version
+            )));
+        }
+
+        let (aad, encrypted) = data.split_at(HEADER_LEN);
+        let decrypted = {
+            let mut keys = self.keys.lock().expect("Keys in use");
+            if header.key_id == keys.current.id {
+                let decrypted = keys
+                    .current
+                    .receiving
+                    .decrypt(header.counter, aad, encrypted)?;
+                keys.current.record_usage(decrypted.len());
+                decrypted
+            } else if let Some(next) = keys.next.take_if(|next| next.id == header.key_id) {
+                // The peer has switched, so can we
+                let decrypted = match next.receiving.decrypt(header.counter, aad, encrypted) {
+                    Ok(decrypted) => decrypted,
+                    Err(e) => {
+                        keys.next = Some(next);
+                        return Err(e);
+                    }
+                };
+                keys.switch_to(next);
+                keys.current.record_usage(decrypted.len());
+                decrypted
+            } else {
+                match &keys.previous {
+                    Some((previous, expires))
+                        if previous.id == header.key_id && Instant::now() < *expires =>
+                    {
+                        previous.receiving.decrypt(header.counter, aad, encrypted)?
+                    }
+                    _ => {
+                        return Err(VpnError::Encryption(format!(
+                            "No keys for key id {}",
+                            header.key_id
+                        )))
+                    }
+                }
+            }
         };
 
-        Ok(VpnPacket {
-            source_ip,
-            dest_ip,
-            packet_type,
-            control_type,
-            payload,
-        })
+        VpnPacket::decode_owned(decrypted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session_pair() -> (ProtocolHandler, ProtocolHandler) {
+        suite_pair(CipherSuite::ChaCha20Poly1305)
+    }
+
+    fn suite_pair(suite: CipherSuite) -> (ProtocolHandler, ProtocolHandler) {
+        let initiator = SessionKeys::from_split([1; 32], [2; 32], [3; 32], true);
+        let responder = SessionKeys::from_split([1; 32], [2; 32], [3; 32], false);
+        (
+            ProtocolHandler::from_session_keys(suite, 7, &initiator),
+            ProtocolHandler::from_session_keys(suite, 7, &responder),
+        )
+    }
+
+    #[test]
+    fn test_rekey_keeps_previous_keys_during_grace() {
+        let (client, server) = session_pair();
+        let in_flight = client.pack(VpnPacket::new_keepalive()).unwrap();
+
+        client.rekey(&[9; 32], true).unwrap();
+        server.rekey(&[9; 32], false).unwrap();
+
+        let fresh = client.pack(VpnPacket::new_keepalive()).unwrap();
+        assert_ne!(fresh[2], in_flight[2]);
+        assert!(server.unpack(&fresh).is_ok());
+        assert!(server.unpack(&in_flight).is_ok());
+    }
+
+    #[test]
+    fn test_previous_keys_expire() {
+        let (client, server) = session_pair();
+        server.set_rekey_policy(RekeyPolicy {
+            grace_period: Duration::ZERO,
+            ..RekeyPolicy::default()
+        });
+        let in_flight = client.pack(VpnPacket::new_keepalive()).unwrap();
+
+        client.rekey(&[9; 32], true).unwrap();
+        server.rekey(&[9; 32], false).unwrap();
+        let fresh = client.pack(VpnPacket::new_keepalive()).unwrap();
+        server.unpack(&fresh).unwrap();
+
+        assert!(server.unpack(&in_flight).is_err());
+    }
+
+    #[test]
+    fn test_responder_switches_once_peer_does() {
+        let (client, server) = session_pair();
+        server.rekey(&[9; 32], false).unwrap();
+
+        // Until the client has the new keys too, say while the reply is lost
+        let old = server.pack(VpnPacket::new_keepalive()).unwrap();
+        assert!(client.unpack(&old).is_ok());
+        assert!(server
+            .unpack(&client.pack(VpnPacket::new_keepalive()).unwrap())
+            .is_ok());
+
+        // Asked again, the responder replaces the keys it set aside
+        server.rekey(&[8; 32], false).unwrap();
+        client.rekey(&[8; 32], true).unwrap();
+        server
+            .unpack(&client.pack(VpnPacket::new_keepalive()).unwrap())
+            .unwrap();
+        let new = server.pack(VpnPacket::new_keepalive()).unwrap();
+        assert_ne!(new[2], old[2]);
+        assert!(client.unpack(&new).is_ok());
+    }
+
+    #[test]
+    fn test_tampered_header_rejected() {
+        let (client, server) = session_pair();
+        let mut packet = client.pack(VpnPacket::new_keepalive()).unwrap();
+        packet[3] ^= 1; // reserved byte, ignored by the parser but authenticated
+
+        assert!(server.unpack(&packet).is_err());
+    }
+
+    #[test]
+    fn test_foreign_session_rejected() {
+        let (client, _) = session_pair();
+        let keys = SessionKeys::from_split([1; 32], [2; 32], [3; 32], false);
+        let other = ProtocolHandler::from_session_keys(CipherSuite::ChaCha20Poly1305, 8, &keys);
+
+        let packet = client.pack(VpnPacket::new_keepalive()).unwrap();
+        assert!(matches!(other.unpack(&packet), Err(VpnError::Protocol(_))));
+    }
+
+    #[test]
+    fn test_other_version_rejected() {
+        let (client, server) = session_pair();
+        let server = server.with_negotiated(PROTOCOL_VERSION + 1, Capabilities::SUPPORTED);
+
+        let packet = client.pack(VpnPacket::new_keepalive()).unwrap();
+        assert!(matches!(server.unpack(&packet), Err(VpnError::Protocol(_))));
+    }
+
+    #[test]
+    fn test_oversized_packet_fragmented() {
+        let (client, server) = session_pair();
+        client.set_mtu(MIN_MTU).unwrap();
+        let packet = VpnPacket::new_data([10, 0, 0, 1], [10, 0, 0, 2], vec![7; 5000]);
+
+        assert!(client.pack(packet.clone()).is_err());
+        let frames = client.pack_fragments(packet.clone()).unwrap();
+        assert_eq!(frames.len(), 10);
+        assert!(frames.iter().all(|frame| frame.len() <= MIN_MTU));
+
+        let (last, rest) = frames.split_last().unwrap();
+        for frame in rest {
+            assert!(server.receive(frame).unwrap().is_none());
+        }
+        assert_eq!(server.receive(last).unwrap(), Some(packet.clone()));
+
+        let client = client.with_negotiated(PROTOCOL_VERSION, Capabilities::REKEY);
+        assert!(client.pack_fragments(packet).is_err());
+    }
+
+    #[test]
+    fn test_fragments_fit_mtu_with_every_suite() {
+        let packet = VpnPacket::new_data([10, 0, 0, 1], [10, 0, 0, 2], vec![7; 5000]);
+        for suite in CipherSuite::ALL {
+            let (client, server) = suite_pair(suite);
+            client.set_mtu(MIN_MTU).unwrap();
+
+            let frames = client.pack_fragments(packet.clone()).unwrap();
+            assert!(frames.iter().all(|frame| frame.len() <= MIN_MTU));
+            let (last, rest) = frames.split_last().unwrap();
+            for frame in rest {
+                assert!(server.receive(frame).unwrap().is_none());
+            }
+            assert_eq!(server.receive(last).unwrap(), Some(packet.clone()));
+        }
+    }
+
+    #[test]
+    fn test_probe_has_exact_size() {
+        for suite in CipherSuite::ALL {
+            let (client, server) = suite_pair(suite);
+            for frame_len in [MIN_MTU, MAX_MTU] {
+                let probe = client.pack_probe(frame_len, 9).unwrap();
+                assert_eq!(probe.len(), frame_len);
+
+                let probe = server.unpack(&probe).unwrap();
+                assert_eq!(probe.transaction_id().unwrap(), Some(9));
+                assert_eq!(
+                    probe.to_control::<MtuProbe>().unwrap(),
+                    MtuProbe {
+                        frame_len: frame_len as u32
+                    }
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_compressed_only_when_negotiated() {
+        let (client, server) = session_pair();
+        let packet =
+            VpnPacket::new_data([10, 0, 0, 1], [10, 0, 0, 2], b"{\"ok\":true}".repeat(500));
+
+        // Off unless both peers agreed to it
+        assert_eq!(client.pack_fragments(packet.clone()).unwrap().len(), 4);
+        assert_eq!(client.compression_stats(), CompressionStats::default());
+
+        let client = client.with_negotiated(PROTOCOL_VERSION, Capabilities::SUPPORTED);
+        let frames = client.pack_fragments(packet.clone()).unwrap();
+        assert_eq!(frames.len(), 1);
+        assert!(client.compression_stats().ratio() < 0.1);
+
+        // A peer that didn't agree to compression refuses compressed packets
+        assert!(server.receive(&frames[0]).is_err());
+        let server = server.with_negotiated(PROTOCOL_VERSION, Capabilities::SUPPORTED);
+        let frames = client.pack_fragments(packet.clone()).unwrap();
+        assert_eq!(server.receive(&frames[0]).unwrap(), Some(packet));
+    }
+
+    #[test]
+    fn test_padding_hides_sizes_and_is_stripped() {
+        // XChaCha20 frames carry a nonce prefix the padding must leave room for
+        for suite in [
+            CipherSuite::ChaCha20Poly1305,
+            CipherSuite::XChaCha20Poly1305,
+        ] {
+            let (client, server) = suite_pair(suite);
+            client.set_padding_policy(PaddingPolicy::Mtu).unwrap();
+
+            for len in [0, 1, 700, 1400] {
+                let packet = VpnPacket::new_data([10, 0, 0, 1], [10, 0, 0, 2], vec![3; len]);
+                let frame = client.pack(packet.clone()).unwrap();
+                assert_eq!(frame.len(), DEFAULT_MTU);
+                assert_eq!(server.receive(&frame).unwrap(), Some(packet));
+            }
+
+            // Fragments are padded too, the last one included
+            let packet = VpnPacket::new_data([10, 0, 0, 1], [10, 0, 0, 2], vec![3; 5000]);
+            let frames = client.pack_fragments(packet.clone()).unwrap();
+            assert!(frames.iter().all(|frame| frame.len() == DEFAULT_MTU));
+            let (last, rest) = frames.split_last().unwrap();
+            for frame in rest {
+                assert!(server.receive(frame).unwrap().is_none());
+            }
+            assert_eq!(server.receive(last).unwrap(), Some(packet));
+
+            client
+                .set_padding_policy(PaddingPolicy::Buckets(vec![128, 512]))
+                .unwrap();
+            let frame = client.pack(VpnPacket::new_keepalive()).unwrap();
+            assert_eq!(frame.len(), 128);
+            assert!(server.receive(&frame).unwrap().unwrap().is_keepalive());
+
+            // Without the peer's agreement frames keep their size
+            let client = client.with_negotiated(PROTOCOL_VERSION, Capabilities::REKEY);
+            assert!(client.pack(VpnPacket::new_keepalive()).unwrap().len() < 128);
+        }
+    }
+
+    #[test]
+    fn test_needs_rekey_after_packet_limit() {
+        let (client, _) = session_pair();
+        client.set_rekey_policy(RekeyPolicy {
+            after_packets: 2,
+            ..RekeyPolicy::default()
+        });
+
+        client.pack(VpnPacket::new_keepalive()).unwrap();
+        assert!(!client.needs_rekey());
+        client.pack(VpnPacket::new_keepalive()).unwrap();
+        assert!(client.needs_rekey());
+
+        client.rekey(&[9; 32], true).unwrap();
+        assert!(!client.needs_rekey());
     }
 }
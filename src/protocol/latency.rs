@@ -0,0 +1,199 @@
+// Round trip estimates from the pings one side sent and the pongs it got
+// back. The smoothed RTT follows TCP (RFC 6298), jitter is the smoothed
+// difference between consecutive round trips as in RTP (RFC 3550), and a
+// ping counts as lost once its pong is overdue.
+use crate::protocol::{Ping, Pong};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How long a ping may wait for its pong before it counts as lost.
+pub const PONG_TIMEOUT: Duration = Duration::from_secs(5);
+// Pings awaiting a pong at most; sending more counts the oldest as lost
+const MAX_OUTSTANDING: usize = 64;
+
+#[derive(Debug, Clone)]
+pub struct LatencyStats {
+    // Ping timestamps count from here
+    epoch: Instant,
+    next_sequence: u32,
+    // Unanswered pings, oldest first
+    outstanding: VecDeque<(u32, Instant)>,
+    pings_sent: u64,
+    pongs_received: u64,
+    lost: u64,
+    rtt: Option<Duration>,
+    last_rtt: Option<Duration>,
+    jitter: Duration,
+}
+
+impl Default for LatencyStats {
+    fn default() -> Self {
+        Self {
+            epoch: Instant::now(),
+            next_sequence: 0,
+            outstanding: VecDeque::new(),
+            pings_sent: 0,
+            pongs_received: 0,
+            lost: 0,
+            rtt: None,
+            last_rtt: None,
+            jitter: Duration::ZERO,
+        }
+    }
+}
+
+impl LatencyStats {
+    /// Smoothed round trip time, none before the first pong.
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Smoothed variation between consecutive round trips.
+    pub fn jitter(&self) -> Duration {
+        self.jitter
+    }
+
+    /// Fraction of the pings settled either way that went unanswered.
+    pub fn loss(&self) -> f64 {
+        let overdue = self
+            .outstanding
+            .iter()
+            .filter(|(_, sent)| sent.elapsed() >= PONG_TIMEOUT)
+            .count() as u64;
+        let lost = self.lost + overdue;
+        match self.pongs_received + lost {
+            0 => 0.0,
+            settled => lost as f64 / settled as f64,
+        }
+    }
+
+    pub fn pings_sent(&self) -> u64 {
+        self.pings_sent
+    }
+
+    pub fn pongs_received(&self) -> u64 {
+        self.pongs_received
+    }
+
+    /// The next ping to send, awaiting its pong from now on.
+    pub(crate) fn ping(&mut self) -> Ping {
+        self.expire();
+        if self.outstanding.len() == MAX_OUTSTANDING {
+            self.outstanding.pop_front();
+            self.lost += 1;
+        }
+
+        let sequence = self.next_sequence;
+        self.next_sequence = sequence.wrapping_add(1);
+        let now = Instant::now();
+        self.outstanding.push_back((sequence, now));
+        self.pings_sent += 1;
+        Ping {
+            sequence,
+            timestamp: self.micros(now),
+        }
+    }
+
+    /// Takes the round trip `pong` measured into the estimates and returns
+    /// it, none for a pong to no ping awaiting one, such as one already
+    /// counted as lost.
+    ///
+    /// The round trip is timed from when we sent the ping; what the peer
+    /// echoes only picks the ping, so it can't claim any round trip it likes.
+    pub(crate) fn pong(&mut self, pong: &Pong) -> Option<Duration> {
+        self.expire();
+        let index = self.outstanding.iter().position(|(sequence, sent)| {
+            *sequence == pong.sequence && self.micros(*sent) == pong.timestamp
+        })?;
+        let (_, sent) = self.outstanding.remove(index)?;
+        self.pongs_received += 1;
+
+        let rtt = sent.elapsed();
+        self.rtt = Some(match self.rtt {
+            Some(smoothed) => smoothed * 7 / 8 + rtt / 8,
+            None => rtt,
+        });
+        if let Some(last_rtt) = self.last_rtt {
+            let difference = rtt.abs_diff(last_rtt).as_secs_f64();
+            let jitter = self.jitter.as_secs_f64();
+            self.jitter = Duration::from_secs_f64(jitter + (difference - jitter) / 16.0);
+        }
+        self.last_rtt = Some(rtt);
+        Some(rtt)
+    }
+
+    // Counts overdue pings as lost, their pongs are ignored from now on
+    fn expire(&mut self) {
+        while self
+            .outstanding
+            .front()
+            .is_some_and(|(_, sent)| sent.elapsed() >= PONG_TIMEOUT)
+        {
+            self.outstanding.pop_front();
+            self.lost += 1;
+        }
+    }
+
+    fn micros(&self, instant: Instant) -> u64 {
+        instant.duration_since(self.epoch).as_micros() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pongs_update_estimates() {
+        let mut stats = LatencyStats::default();
+        assert_eq!(stats.rtt(), None);
+
+        let first = stats.ping();
+        let second = stats.ping();
+        let rtt = stats.pong(&second.pong()).unwrap();
+        assert_eq!(stats.rtt(), Some(rtt));
+        assert!(stats.pong(&second.pong()).is_none());
+
+        stats.pong(&first.pong()).unwrap();
+        assert_eq!(stats.pongs_received(), 2);
+        assert_eq!(stats.loss(), 0.0);
+
+        // A pong to a ping never sent
+        let unknown = Pong {
+            sequence: 99,
+            timestamp: 0,
+        };
+        assert!(stats.pong(&unknown).is_none());
+    }
+
+    #[test]
+    fn test_echoed_timestamp_not_trusted() {
+        let mut stats = LatencyStats::default();
+        std::thread::sleep(Duration::from_millis(10));
+        let ping = stats.ping();
+
+        // Claiming the ping left at the epoch doesn't make the round trip longer
+        let forged = Pong {
+            sequence: ping.sequence,
+            timestamp: 0,
+        };
+        assert!(stats.pong(&forged).is_none());
+
+        let rtt = stats.pong(&ping.pong()).unwrap();
+        assert!(rtt < Duration::from_millis(10));
+        assert_eq!(stats.rtt(), Some(rtt));
+    }
+
+    #[test]
+    fn test_unanswered_pings_lost() {
+        let mut stats = LatencyStats::default();
+        let first = stats.ping();
+        let rest: Vec<_> = (0..MAX_OUTSTANDING).map(|_| stats.ping()).collect();
+        assert_eq!(stats.pings_sent(), MAX_OUTSTANDING as u64 + 1);
+
+        // Pushed out by the pings after it, too late now
+        assert!(stats.pong(&first.pong()).is_none());
+        stats.pong(&rest[0].pong()).unwrap();
+        assert_eq!(stats.loss(), 0.5);
+    }
+}
@@ -1,6 +1,27 @@
+pub mod codec; // Inner packet layout
+pub mod compression; // Optional payload compression
+pub mod control; // Control message payloads
+pub mod fragment; // Splitting packets above the MTU
 mod handler;
+pub mod header; // Cleartext outer header
+pub mod latency; // Round trip and loss estimates
+pub mod negotiation; // Handshake payloads
 pub mod packet; // Packet structure definition // Protocol handling logic
+pub mod padding; // Hiding frame sizes
 
 pub use crate::protocol::packet::VpnPacket;
-pub use handler::ProtocolHandler;
+pub use codec::{PacketCodec, PacketView};
+pub use compression::{Compression, CompressionStats};
+pub use control::{
+    ConfigRequest, ControlPayload, Disconnect, ErrorCode, ErrorMessage, MtuProbe, MtuProbeAck,
+    MtuUpdate, Ping, Pong, Rekey, RouteEntry, RouteUpdate, RouteUpdateAck, VpnConfig,
+};
+pub use fragment::ReassemblyLimits;
+pub use handler::{ProtocolHandler, RekeyPolicy, MAX_MTU, MIN_MTU};
+pub use header::{MessageType, PacketHeader, HEADER_LEN, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+pub use latency::LatencyStats;
+pub use negotiation::{
+    Capabilities, HandshakeOffer, HandshakeRefusal, HandshakeReply, HandshakeSelection,
+};
 pub use packet::{ControlType, PacketType};
+pub use padding::PaddingPolicy;
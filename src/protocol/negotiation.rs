@@ -0,0 +1,390 @@
+use crate::crypto::{CipherSuite, KeyExchangeMode};
+use crate::error::VpnError;
+use crate::protocol::header::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+
+// Every payload starts with the version fields, so any release can read at
+// least that much of a peer's handshake and refuse it cleanly.
+
+/// Optional protocol features, negotiated as the intersection of what both
+/// peers advertise. Unknown bits from newer peers are carried but never set
+/// in the result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Either side may switch the session to fresh keys.
+    pub const REKEY: Self = Self(1 << 0);
+    /// The session survives moving to a new connection.
+    pub const ROAMING: Self = Self(1 << 1);
+    /// Packets above the MTU are split into fragments instead of refused.
+    pub const FRAGMENTATION: Self = Self(1 << 2);
+    /// The client may probe for the path MTU and lower the session's to it.
+    pub const PATH_MTU_DISCOVERY: Self = Self(1 << 3);
+
+    /// Data payloads may be LZ4 compressed.
+    pub const LZ4_COMPRESSION: Self = Self(1 << 4);
+    /// Data payloads may be zstd compressed, preferred over LZ4 when both are
+    /// agreed.
+    pub const ZSTD_COMPRESSION: Self = Self(1 << 5);
+
+    /// Frames may be padded, and the peer strips the padding.
+    pub const PADDING: Self = Self(1 << 6);
+
+    /// Either side may ping the other to measure round trip time and loss.
+    pub const PING: Self = Self(1 << 7);
+
+    /// Everything this build implements.
+    pub const SUPPORTED: Self = Self(
+        Self::REKEY.0
+            | Self::ROAMING.0
+            | Self::FRAGMENTATION.0
+            | Self::PATH_MTU_DISCOVERY.0
+            | Self::LZ4_COMPRESSION.0
+            | Self::ZSTD_COMPRESSION.0
+            | Self::PADDING.0
+            | Self::PING.0,
+    );
+
+    /// What a server agrees to unless configured otherwise. Compression is
+    /// left out: packet sizes then reveal how compressible the plaintext is.
+    pub const DEFAULT: Self =
+        Self(Self::SUPPORTED.0 & !(Self::LZ4_COMPRESSION.0 | Self::ZSTD_COMPRESSION.0));
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+}
+
+/// Highest version in both `[min, max]` and what we accept from `floor` up.
+pub fn negotiate_version(min: u8, max: u8, floor: u8) -> Option<u8> {
+    let version = max.min(PROTOCOL_VERSION);
+    (version >= min && version >= floor.max(MIN_PROTOCOL_VERSION)).then_some(version)
+}
+
+/// Payload of the handshake initiation: what the client supports, most
+/// preferred first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeOffer {
+    /// Oldest and newest protocol versions the client speaks.
+    pub min_version: u8,
+    pub max_version: u8,
+    pub capabilities: Capabilities,
+    pub cipher_suites: Vec<CipherSuite>,
+    pub key_exchange_modes: Vec<KeyExchangeMode>,
+    /// ML-KEM encapsulation key, present when a hybrid mode is offered.
+    pub kem_encapsulation_key: Option<Vec<u8>>,
+}
+
+/// Payload of the handshake response: what the server picked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeSelection {
+    /// Version every packet of the session is sent with.
+    pub version: u8,
+    pub capabilities: Capabilities,
+    pub cipher_suite: CipherSuite,
+    pub session_id: u32,
+    pub key_exchange_mode: KeyExchangeMode,
+    /// ML-KEM ciphertext, present when the hybrid mode was selected.
+    pub kem_ciphertext: Option<Vec<u8>>,
+}
+
+/// Payload of a handshake response that declines to establish a session,
+/// telling the client what the server would have accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HandshakeRefusal {
+    pub min_version: u8,
+    pub max_version: u8,
+    pub reason: String,
+}
+
+/// Either outcome of a handshake, as carried in the response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HandshakeReply {
+    Accepted(HandshakeSelection),
+    Refused(HandshakeRefusal),
+}
+
+impl HandshakeOffer {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, VpnError> {
+        let mut bytes = vec![self.min_version, self.max_version];
+        bytes.extend_from_slice(&self.capabilities.bits().to_be_bytes());
+        write_list(&mut bytes, &CipherSuite::encode_list(&self.cipher_suites))?;
+        write_list(
+            &mut bytes,
+            &KeyExchangeMode::encode_list(&self.key_exchange_modes),
+        )?;
+        write_blob(&mut bytes, self.kem_encapsulation_key.as_deref())?;
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VpnError> {
+        let mut reader = Reader::new(bytes);
+        let min_version = reader.u8()?;
+        let max_version = reader.u8()?;
+        let capabilities = Capabilities::from_bits(reader.u32()?);
+        let cipher_suites = CipherSuite::decode_list(reader.list()?);
+        let key_exchange_modes = KeyExchangeMode::decode_list(reader.list()?);
+        let kem_encapsulation_key = reader.blob()?;
+
+        Ok(Self {
+            min_version,
+            max_version,
+            capabilities,
+            cipher_suites,
+            key_exchange_modes,
+            kem_encapsulation_key,
+        })
+    }
+}
+
+impl HandshakeSelection {
+    pub fn to_bytes(&self) -> Result<Vec<u8>, VpnError> {
+        let mut bytes = vec![self.version];
+        bytes.extend_from_slice(&self.capabilities.bits().to_be_bytes());
+        bytes.push(self.cipher_suite as u8);
+        bytes.extend_from_slice(&self.session_id.to_be_bytes());
+        bytes.push(self.key_exchange_mode as u8);
+        write_blob(&mut bytes, self.kem_ciphertext.as_deref())?;
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VpnError> {
+        let mut reader = Reader::new(bytes);
+        let version = reader.u8()?;
+        let capabilities = Capabilities::from_bits(reader.u32()?);
+        let cipher_suite = CipherSuite::try_from(reader.u8()?)?;
+        let session_id = reader.u32()?;
+        let key_exchange_mode = KeyExchangeMode::try_from(reader.u8()?)?;
+        let kem_ciphertext = reader.blob()?;
+
+        Ok(Self {
+            version,
+            capabilities,
+            cipher_suite,
+            session_id,
+            key_exchange_mode,
+            kem_ciphertext,
+        })
+    }
+}
+
+impl HandshakeReply {
+    const ACCEPTED: u8 = 0;
+    const REFUSED: u8 = 1;
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, VpnError> {
+        match self {
+            HandshakeReply::Accepted(selection) => {
+                let mut bytes = vec![Self::ACCEPTED];
+                bytes.extend_from_slice(&selection.to_bytes()?);
+                Ok(bytes)
+            }
+            HandshakeReply::Refused(refusal) => {
+                let mut bytes = vec![Self::REFUSED, refusal.min_version, refusal.max_version];
+                write_blob(&mut bytes, Some(refusal.reason.as_bytes()))?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VpnError> {
+        let mut reader = Reader::new(bytes);
+        match reader.u8()? {
+            Self::ACCEPTED => Ok(HandshakeReply::Accepted(HandshakeSelection::from_bytes(
+                reader.bytes,
+            )?)),
+            Self::REFUSED => {
+                let min_version = reader.u8()?;
+                let max_version = reader.u8()?;
+                let reason = reader.blob()?.unwrap_or_default();
+                Ok(HandshakeReply::Refused(HandshakeRefusal {
+                    min_version,
+                    max_version,
+                    reason: String::from_utf8_lossy(&reason).into_owned(),
+                }))
+            }
+            status => Err(VpnError::KeyExchange(format!(
+                "Invalid handshake status: {}",
+                status
+            ))),
+        }
+    }
+}
+
+// Lists carry a one byte count, blobs a two byte length where zero means absent
+fn write_list(bytes: &mut Vec<u8>, items: &[u8]) -> Result<(), VpnError> {
+    let count = u8::try_from(items.len()).map_err(|_| {
+        VpnError::Protocol(format!(
+            "List of {} items is too long to encode",
+            items.len()
+        ))
+    })?;
+    bytes.push(count);
+    bytes.extend_from_slice(items);
+    Ok(())
+}
+
+fn write_blob(bytes: &mut Vec<u8>, blob: Option<&[u8]>) -> Result<(), VpnError> {
+    let blob = blob.unwrap_or_default();
+    let len = u16::try_from(blob.len()).map_err(|_| {
+        VpnError::Protocol(format!(
+            "Blob of {} bytes is too long to encode",
+            blob.len()
+        ))
+    })?;
+    bytes.extend_from_slice(&len.to_be_bytes());
+    bytes.extend_from_slice(blob);
+    Ok(())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], VpnError> {
+        if self.bytes.len() < len {
+            return Err(VpnError::KeyExchange("Truncated handshake payload".into()));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, VpnError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, VpnError> {
+        let mut value = [0u8; 4];
+        value.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(value))
+    }
+
+    fn list(&mut self) -> Result<&'a [u8], VpnError> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn blob(&mut self) -> Result<Option<Vec<u8>>, VpnError> {
+        let mut len = [0u8; 2];
+        len.copy_from_slice(self.take(2)?);
+        match u16::from_be_bytes(len) as usize {
+            0 => Ok(None),
+            len => Ok(Some(self.take(len)?.to_vec())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offer_round_trip() {
+        let offer = HandshakeOffer {
+            min_version: 1,
+            max_version: 3,
+            capabilities: Capabilities::from_bits(0x8000_0001),
+            cipher_suites: vec![CipherSuite::ChaCha20Poly1305, CipherSuite::Aes256Gcm],
+            key_exchange_modes: KeyExchangeMode::ALL.to_vec(),
+            kem_encapsulation_key: Some(vec![7; 1184]),
+        };
+        assert_eq!(
+            HandshakeOffer::from_bytes(&offer.to_bytes().unwrap()).unwrap(),
+            offer
+        );
+    }
+
+    #[test]
+    fn test_oversized_lengths_rejected() {
+        // Rather than a length prefix that no longer matches what follows
+        let offer = HandshakeOffer {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+            cipher_suites: vec![CipherSuite::ChaCha20Poly1305; 256],
+            key_exchange_modes: KeyExchangeMode::ALL.to_vec(),
+            kem_encapsulation_key: None,
+        };
+        assert!(matches!(offer.to_bytes(), Err(VpnError::Protocol(_))));
+
+        let refusal = HandshakeReply::Refused(HandshakeRefusal {
+            min_version: 1,
+            max_version: 2,
+            reason: "x".repeat(65536),
+        });
+        assert!(matches!(refusal.to_bytes(), Err(VpnError::Protocol(_))));
+    }
+
+    #[test]
+    fn test_truncated_selection_rejected() {
+        let selection = HandshakeSelection {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+            cipher_suite: CipherSuite::XChaCha20Poly1305,
+            session_id: 42,
+            key_exchange_mode: KeyExchangeMode::HybridMlKem768,
+            kem_ciphertext: Some(vec![1; 1088]),
+        };
+        let bytes = selection.to_bytes().unwrap();
+        assert_eq!(HandshakeSelection::from_bytes(&bytes).unwrap(), selection);
+        assert!(HandshakeSelection::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_version_negotiation() {
+        // A newer peer falls back to our version, an older-only one is refused
+        assert_eq!(
+            negotiate_version(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION + 1, 0),
+            Some(PROTOCOL_VERSION)
+        );
+        assert_eq!(
+            negotiate_version(PROTOCOL_VERSION + 1, PROTOCOL_VERSION + 2, 0),
+            None
+        );
+        assert_eq!(negotiate_version(0, MIN_PROTOCOL_VERSION - 1, 0), None);
+        assert_eq!(
+            negotiate_version(MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, PROTOCOL_VERSION + 1),
+            None
+        );
+    }
+
+    #[test]
+    fn test_refusal_round_trip() {
+        let reply = HandshakeReply::Refused(HandshakeRefusal {
+            min_version: 2,
+            max_version: 4,
+            reason: "Unsupported protocol version".into(),
+        });
+        assert_eq!(
+            HandshakeReply::from_bytes(&reply.to_bytes().unwrap()).unwrap(),
+            reply
+        );
+        assert!(HandshakeReply::from_bytes(&[9]).is_err());
+    }
+}
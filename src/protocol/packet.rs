@@ -1,24 +1,39 @@
 // src/protocol/packet.rs
 use crate::error::VpnError;
+use crate::protocol::codec::{PacketCodec, PacketView};
+use crate::protocol::header::{MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
 use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+// Every address is led by its family
+const FAMILY_IPV4: u8 = 4;
+const FAMILY_IPV6: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum PacketType {
     Data = 0,
     Keepalive = 1,
     Control = 2,
+    /// Part of a packet larger than the MTU.
+    Fragment = 3,
+    /// Data packet whose payload is compressed.
+    Compressed = 4,
+    /// Another packet followed by padding.
+    Padded = 5,
 }
 
 impl TryFrom<u8> for PacketType {
     type Error = VpnError;
 
     fn try_from(value: u8) -> Result<Self, Self::Error> {
-        println!("PacketType::try_from: value: {}", value);
         match value {
             0 => Ok(PacketType::Data),
             1 => Ok(PacketType::Keepalive),
             2 => Ok(PacketType::Control),
+            3 => Ok(PacketType::Fragment),
+            4 => Ok(PacketType::Compressed),
+            5 => Ok(PacketType::Padded),
             _ => Err(VpnError::Protocol(format!(
                 "Invalid packet type: {}",
                 value
@@ -27,24 +42,46 @@ impl TryFrom<u8> for PacketType {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum ControlType {
     ConfigRequest = 0,
     ConfigResponse = 1,
     RouteUpdate = 2,
     Disconnect = 3,
+    Rekey = 4,
+    /// Padded to a candidate MTU; answered by `MtuProbeAck` if it got through.
+    MtuProbe = 5,
+    MtuProbeAck = 6,
+    /// Settles the session on the MTU the probes found.
+    MtuUpdate = 7,
+    /// Answered by a `Pong` echoing it, from either side.
+    Ping = 8,
+    Pong = 9,
+    /// Sent before the sender drops the connection, saying why.
+    Error = 10,
+    /// Acknowledges a `RouteUpdate`.
+    RouteUpdateAck = 11,
 }
 
 impl TryFrom<u8> for ControlType {
     type Error = VpnError;
 
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
+    // `Self::Error` would be ambiguous with the variant
+    fn try_from(value: u8) -> Result<Self, VpnError> {
         match value {
             0 => Ok(ControlType::ConfigRequest),
             1 => Ok(ControlType::ConfigResponse),
             2 => Ok(ControlType::RouteUpdate),
             3 => Ok(ControlType::Disconnect),
+            4 => Ok(ControlType::Rekey),
+            5 => Ok(ControlType::MtuProbe),
+            6 => Ok(ControlType::MtuProbeAck),
+            7 => Ok(ControlType::MtuUpdate),
+            8 => Ok(ControlType::Ping),
+            9 => Ok(ControlType::Pong),
+            10 => Ok(ControlType::Error),
+            11 => Ok(ControlType::RouteUpdateAck),
             _ => Err(VpnError::Protocol(format!(
                 "Invalid control type: {}",
                 value
@@ -53,20 +90,25 @@ impl TryFrom<u8> for ControlType {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct VpnPacket {
-    pub source_ip: [u8; 4],
-    pub dest_ip: [u8; 4],
+    pub source_ip: IpAddr,
+    pub dest_ip: IpAddr,
     pub packet_type: PacketType,
     pub control_type: Option<ControlType>,
     pub payload: Vec<u8>,
 }
 
 impl VpnPacket {
-    pub fn new_data(source_ip: [u8; 4], dest_ip: [u8; 4], payload: Vec<u8>) -> Self {
+    /// Data packet between two addresses of the same or different families.
+    pub fn new_data(
+        source_ip: impl Into<IpAddr>,
+        dest_ip: impl Into<IpAddr>,
+        payload: Vec<u8>,
+    ) -> Self {
         Self {
-            source_ip,
-            dest_ip,
+            source_ip: source_ip.into(),
+            dest_ip: dest_ip.into(),
             packet_type: PacketType::Data,
             control_type: None,
             payload,
@@ -75,8 +117,8 @@ impl VpnPacket {
 
     pub fn new_keepalive() -> Self {
         Self {
-            source_ip: [0u8; 4],
-            dest_ip: [0u8; 4],
+            source_ip: Ipv4Addr::UNSPECIFIED.into(),
+            dest_ip: Ipv4Addr::UNSPECIFIED.into(),
             packet_type: PacketType::Keepalive,
             control_type: None,
             payload: Vec::new(),
@@ -85,64 +127,67 @@ impl VpnPacket {
 
     pub fn new_control(control_type: ControlType) -> Self {
         Self {
-            source_ip: [0; 4],
-            dest_ip: [0; 4],
+            source_ip: Ipv4Addr::UNSPECIFIED.into(),
+            dest_ip: Ipv4Addr::UNSPECIFIED.into(),
             packet_type: PacketType::Control,
             control_type: Some(control_type),
             payload: Vec::new(),
         }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(10 + self.payload.len());
-
-        // Add source and dest IPs
-        bytes.extend_from_slice(&self.source_ip);
-        bytes.extend_from_slice(&self.dest_ip);
-
-        // Add packet type
-        bytes.push(self.packet_type as u8);
-
-        // Add control type if present
-        match self.control_type {
-            Some(ct) => bytes.push(ct as u8),
-            None => bytes.push(0),
+    /// Borrows the packet for encoding without copying the payload.
+    pub fn view(&self) -> PacketView<'_> {
+        PacketView {
+            source_ip: self.source_ip,
+            dest_ip: self.dest_ip,
+            packet_type: self.packet_type,
+            control_type: self.control_type,
+            payload: &self.payload,
         }
-
-        // Add payload
-        bytes.extend_from_slice(&self.payload);
-
-        bytes
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VpnError> {
-        if bytes.len() < 10 {
-            return Err(VpnError::Protocol("Packet too short".into()));
-        }
-
-        let mut source_ip = [0u8; 4];
-        let mut dest_ip = [0u8; 4];
-        source_ip.copy_from_slice(&bytes[0..4]);
-        dest_ip.copy_from_slice(&bytes[4..8]);
-
-        let packet_type = PacketType::try_from(bytes[8])?;
-        let control_type = if packet_type == PacketType::Control {
-            Some(ControlType::try_from(bytes[9])?)
-        } else {
-            None
-        };
-
-        let payload = bytes[10..].to_vec();
+    /// Decodes an encoding, reusing its allocation for the payload.
+    pub fn decode_owned(mut bytes: Vec<u8>) -> Result<Self, VpnError> {
+        let view = PacketView::decode_from(&bytes)?;
+        let (source_ip, dest_ip, packet_type, control_type) = (
+            view.source_ip,
+            view.dest_ip,
+            view.packet_type,
+            view.control_type,
+        );
+        let payload_start = bytes.len() - view.payload.len();
+        bytes.drain(..payload_start);
 
         Ok(Self {
             source_ip,
             dest_ip,
             packet_type,
             control_type,
-            payload,
+            payload: bytes,
         })
     }
 
+    /// Standalone encoding, led by the protocol version since there is no
+    /// outer header to carry it.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, VpnError> {
+        let mut bytes = vec![PROTOCOL_VERSION];
+        self.encode_into(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VpnError> {
+        let (&version, bytes) = bytes
+            .split_first()
+            .ok_or_else(|| VpnError::Protocol("Packet too short".into()))?;
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            return Err(VpnError::Protocol(format!(
+                "Unsupported protocol version: {}",
+                version
+            )));
+        }
+        Self::decode_from(bytes)
+    }
+
     pub fn is_keepalive(&self) -> bool {
         self.packet_type == PacketType::Keepalive
     }
@@ -159,12 +204,51 @@ impl VpnPacket {
         self.payload = payload;
     }
 
-    pub fn set_source_ip(&mut self, ip: [u8; 4]) {
-        self.source_ip = ip;
+    pub fn set_source_ip(&mut self, ip: impl Into<IpAddr>) {
+        self.source_ip = ip.into();
+    }
+
+    pub fn set_dest_ip(&mut self, ip: impl Into<IpAddr>) {
+        self.dest_ip = ip.into();
+    }
+}
+
+/// Appends `ip`, led by its family.
+pub(crate) fn write_ip(bytes: &mut Vec<u8>, ip: IpAddr) {
+    match ip {
+        IpAddr::V4(ip) => {
+            bytes.push(FAMILY_IPV4);
+            bytes.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            bytes.push(FAMILY_IPV6);
+            bytes.extend_from_slice(&ip.octets());
+        }
     }
+}
 
-    pub fn set_dest_ip(&mut self, ip: [u8; 4]) {
-        self.dest_ip = ip;
+/// Reads an address written by `write_ip`, returning the rest.
+pub(crate) fn read_ip(bytes: &[u8]) -> Result<(IpAddr, &[u8]), VpnError> {
+    let (&family, bytes) = bytes
+        .split_first()
+        .ok_or_else(|| VpnError::Protocol("Truncated address".into()))?;
+
+    match family {
+        FAMILY_IPV4 if bytes.len() >= 4 => {
+            let (octets, rest) = bytes.split_at(4);
+            let octets: [u8; 4] = octets.try_into().expect("Length checked");
+            Ok((Ipv4Addr::from(octets).into(), rest))
+        }
+        FAMILY_IPV6 if bytes.len() >= 16 => {
+            let (octets, rest) = bytes.split_at(16);
+            let octets: [u8; 16] = octets.try_into().expect("Length checked");
+            Ok((Ipv6Addr::from(octets).into(), rest))
+        }
+        FAMILY_IPV4 | FAMILY_IPV6 => Err(VpnError::Protocol("Truncated address".into())),
+        family => Err(VpnError::Protocol(format!(
+            "Invalid address family: {}",
+            family
+        ))),
     }
 }
 
@@ -177,13 +261,27 @@ mod tests {
         let packet =
             VpnPacket::new_data([192, 168, 1, 1], [192, 168, 1, 2], b"test payload".to_vec());
 
-        let bytes = packet.to_bytes();
+        let bytes = packet.to_bytes().unwrap();
         let decoded = VpnPacket::from_bytes(&bytes).unwrap();
 
         assert_eq!(decoded.source_ip, packet.source_ip);
         assert_eq!(decoded.dest_ip, packet.dest_ip);
         assert_eq!(decoded.packet_type, packet.packet_type);
         assert_eq!(decoded.payload, packet.payload);
+
+        let mut future = bytes.clone();
+        future[0] = PROTOCOL_VERSION + 1;
+        assert!(VpnPacket::from_bytes(&future).is_err());
+    }
+
+    #[test]
+    fn test_dual_stack_packet() {
+        let source: Ipv6Addr = "fd00::1".parse().unwrap();
+        let packet = VpnPacket::new_data(source, [10, 0, 0, 2], b"mixed".to_vec());
+
+        let decoded = VpnPacket::from_bytes(&packet.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.source_ip, IpAddr::V6(source));
+        assert_eq!(decoded.dest_ip, IpAddr::from([10, 0, 0, 2]));
     }
 
     #[test]
@@ -0,0 +1,140 @@
+// With a padding policy in place every frame carries a packet of type
+// `Padded` wrapping the packet it would have carried:
+//
+//   inner length (4) | inner packet encoding | zeros
+//
+// The receiver keeps the first `inner length` bytes after the length field
+// and ignores the rest, which makes stripping exact whatever the padding.
+use crate::error::VpnError;
+use crate::protocol::{PacketType, VpnPacket, MAX_MTU};
+use rand::Rng;
+use std::net::Ipv4Addr;
+
+pub const PADDING_HEADER_LEN: usize = 4;
+
+/// How far frames are padded before encryption, hiding the size of what they
+/// carry. Padding never makes a frame exceed the MTU.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PaddingPolicy {
+    /// Frames keep their natural size.
+    #[default]
+    None,
+    /// Frames grow to the smallest of these sizes that fits them, or to the
+    /// MTU when none does.
+    Buckets(Vec<usize>),
+    /// Every frame is as large as the MTU.
+    Mtu,
+    /// Frames grow by a random amount of up to `max` bytes, which can't
+    /// exceed `MAX_MTU`.
+    Random { max: usize },
+}
+
+impl PaddingPolicy {
+    /// Rejects a policy that pads by more than any frame can hold.
+    pub(crate) fn check(&self) -> Result<(), VpnError> {
+        match self {
+            PaddingPolicy::Random { max } if *max > MAX_MTU => Err(VpnError::Config(format!(
+                "Random padding of up to {} bytes exceeds the largest MTU, {}",
+                max, MAX_MTU
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Frame size to pad a frame of `len` bytes to.
+    pub(crate) fn target_len(&self, len: usize, mtu: usize) -> usize {
+        let target = match self {
+            PaddingPolicy::None => len,
+            PaddingPolicy::Buckets(sizes) => sizes
+                .iter()
+                .copied()
+                .filter(|&size| size >= len)
+                .min()
+                .unwrap_or(mtu),
+            PaddingPolicy::Mtu => mtu,
+            PaddingPolicy::Random { max } => {
+                len.saturating_add(rand::thread_rng().gen_range(0..=*max))
+            }
+        };
+        target.min(mtu).max(len)
+    }
+}
+
+/// Wraps `encoded` in a padded packet with `padding` zero bytes after it.
+pub(crate) fn pad(encoded: &[u8], padding: usize) -> VpnPacket {
+    let mut payload = Vec::with_capacity(PADDING_HEADER_LEN + encoded.len() + padding);
+    payload.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+    payload.extend_from_slice(encoded);
+    payload.resize(payload.len() + padding, 0);
+    new_padded(payload)
+}
+
+/// A padded packet; `payload` starts with the padding header.
+pub(crate) fn new_padded(payload: Vec<u8>) -> VpnPacket {
+    VpnPacket {
+        source_ip: Ipv4Addr::UNSPECIFIED.into(),
+        dest_ip: Ipv4Addr::UNSPECIFIED.into(),
+        packet_type: PacketType::Padded,
+        control_type: None,
+        payload,
+    }
+}
+
+/// The inner packet encoding of a padded packet's payload.
+pub(crate) fn strip(payload: &[u8]) -> Result<&[u8], VpnError> {
+    let Some((len, rest)) = payload.split_first_chunk::<PADDING_HEADER_LEN>() else {
+        return Err(VpnError::Protocol("Truncated padded packet".into()));
+    };
+    let len = u32::from_be_bytes(*len) as usize;
+    rest.get(..len).ok_or_else(|| {
+        VpnError::Protocol(format!(
+            "Padded packet claims {} bytes but carries {}",
+            len,
+            rest.len()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_targets_stay_within_mtu() {
+        let buckets = PaddingPolicy::Buckets(vec![256, 512, 1024]);
+        assert_eq!(buckets.target_len(100, 1500), 256);
+        assert_eq!(buckets.target_len(512, 1500), 512);
+        assert_eq!(buckets.target_len(1100, 1500), 1500);
+        assert_eq!(buckets.target_len(100, 200), 200);
+
+        assert_eq!(PaddingPolicy::Mtu.target_len(100, 1500), 1500);
+        assert_eq!(PaddingPolicy::None.target_len(100, 1500), 100);
+        for _ in 0..100 {
+            let target = PaddingPolicy::Random { max: 50 }.target_len(100, 120);
+            assert!((100..=120).contains(&target));
+        }
+    }
+
+    #[test]
+    fn test_oversized_random_padding_rejected() {
+        let huge = PaddingPolicy::Random { max: usize::MAX };
+        assert!(matches!(huge.check(), Err(VpnError::Config(_))));
+        assert!(PaddingPolicy::Random { max: MAX_MTU }.check().is_ok());
+
+        // Even unchecked, the target can't overflow
+        for _ in 0..100 {
+            assert!((1400..=1500).contains(&huge.target_len(1400, 1500)));
+        }
+    }
+
+    #[test]
+    fn test_strip_is_exact() {
+        let encoded = [1, 2, 3, 0, 0];
+        let padded = pad(&encoded, 7);
+        assert_eq!(padded.payload.len(), PADDING_HEADER_LEN + 5 + 7);
+        assert_eq!(strip(&padded.payload).unwrap(), encoded);
+
+        assert!(strip(&padded.payload[..6]).is_err());
+        assert!(strip(&[0, 0]).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+mod session;
 pub mod vpn_client;
 pub mod vpn_service;
 mod vpn_worker;
@@ -0,0 +1,120 @@
+use crate::protocol::{LatencyStats, Ping, Pong, ProtocolHandler, Rekey};
+
+use std::time::{Duration, Instant};
+
+/// How long a session survives without an authenticated packet.
+pub const SESSION_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Server-side state of a client that completed the handshake.
+///
+/// Sessions are keyed by the id assigned in the handshake rather than by the
+/// connection, so a client can come back from a new address and keep going.
+#[derive(Clone)]
+pub struct Session {
+    pub protocol_handler: ProtocolHandler,
+    pub info: ConnectionInfo,
+    /// Connection the session was last heard from, where replies are sent.
+    pub connection_id: String,
+    /// The client's key in the last rekey request and the reply to it, sent
+    /// again if the client asks again.
+    pub last_rekey: Option<([u8; 32], Rekey)>,
+}
+
+impl Session {
+    pub fn new(protocol_handler: ProtocolHandler, connection_id: &str) -> Self {
+        Self {
+            protocol_handler,
+            info: ConnectionInfo::new(),
+            connection_id: connection_id.to_string(),
+            last_rekey: None,
+        }
+    }
+
+    pub fn session_id(&self) -> u32 {
+        self.protocol_handler.session_id()
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.info.last_seen().elapsed() > SESSION_TIMEOUT
+    }
+}
+
+/// Traffic and liveness counters of a session.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    last_seen: Instant,
+    bytes_sent: u64,
+    bytes_received: u64,
+    packets_sent: u64,
+    packets_received: u64,
+    replayed_packets: u64,
+    latency: LatencyStats,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new() -> Self {
+        Self {
+            last_seen: Instant::now(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            packets_sent: 0,
+            packets_received: 0,
+            replayed_packets: 0,
+            latency: LatencyStats::default(),
+        }
+    }
+
+    pub(crate) fn record_sent(&mut self, bytes: u64) {
+        self.bytes_sent += bytes;
+        self.packets_sent += 1;
+    }
+
+    pub(crate) fn record_received(&mut self, bytes: u64) {
+        self.last_seen = Instant::now();
+        self.bytes_received += bytes;
+        self.packets_received += 1;
+    }
+
+    pub(crate) fn record_replay(&mut self) {
+        self.replayed_packets += 1;
+    }
+
+    pub(crate) fn record_ping(&mut self) -> Ping {
+        self.latency.ping()
+    }
+
+    pub(crate) fn record_pong(&mut self, pong: &Pong) -> Option<Duration> {
+        self.latency.pong(pong)
+    }
+
+    pub fn last_seen(&self) -> Instant {
+        self.last_seen
+    }
+
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    pub fn packets_sent(&self) -> u64 {
+        self.packets_sent
+    }
+
+    pub fn packets_received(&self) -> u64 {
+        self.packets_received
+    }
+
+    /// Authentic packets dropped because their counter was already seen or
+    /// too old. Forgeries fail to decrypt and are not counted.
+    pub fn replayed_packets(&self) -> u64 {
+        self.replayed_packets
+    }
+
+    /// Round trip time, jitter and loss measured by pinging the peer.
+    pub fn latency(&self) -> &LatencyStats {
+        &self.latency
+    }
+}
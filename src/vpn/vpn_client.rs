@@ -1,144 +1,1280 @@
-use std::thread;
-
+use crate::protocol::latency::PONG_TIMEOUT;
 use crate::protocol::packet::VpnPacket;
-use crate::protocol::ControlType;
-use crate::protocol::PacketType;
-use crate::vpn::vpn_service::VpnConfig;
+use crate::protocol::{
+    Capabilities, CompressionStats, HandshakeOffer, HandshakeReply, MessageType, PacketHeader,
+    HEADER_LEN, MIN_MTU, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::protocol::{ConfigRequest, ControlPayload, Disconnect, MtuProbeAck, MtuUpdate, Rekey};
+use crate::protocol::{ControlType, ErrorCode, ErrorMessage, LatencyStats, Ping, Pong};
+use crate::protocol::{PaddingPolicy, RekeyPolicy};
+use crate::protocol::{RouteEntry, RouteUpdate, RouteUpdateAck, VpnConfig};
 use crate::{
-    crypto::EncryptionManager, network::tcp_client::TcpClient, protocol::ProtocolHandler, VpnError,
+    crypto::{keys, CipherSuite, Handshake, KemKeypair, KeyExchange, KeyExchangeMode, SecretKey},
+    network::tcp_client::TcpClient,
+    network::transport::{exceeds_path_mtu, Connect},
+    protocol::ProtocolHandler,
+    VpnError,
 };
 
-use std::sync::{atomic::AtomicBool, Arc};
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::{task::JoinHandle, time::timeout};
+
+// How long to wait on the server before giving up on the connection
+const READ_TIMEOUT: Duration = Duration::from_secs(45);
+// How long to wait for a probe's ack before taking the size as too large
+const MTU_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+// How long to look for the server's reason once a write fails
+const CLOSING_ERROR_TIMEOUT: Duration = Duration::from_millis(100);
+// How long to wait for an answer before first resending over lossy transports
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Handshake parameters beyond the two static keys.
+pub struct HandshakeOptions {
+    /// Suites to offer, most preferred first.
+    pub cipher_suites: Vec<CipherSuite>,
+    /// Must match the key the server holds for us, if it has one.
+    pub preshared_key: Option<SecretKey>,
+    /// Modes to offer, most preferred first. Include
+    /// `KeyExchangeMode::HybridMlKem768` to opt into post-quantum protection.
+    pub key_exchange_modes: Vec<KeyExchangeMode>,
+    /// Optional features to offer. Those the client doesn't support are left
+    /// out.
+    pub capabilities: Capabilities,
+}
+
+impl Default for HandshakeOptions {
+    fn default() -> Self {
+        Self {
+            cipher_suites: CipherSuite::preferred(),
+            preshared_key: None,
+            key_exchange_modes: vec![KeyExchangeMode::X25519],
+            capabilities: Capabilities::SUPPORTED,
+        }
+    }
+}
 
-pub struct VpnClient {
-    client: TcpClient,
+pub struct VpnClient<C: Connect = TcpClient> {
+    server_addr: String,
+    // Shared with the keepalive task, which is restarted on reconnect
+    client: Arc<C>,
     protocol_handler: ProtocolHandler,
+    key_exchange_mode: KeyExchangeMode,
     config: VpnConfig,
     connected: bool,
-    client_thread: Option<thread::JoinHandle<()>>,
-    shutdown_flag: Arc<AtomicBool>,
+    keepalive_task: Option<JoinHandle<()>>,
+    // How long reads wait for the server
+    read_timeout: Duration,
+    last_transaction_id: u32,
+    // Requests awaiting a response in the order sent, with the response once
+    // it was read
+    in_flight: Vec<(u32, Option<VpnPacket>)>,
+    // Control messages the server sent unprompted
+    pushes: VecDeque<VpnPacket>,
+    // Data packets read while waiting for a response
+    received: VecDeque<VpnPacket>,
+    latency: LatencyStats,
+    // Why the server dropped the connection, once it said
+    closed_by_server: Option<(ErrorCode, String)>,
 }
 
 impl VpnClient {
-    pub fn new(
+    /// Connects to the server identified by `server_public_key`, proving our
+    /// own identity with `private_key`.
+    pub async fn new(
         server_addr: &str,
-        encryption_key: [u8; 32],
+        private_key: SecretKey,
+        server_public_key: [u8; 32],
         config: Option<VpnConfig>,
     ) -> Result<Self, VpnError> {
-        let client = TcpClient::connect(server_addr)?;
+        Self::with_options(
+            server_addr,
+            private_key,
+            server_public_key,
+            HandshakeOptions::default(),
+            config,
+        )
+        .await
+    }
 
-        let encryption = EncryptionManager::new(&encryption_key);
-        let protocol_handler = ProtocolHandler::new(encryption);
+    /// Like `new`, but reads our private key and the server's public key
+    /// from key files.
+    pub async fn from_key_files(
+        server_addr: &str,
+        private_key_file: impl AsRef<Path>,
+        server_public_key_file: impl AsRef<Path>,
+        config: Option<VpnConfig>,
+    ) -> Result<Self, VpnError> {
+        Self::new(
+            server_addr,
+            keys::read_private_key(private_key_file)?,
+            keys::read_public_key(server_public_key_file)?,
+            config,
+        )
+        .await
+    }
+
+    /// Like `new`, but offers `cipher_suites` in the given preference order.
+    pub async fn with_cipher_suites(
+        server_addr: &str,
+        private_key: SecretKey,
+        server_public_key: [u8; 32],
+        cipher_suites: &[CipherSuite],
+        config: Option<VpnConfig>,
+    ) -> Result<Self, VpnError> {
+        let options = HandshakeOptions {
+            cipher_suites: cipher_suites.to_vec(),
+            ..HandshakeOptions::default()
+        };
+        Self::with_options(server_addr, private_key, server_public_key, options, config).await
+    }
+
+    /// Like `new`, with full control over the handshake.
+    pub async fn with_options(
+        server_addr: &str,
+        private_key: SecretKey,
+        server_public_key: [u8; 32],
+        options: HandshakeOptions,
+        config: Option<VpnConfig>,
+    ) -> Result<Self, VpnError> {
+        Self::connect(server_addr, private_key, server_public_key, options, config).await
+    }
+}
+
+impl<C: Connect> VpnClient<C> {
+    /// Like `with_options`, connecting over `C`, such as a `UdpClient`. The
+    /// server must listen over the matching transport.
+    pub async fn connect(
+        server_addr: &str,
+        private_key: SecretKey,
+        server_public_key: [u8; 32],
+        options: HandshakeOptions,
+        config: Option<VpnConfig>,
+    ) -> Result<Self, VpnError> {
+        let client = C::connect(server_addr).await?;
+
+        // Negotiate per-session keys before anything is encrypted
+        let (protocol_handler, key_exchange_mode) =
+            Self::key_exchange(&client, &private_key, server_public_key, &options).await?;
         let config = config.unwrap_or_default();
 
         let mut vpn_client = Self {
-            client,
+            server_addr: server_addr.to_string(),
+            client: Arc::new(client),
             protocol_handler,
+            key_exchange_mode,
             config,
             connected: false,
-            client_thread: None,
-            shutdown_flag: Arc::new(AtomicBool::new(false)),
+            keepalive_task: None,
+            read_timeout: READ_TIMEOUT,
+            last_transaction_id: 0,
+            in_flight: Vec::new(),
+            pushes: VecDeque::new(),
+            received: VecDeque::new(),
+            latency: LatencyStats::default(),
+            closed_by_server: None,
         };
 
         // Perform initial handshake
-        vpn_client.handshake()?;
+        vpn_client.handshake().await?;
 
         Ok(vpn_client)
     }
 
-    fn handshake(&mut self) -> Result<(), VpnError> {
-        // Create config request packet
-        let config_request: VpnPacket = VpnPacket::new_control(ControlType::ConfigRequest);
-        println!("config_request: {:?}", config_request);
-        let encrypted_request = self.protocol_handler.pack(config_request)?;
+    async fn key_exchange(
+        client: &C,
+        private_key: &SecretKey,
+        server_public_key: [u8; 32],
+        options: &HandshakeOptions,
+    ) -> Result<(ProtocolHandler, KeyExchangeMode), VpnError> {
+        let cipher_suites = options.cipher_suites.as_slice();
 
-        // Send config request
-        self.client.write_packet(&encrypted_request)?;
+        // Only pay for an ML-KEM keypair when offering the hybrid mode
+        let kem_keypair = options
+            .key_exchange_modes
+            .contains(&KeyExchangeMode::HybridMlKem768)
+            .then(KemKeypair::new);
+        let capabilities = options.capabilities.intersection(Capabilities::SUPPORTED);
+        let offer = HandshakeOffer {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities,
+            cipher_suites: cipher_suites.to_vec(),
+            key_exchange_modes: options.key_exchange_modes.clone(),
+            kem_encapsulation_key: kem_keypair
+                .as_ref()
+                .map(|keypair| keypair.encapsulation_key().to_vec()),
+        };
 
-        // Read response
-        let encrypted_response = self.client.client_read_packet()?;
+        // Send a handshake initiation in the clear, a fresh one while it may
+        // have been lost since the server refuses any it has seen before. It
+        // answers each with a session of its own, the spares expire unused.
+        let mut attempts = Vec::new();
+        let mut backoff = Backoff::new(C::LOSES_FRAMES);
+        let response = loop {
+            let mut handshake = Handshake::new_initiator(
+                KeyExchange::from_private_key(private_key),
+                server_public_key,
+            );
+            if let Some(preshared_key) = &options.preshared_key {
+                handshake.set_preshared_key(preshared_key);
+            }
+            let initiation = PacketHeader::handshake(MessageType::HandshakeInitiation, 0)
+                .frame(&handshake.write_initiation(&offer.to_bytes()?)?);
+            attempts.push(handshake);
+            client.send_frame(&initiation).await?;
+            // Only the pinned server can produce a valid response
+            match timeout(backoff.wait(), client.receive_frame()).await {
+                Ok(response) => break response?,
+                Err(_) if backoff.retry() => continue,
+                Err(_) => return Err(VpnError::Io(ErrorKind::TimedOut.into())),
+            }
+        };
+        let header = PacketHeader::from_bytes(&response)?;
+        if header.message_type != MessageType::HandshakeResponse {
+            return Err(VpnError::KeyExchange("Invalid handshake response".into()));
+        }
 
-        let response = self.protocol_handler.unpack(&encrypted_response)?;
+        // A late answer to an earlier initiation is as good as one to the last
+        let mut reply = Err(VpnError::KeyExchange("No handshake initiated".into()));
+        for mut handshake in attempts.into_iter().rev() {
+            reply = handshake
+                .read_response(&response[HEADER_LEN..])
+                .map(|reply| (handshake, reply));
+            if reply.is_ok() {
+                break;
+            }
+        }
+        let (mut handshake, reply) = reply?;
+        let selection = match HandshakeReply::from_bytes(&reply)? {
+            HandshakeReply::Accepted(selection) => selection,
+            // Typed when retrying can't help until one side upgrades
+            HandshakeReply::Refused(refusal)
+                if refusal.max_version < MIN_PROTOCOL_VERSION
+                    || refusal.min_version > PROTOCOL_VERSION =>
+            {
+                return Err(VpnError::Remote(
+                    ErrorCode::BadVersion,
+                    format!(
+                        "{} (it accepts protocol versions {}-{})",
+                        refusal.reason, refusal.min_version, refusal.max_version
+                    ),
+                ))
+            }
+            HandshakeReply::Refused(refusal) => {
+                return Err(VpnError::KeyExchange(format!(
+                    "Server refused the handshake: {} (it accepts protocol versions {}-{})",
+                    refusal.reason, refusal.min_version, refusal.max_version
+                )))
+            }
+        };
 
-        // Verify response type
-        if response.packet_type != PacketType::Control
-            || response.control_type != Some(ControlType::ConfigResponse)
-        {
-            return Err(VpnError::Protocol("Invalid handshake response".into()));
+        // The server must pick one of the versions, suites and modes we offered
+        let (version, suite, session_id, mode) = (
+            selection.version,
+            selection.cipher_suite,
+            selection.session_id,
+            selection.key_exchange_mode,
+        );
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            return Err(VpnError::KeyExchange(format!(
+                "Server selected unsupported protocol version {}",
+                version
+            )));
         }
+        if !capabilities.contains(selection.capabilities) {
+            return Err(VpnError::KeyExchange(format!(
+                "Server selected unoffered capabilities {:#x}",
+                selection.capabilities.bits()
+            )));
+        }
+        if session_id != header.session_id {
+            return Err(VpnError::KeyExchange("Session id mismatch".into()));
+        }
+        if !cipher_suites.contains(&suite) {
+            return Err(VpnError::KeyExchange(format!(
+                "Server selected unoffered cipher suite {:?}",
+                suite
+            )));
+        }
+        if !options.key_exchange_modes.contains(&mode) {
+            return Err(VpnError::KeyExchange(format!(
+                "Server selected unoffered key exchange mode {:?}",
+                mode
+            )));
+        }
+
+        if let (KeyExchangeMode::HybridMlKem768, Some(kem_keypair)) = (mode, &kem_keypair) {
+            let ciphertext = selection.kem_ciphertext.as_deref().ok_or_else(|| {
+                VpnError::KeyExchange("Hybrid mode selected without an ML-KEM ciphertext".into())
+            })?;
+            handshake.mix_kem_secret(&*kem_keypair.decapsulate(ciphertext)?)?;
+        }
+
+        let session_keys = handshake.into_session_keys()?;
+        Ok((
+            ProtocolHandler::from_session_keys(suite, session_id, &session_keys)
+                .with_negotiated(version, selection.capabilities),
+            mode,
+        ))
+    }
+
+    async fn handshake(&mut self) -> Result<(), VpnError> {
+        // Request the config, the first packet sent under the session keys
+        let config: VpnConfig = self.exchange(&ConfigRequest).await?;
 
         // Apply received configuration
-        self.apply_config(&response.payload)?;
+        self.apply_config(config)?;
         self.connected = true;
 
-        // Start keepalive thread
-        self.start_keepalive()?;
+        // The announced MTU is only an upper bound for the path to the server,
+        // which matters where datagrams carry the frames
+        if C::LOSES_FRAMES
+            && self
+                .capabilities()
+                .contains(Capabilities::PATH_MTU_DISCOVERY)
+        {
+            if let Err(e) = self.discover_path_mtu().await {
+                eprintln!(
+                    "Path MTU discovery failed, keeping {}: {:?}",
+                    self.path_mtu(),
+                    e
+                );
+            }
+        }
+
+        self.start_keepalive();
+
+        Ok(())
+    }
+
+    /// Sends `packet` and returns what answers it: the response to a control
+    /// request, otherwise the next data packet from the server. Pushes read
+    /// meanwhile are kept for `take_pushes`.
+    pub async fn send_packet(&mut self, packet: VpnPacket) -> Result<VpnPacket, VpnError> {
+        self.prepare_send().await?;
+
+        if packet.is_control() {
+            let transaction_id = self.start_request(packet).await?;
+            return self.wait_response(transaction_id).await;
+        }
+        self.write_packet(packet).await?;
+        self.next_data().await
+    }
+
+    /// Sends a control request without waiting for its response, returning
+    /// the transaction id to pass to `wait_response`. Any number of requests
+    /// may be in flight at once.
+    pub async fn send_request<T: ControlPayload>(&mut self, message: &T) -> Result<u32, VpnError> {
+        self.prepare_send().await?;
+        let request = self.control(message)?;
+        self.start_request(request).await
+    }
+
+    /// Waits for the response to the request sent as `transaction_id`.
+    /// Responses to other requests, pushes and data read meanwhile are set
+    /// aside until they are asked for.
+    pub async fn wait_response(&mut self, transaction_id: u32) -> Result<VpnPacket, VpnError> {
+        loop {
+            let Some(index) = self
+                .in_flight
+                .iter()
+                .position(|(id, _)| *id == transaction_id)
+            else {
+                return Err(VpnError::Protocol(format!(
+                    "No request {} in flight",
+                    transaction_id
+                )));
+            };
+            if let Some(response) = self.in_flight[index].1.take() {
+                self.in_flight.remove(index);
+                return Ok(response);
+            }
+
+            let packet = self.read_packet().await?;
+            self.set_aside(packet).await?;
+        }
+    }
+
+    /// Control messages the server sent unprompted, oldest first, as far as
+    /// they were read while waiting for responses and data.
+    pub fn take_pushes(&mut self) -> Vec<VpnPacket> {
+        self.pushes.drain(..).collect()
+    }
+
+    // Checks the client may send, switching to fresh keys first if the current
+    // ones wear out and the server agreed to; otherwise the counter limit
+    // still ends the session safely
+    async fn prepare_send(&mut self) -> Result<(), VpnError> {
+        if !self.connected {
+            return Err(VpnError::Protocol("Not connected".into()));
+        }
+        if let Some((code, reason)) = &self.closed_by_server {
+            return Err(VpnError::Remote(*code, reason.clone()));
+        }
+        if self.protocol_handler.needs_rekey() && self.capabilities().contains(Capabilities::REKEY)
+        {
+            self.rekey().await?;
+        }
+        Ok(())
+    }
 
+    // Packs and encrypts the packet, in fragments if it exceeds the MTU
+    async fn write_packet(&mut self, packet: VpnPacket) -> Result<(), VpnError> {
+        if let Err(e) = self.send_frames(packet).await {
+            return Err(self.closing_error().await.unwrap_or(e));
+        }
         Ok(())
     }
 
-    pub fn send_packet(&mut self, packet: VpnPacket) -> Result<VpnPacket, VpnError> {
+    // Like `write_packet`, without looking for why a failed write failed
+    async fn send_frames(&self, packet: VpnPacket) -> Result<(), VpnError> {
+        for encrypted in self.protocol_handler.pack_fragments(packet)? {
+            self.client.send_frame(&encrypted).await?;
+        }
+        Ok(())
+    }
+
+    // Why the server dropped the connection, if it said so before closing
+    async fn closing_error(&mut self) -> Option<VpnError> {
+        self.read_timeout = CLOSING_ERROR_TIMEOUT;
+        let error = loop {
+            let result = match self.read_packet().await {
+                Ok(packet) => self.set_aside(packet).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(()) => continue,
+                Err(e @ VpnError::Remote(..)) => break Some(e),
+                Err(_) => break None,
+            }
+        };
+        self.read_timeout = READ_TIMEOUT;
+        error
+    }
+
+    fn new_transaction_id(&mut self) -> u32 {
+        self.last_transaction_id = self.last_transaction_id.wrapping_add(1);
+        self.last_transaction_id
+    }
+
+    // Tags a request with a fresh transaction id and sends it
+    async fn start_request(&mut self, request: VpnPacket) -> Result<u32, VpnError> {
+        let transaction_id = self.new_transaction_id();
+        self.write_packet(request.with_transaction_id(transaction_id)?)
+            .await?;
+        self.in_flight.push((transaction_id, None));
+        Ok(transaction_id)
+    }
+
+    // Sends a request and decodes its response. Over lossy transports the
+    // request is resent until answered, the server answers every copy alike.
+    async fn exchange<T: ControlPayload, R: ControlPayload>(
+        &mut self,
+        message: &T,
+    ) -> Result<R, VpnError> {
+        let transaction_id = self.new_transaction_id();
+        let request = self.control(message)?.with_transaction_id(transaction_id)?;
+        self.write_packet(request.clone()).await?;
+        self.in_flight.push((transaction_id, None));
+
+        let mut backoff = Backoff::new(C::LOSES_FRAMES);
+        let response = loop {
+            self.read_timeout = backoff.wait();
+            let response = self.wait_response(transaction_id).await;
+            self.read_timeout = READ_TIMEOUT;
+            match response {
+                Err(VpnError::Io(e)) if e.kind() == ErrorKind::TimedOut && backoff.retry() => {
+                    self.write_packet(request.clone()).await?;
+                }
+                response => break response?,
+            }
+        };
+        response.to_control()
+    }
+
+    // Files a packet read while waiting for something else
+    async fn set_aside(&mut self, packet: VpnPacket) -> Result<(), VpnError> {
+        if packet.is_keepalive() {
+            return Ok(());
+        }
+        if !packet.is_control() {
+            self.received.push_back(packet);
+            return Ok(());
+        }
+
+        // The server says why before it drops the connection
+        if packet.control_type() == Some(ControlType::Error) {
+            let error: ErrorMessage = packet.to_control()?;
+            self.closed_by_server = Some((error.code, error.reason.clone()));
+            return Err(VpnError::Remote(error.code, error.reason));
+        }
+
+        let pending = match packet.transaction_id()? {
+            Some(transaction_id) => self
+                .in_flight
+                .iter_mut()
+                .find(|(id, response)| *id == transaction_id && response.is_none()),
+            None => {
+                // Answer pings right away, the server is timing us
+                if let Ok(ping) = packet.to_control::<Ping>() {
+                    let pong = self.control(&ping.pong())?;
+                    return self.send_frames(pong).await;
+                }
+                self.pushes.push_back(packet);
+                return Ok(());
+            }
+        };
+        match pending {
+            Some((_, response)) => *response = Some(packet),
+            // Most likely the ack of a probe that was given up on
+            None => eprintln!(
+                "Dropping {:?} response to no request in flight",
+                packet.control_type()
+            ),
+        }
+        Ok(())
+    }
+
+    // Next data packet from the server, setting aside anything else
+    async fn next_data(&mut self) -> Result<VpnPacket, VpnError> {
+        loop {
+            if let Some(packet) = self.received.pop_front() {
+                return Ok(packet);
+            }
+            let packet = self.read_packet().await?;
+            self.set_aside(packet).await?;
+        }
+    }
+
+    /// Measures the round trip to the server, which also updates the
+    /// estimates `latency` returns. A ping unanswered for `PONG_TIMEOUT`
+    /// fails and counts as lost.
+    pub async fn ping(&mut self) -> Result<Duration, VpnError> {
+        if !self.capabilities().contains(Capabilities::PING) {
+            return Err(VpnError::Protocol("Server did not agree to pings".into()));
+        }
+        self.prepare_send().await?;
+
+        let ping = self.latency.ping();
+        let request = self.control(&ping)?;
+        let transaction_id = self.start_request(request).await?;
+
+        self.read_timeout = PONG_TIMEOUT;
+        let response = self.wait_response(transaction_id).await;
+        self.read_timeout = READ_TIMEOUT;
+        if response.is_err() {
+            // Drop the pong if it still arrives
+            self.in_flight.retain(|(id, _)| *id != transaction_id);
+        }
+
+        let pong: Pong = response?.to_control()?;
+        self.latency.pong(&pong).ok_or_else(|| {
+            VpnError::Protocol(format!(
+                "Pong {} answers no ping awaiting one",
+                pong.sequence
+            ))
+        })
+    }
+
+    /// Round trip time, jitter and loss measured by `ping`.
+    pub fn latency(&self) -> &LatencyStats {
+        &self.latency
+    }
+
+    /// MTU the session currently sends with.
+    pub fn path_mtu(&self) -> usize {
+        self.protocol_handler.mtu()
+    }
+
+    /// Probes for the largest frame that reaches the server, up to the MTU it
+    /// announced, and moves both directions of the session to it. Runs after
+    /// connecting and reconnecting over datagram transports, streams carry
+    /// frames of any size; call it again if the path may have changed.
+    pub async fn discover_path_mtu(&mut self) -> Result<usize, VpnError> {
         if !self.connected {
             return Err(VpnError::Protocol("Not connected".into()));
         }
+        if !C::LOSES_FRAMES {
+            return Err(VpnError::Protocol(
+                "Path MTU discovery needs a datagram transport".into(),
+            ));
+        }
+        if !self
+            .capabilities()
+            .contains(Capabilities::PATH_MTU_DISCOVERY)
+        {
+            return Err(VpnError::Protocol(
+                "Server did not agree to path MTU discovery".into(),
+            ));
+        }
 
-        // Pack and encrypt the packet
-        let encrypted = self.protocol_handler.pack(packet)?;
+        self.read_timeout = MTU_PROBE_TIMEOUT;
+        let found = self
+            .search_path_mtu(MIN_MTU, self.config.mtu.max(MIN_MTU))
+            .await;
+        self.read_timeout = READ_TIMEOUT;
 
-        // Send packet
-        self.client.write_packet(&encrypted)?;
+        self.prepare_send().await?;
+        let applied: MtuUpdate = self.exchange(&MtuUpdate { mtu: found? as u32 }).await?;
 
-        // Read response
-        let encrypted_response = self.client.client_read_packet()?;
+        let mtu = applied.mtu as usize;
+        self.protocol_handler.set_mtu(mtu)?;
+        Ok(mtu)
+    }
 
-        // Decrypt and unpack response
-        self.protocol_handler.unpack(&encrypted_response)
+    // Binary search for the largest size that gets an ack, `low` assumed to work
+    async fn search_path_mtu(
+        &mut self,
+        mut low: usize,
+        mut high: usize,
+    ) -> Result<usize, VpnError> {
+        // Most paths carry the full MTU, so try that before searching
+        let mut candidate = high;
+        while low < high {
+            if self.probe(candidate).await? {
+                low = candidate;
+            } else {
+                high = candidate - 1;
+            }
+            candidate = (low + high).div_ceil(2);
+        }
+        Ok(low)
     }
 
-    fn apply_config(&mut self, config_data: &[u8]) -> Result<(), VpnError> {
-        // Parse and apply configuration from server
-        let config = VpnConfig::from_bytes(config_data)?;
+    async fn probe(&mut self, frame_len: usize) -> Result<bool, VpnError> {
+        let transaction_id = self.new_transaction_id();
+        let probe = self
+            .protocol_handler
+            .pack_probe(frame_len, transaction_id)?;
+        match self.client.send_frame(&probe).await {
+            Ok(()) => {}
+            // Too large for our own link already
+            Err(e) if exceeds_path_mtu(&e) => return Ok(false),
+            Err(e) => return Err(e),
+        }
+        self.in_flight.push((transaction_id, None));
+
+        let acked = match self.wait_response(transaction_id).await {
+            Ok(ack) => Ok(ack
+                .to_control::<MtuProbeAck>()
+                .is_ok_and(|ack| ack.frame_len as usize == frame_len)),
+            Err(VpnError::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        };
+
+        // An ack arriving after this is dropped
+        self.in_flight.retain(|(id, _)| *id != transaction_id);
+        acked
+    }
+
+    // Reads frames until a whole packet has arrived
+    async fn read_packet(&mut self) -> Result<VpnPacket, VpnError> {
+        loop {
+            let encrypted = timeout(self.read_timeout, self.client.receive_frame())
+                .await
+                .map_err(|_| VpnError::Io(ErrorKind::TimedOut.into()))??;
+            let received = match PacketHeader::from_bytes(&encrypted) {
+                // A late answer to a resent handshake initiation
+                Ok(header)
+                    if C::LOSES_FRAMES && header.message_type == MessageType::HandshakeResponse =>
+                {
+                    continue
+                }
+                Ok(_) => self.protocol_handler.receive(&encrypted),
+                Err(e) => Err(e),
+            };
+            match received {
+                Ok(Some(packet)) => return Ok(packet),
+                Ok(None) => {}
+                // Datagrams get duplicated and reordered, and anyone can send
+                // one; losing the bad one is no reason to give up on the rest
+                Err(
+                    e @ (VpnError::Protocol(_)
+                    | VpnError::Encryption(_)
+                    | VpnError::ReplayedPacket(_)),
+                ) if C::LOSES_FRAMES => eprintln!("Dropped a frame from the server: {:?}", e),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Session id the server assigned, stable across reconnects.
+    pub fn session_id(&self) -> u32 {
+        self.protocol_handler.session_id()
+    }
+
+    /// Opens a fresh connection to the server and resumes the current session
+    /// on it, keeping the routes and config the server holds for us. Retries
+    /// up to the configured number of reconnect attempts.
+    pub async fn reconnect(&mut self) -> Result<(), VpnError> {
+        if !self.connected {
+            return Err(VpnError::Protocol("Not connected".into()));
+        }
+        if !self.capabilities().contains(Capabilities::ROAMING) {
+            return Err(VpnError::Protocol(
+                "Server did not agree to roaming, reconnect with a new client".into(),
+            ));
+        }
+
+        let mut attempts_left = self.config.reconnect_attempts.max(1);
+        loop {
+            match self.resume_session().await {
+                Ok(()) => break,
+                Err(e) if attempts_left > 1 => {
+                    eprintln!("Reconnect to {} failed: {:?}", self.server_addr, e);
+                    attempts_left -= 1;
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        // The new connection may take a different path
+        if C::LOSES_FRAMES
+            && self
+                .capabilities()
+                .contains(Capabilities::PATH_MTU_DISCOVERY)
+        {
+            if let Err(e) = self.discover_path_mtu().await {
+                eprintln!(
+                    "Path MTU discovery failed, keeping {}: {:?}",
+                    self.path_mtu(),
+                    e
+                );
+            }
+        }
+        Ok(())
+    }
+
+    async fn resume_session(&mut self) -> Result<(), VpnError> {
+        let client = C::connect(&self.server_addr).await?;
+
+        // Any authenticated packet moves the session over to the new connection
+        let keepalive = self.protocol_handler.pack(VpnPacket::new_keepalive())?;
+        client.send_frame(&keepalive).await?;
+
+        self.client = Arc::new(client);
+
+        // Responses to requests sent on the old connection are lost with it
+        self.in_flight.clear();
+        self.closed_by_server = None;
+
+        // Keepalives follow onto the new connection
+        self.start_keepalive();
+        Ok(())
+    }
+
+    /// Protocol version negotiated with the server.
+    pub fn protocol_version(&self) -> u8 {
+        self.protocol_handler.version()
+    }
+
+    /// Optional features both sides agreed to.
+    pub fn capabilities(&self) -> Capabilities {
+        self.protocol_handler.capabilities()
+    }
+
+    /// How well data packets compressed in each direction, if the server
+    /// agreed to compression.
+    pub fn compression_stats(&self) -> CompressionStats {
+        self.protocol_handler.compression_stats()
+    }
+
+    /// Key exchange mode negotiated with the server.
+    pub fn key_exchange_mode(&self) -> KeyExchangeMode {
+        self.key_exchange_mode
+    }
+
+    /// Cipher suite negotiated with the server.
+    pub fn cipher_suite(&self) -> CipherSuite {
+        self.protocol_handler.cipher_suite()
+    }
+
+    pub fn set_rekey_policy(&mut self, policy: RekeyPolicy) {
+        self.protocol_handler.set_rekey_policy(policy);
+    }
+
+    /// Pads the frames the client sends to hide their size, if the server
+    /// agreed to strip padding.
+    pub fn set_padding_policy(&mut self, policy: PaddingPolicy) -> Result<(), VpnError> {
+        self.protocol_handler.set_padding_policy(policy)
+    }
+
+    async fn rekey(&mut self) -> Result<(), VpnError> {
+        let ephemeral = KeyExchange::new();
+
+        // The server answers under the old keys before switching
+        let response: Rekey = self
+            .exchange(&Rekey {
+                public_key: ephemeral.public_key_bytes(),
+            })
+            .await?;
+        let shared_secret = ephemeral.diffie_hellman(&response.public_key)?;
+
+        self.protocol_handler.rekey(&shared_secret, true)
+    }
+
+    // Encodes `message` as a control packet
+    fn control<T: ControlPayload>(&self, message: &T) -> Result<VpnPacket, VpnError> {
+        VpnPacket::from_control(message)
+    }
+
+    fn apply_config(&mut self, config: VpnConfig) -> Result<(), VpnError> {
+        self.protocol_handler.set_mtu(config.mtu)?;
         self.config = config;
         Ok(())
     }
 
-    fn start_keepalive(&mut self) -> Result<(), VpnError> {
-        let mut client = self.client.clone();
+    // Replaces any keepalive task running for an earlier connection
+    fn start_keepalive(&mut self) {
+        let client = self.client.clone();
         let protocol_handler = self.protocol_handler.clone();
         let interval = self.config.keepalive_interval;
-        let shutdown_flag = self.shutdown_flag.clone();
 
-        self.client_thread = Some(std::thread::spawn(move || {
-            while !shutdown_flag.load(std::sync::atomic::Ordering::Relaxed) {
+        let task = tokio::spawn(async move {
+            loop {
                 let keepalive = VpnPacket::new_keepalive();
                 if let Ok(encrypted) = protocol_handler.pack(keepalive) {
-                    if client.write_packet(&encrypted).is_err() {
+                    if client.send_frame(&encrypted).await.is_err() {
                         break;
                     }
                 }
-                std::thread::sleep(interval);
+                tokio::time::sleep(interval).await;
             }
-        }));
+        });
+        if let Some(previous) = self.keepalive_task.replace(task) {
+            previous.abort();
+        }
+    }
 
+    /// Announces the networks reachable through this client, replacing any
+    /// routes announced before.
+    pub async fn update_routes(&mut self, routes: &[RouteEntry]) -> Result<(), VpnError> {
+        self.prepare_send().await?;
+        let _: RouteUpdateAck = self
+            .exchange(&RouteUpdate {
+                routes: routes.to_vec(),
+            })
+            .await?;
         Ok(())
     }
 
-    pub fn disconnect(&mut self) -> Result<(), VpnError> {
+    pub async fn disconnect(&mut self) -> Result<(), VpnError> {
         if self.connected {
-            let disconnect_packet = VpnPacket::new_control(ControlType::Disconnect);
+            let disconnect_packet = self.control(&Disconnect)?;
             let encrypted = self.protocol_handler.pack(disconnect_packet)?;
-            self.client.write_packet(&encrypted)?;
+            self.client.send_frame(&encrypted).await?;
             self.connected = false;
-            if let Some(handle) = std::mem::take(&mut self.client_thread) {
-                handle.join().unwrap();
-            };
+            if let Some(task) = self.keepalive_task.take() {
+                task.abort();
+            }
+            self.client.close().await?;
         }
         Ok(())
     }
 }
 
-impl Drop for VpnClient {
+// When to give up waiting for an answer and resend, at doubling intervals
+// until the read timeout runs out. Only lossy transports resend at all.
+struct Backoff {
+    deadline: Instant,
+    wait: Duration,
+}
+
+impl Backoff {
+    fn new(resend: bool) -> Self {
+        Self {
+            deadline: Instant::now() + READ_TIMEOUT,
+            wait: if resend {
+                RETRANSMIT_TIMEOUT
+            } else {
+                READ_TIMEOUT
+            },
+        }
+    }
+
+    // How long to wait for the answer this time
+    fn wait(&self) -> Duration {
+        self.wait
+            .min(self.deadline.saturating_duration_since(Instant::now()))
+    }
+
+    // Whether to resend after a wait ran out
+    fn retry(&mut self) -> bool {
+        if Instant::now() >= self.deadline {
+            return false;
+        }
+        self.wait *= 2;
+        true
+    }
+}
+
+impl<C: Connect> Drop for VpnClient<C> {
     fn drop(&mut self) {
-        let _ = self.disconnect();
+        if let Some(task) = self.keepalive_task.take() {
+            task.abort();
+        }
+        if !self.connected {
+            return;
+        }
+
+        // Tell the server when there is still a runtime to do it on, otherwise
+        // the session expires
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let Ok(encrypted) = self
+            .control(&Disconnect)
+            .and_then(|packet| self.protocol_handler.pack(packet))
+        else {
+            return;
+        };
+        let client = self.client.clone();
+        runtime.spawn(async move {
+            let _ = client.send_frame(&encrypted).await;
+            let _ = client.close().await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::memory::{MemoryListener, MemoryTransport};
+    use crate::network::transport::{Listener, Transport};
+    use crate::protocol::HandshakeRefusal;
+    use crate::vpn_service::VpnService;
+
+    // A service bound to `name` on the in-memory transport, and a client of it
+    async fn connect(name: &str) -> (VpnService<MemoryListener>, VpnClient<MemoryTransport>) {
+        connect_with(name, HandshakeOptions::default()).await
+    }
+
+    async fn connect_with(
+        name: &str,
+        options: HandshakeOptions,
+    ) -> (VpnService<MemoryListener>, VpnClient<MemoryTransport>) {
+        let server_key = SecretKey::generate();
+        let server_public_key = server_key.public_key();
+        let client_key = SecretKey::generate();
+        let mut service = VpnService::with_listener(
+            MemoryListener::bind(name).unwrap(),
+            server_key,
+            &[client_key.public_key()],
+            None,
+        )
+        .unwrap();
+        service.start().unwrap();
+
+        let client = VpnClient::<MemoryTransport>::connect(
+            name,
+            client_key,
+            server_public_key,
+            options,
+            None,
+        )
+        .await
+        .unwrap();
+        (service, client)
+    }
+
+    fn data_packet() -> VpnPacket {
+        VpnPacket::new_data([10, 0, 0, 1], [10, 0, 0, 2], vec![7; 64])
+    }
+
+    #[tokio::test]
+    async fn test_error_message_ends_session() {
+        let (service, mut client) = connect("client-test-error").await;
+        service
+            .terminate(client.session_id(), ErrorCode::QuotaExceeded, "Over quota")
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            client.send_packet(data_packet()).await,
+            Err(VpnError::Remote(ErrorCode::QuotaExceeded, reason)) if reason == "Over quota"
+        ));
+        // The server hung up after saying why, and the client stays closed
+        assert!(client.client.receive_frame().await.is_err());
+        assert!(service.session_ids().is_empty());
+        assert!(matches!(
+            client.send_packet(data_packet()).await,
+            Err(VpnError::Remote(ErrorCode::QuotaExceeded, _))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fatal_error_reason_is_fixed() {
+        let (_service, mut client) = connect("client-test-fatal").await;
+
+        // A rekey to a low order point fails the server's key exchange
+        let rekey = client.exchange::<Rekey, Rekey>(&Rekey {
+            public_key: [0; 32],
+        });
+        assert!(matches!(
+            rekey.await,
+            Err(VpnError::Remote(ErrorCode::AuthFailed, reason))
+                if reason == ErrorCode::AuthFailed.description()
+        ));
+        assert!(client.client.receive_frame().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_malformed_control_keeps_connection() {
+        let (_service, mut client) = connect("client-test-malformed").await;
+
+        // A field announcing 9 bytes that never come
+        let mut update = VpnPacket::new_control(ControlType::RouteUpdate);
+        update.set_payload(vec![1, 0, 9]);
+        client.send_frames(update).await.unwrap();
+
+        let echo = client.send_packet(data_packet()).await.unwrap();
+        assert_eq!(echo.payload, data_packet().payload);
+    }
+
+    #[tokio::test]
+    async fn test_connection_task_ends_when_peer_drops() {
+        let (service, client) = connect("client-test-drop").await;
+        let session_id = client.session_id();
+
+        // Hang up without a goodbye, as a client losing its network would
+        client.client.close().await.unwrap();
+        let forgotten = async {
+            while !matches!(
+                service.push(session_id, &Disconnect).await,
+                Err(VpnError::ClientNotFound)
+            ) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        timeout(Duration::from_secs(1), forgotten)
+            .await
+            .expect("Connection task outlived its peer");
+        // The session waits for the client to come back
+        assert_eq!(service.session_ids(), vec![session_id]);
+    }
+
+    #[tokio::test]
+    async fn test_capabilities_offered() {
+        let options = HandshakeOptions {
+            capabilities: Capabilities::PING,
+            ..HandshakeOptions::default()
+        };
+        let (_service, mut client) = connect_with("client-test-capabilities", options).await;
+
+        assert_eq!(client.capabilities(), Capabilities::PING);
+        client.ping().await.unwrap();
+        assert!(matches!(
+            client.reconnect().await,
+            Err(VpnError::Protocol(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_server_without_common_version_refused() {
+        // A server from before versions 1 and 2 were dropped
+        let name = "client-test-old-server";
+        let server_key = SecretKey::generate();
+        let server_public_key = server_key.public_key();
+        let listener = MemoryListener::bind(name).unwrap();
+        let server = tokio::spawn(async move {
+            let link = listener.accept().await.unwrap();
+            let initiation = link.receive_frame().await.unwrap();
+            let mut handshake =
+                Handshake::new_responder(KeyExchange::from_private_key(&server_key));
+            let offer = handshake
+                .read_initiation(&initiation[HEADER_LEN..])
+                .unwrap();
+            assert_eq!(
+                HandshakeOffer::from_bytes(&offer).unwrap().min_version,
+                MIN_PROTOCOL_VERSION
+            );
+
+            let refusal = HandshakeReply::Refused(HandshakeRefusal {
+                min_version: 1,
+                max_version: 2,
+                reason: "Unsupported protocol versions".into(),
+            });
+            let response = PacketHeader::handshake(MessageType::HandshakeResponse, 0).frame(
+                &handshake
+                    .write_response(&refusal.to_bytes().unwrap())
+                    .unwrap(),
+            );
+            link.send_frame(&response).await.unwrap();
+        });
+
+        let result = VpnClient::<MemoryTransport>::connect(
+            name,
+            SecretKey::generate(),
+            server_public_key,
+            HandshakeOptions::default(),
+            None,
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(VpnError::Remote(ErrorCode::BadVersion, reason))
+                if reason.ends_with("(it accepts protocol versions 1-2)")
+        ));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_key_exchange_establishes_session() {
+        let (_service, mut client) = connect("client-test-session").await;
+
+        // The server only echoes what it could decrypt under its side's keys
+        let echo = client.send_packet(data_packet()).await.unwrap();
+        assert_eq!(echo.payload, data_packet().payload);
+    }
+
+    #[tokio::test]
+    async fn test_wrong_server_key_rejected() {
+        let (_service, _client) = connect("client-test-wrong-server-key").await;
+        let impostor_public = KeyExchange::new().public_key_bytes();
+
+        let result = VpnClient::<MemoryTransport>::connect(
+            "client-test-wrong-server-key",
+            SecretKey::generate(),
+            impostor_public,
+            HandshakeOptions::default(),
+            None,
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_keeps_session() {
+        let (service, mut client) = connect("client-test-reconnect").await;
+        let session_id = client.session_id();
+
+        // A new connection, from the server's view a new address
+        client.reconnect().await.unwrap();
+
+        assert_eq!(client.session_id(), session_id);
+        assert_eq!(service.session_ids(), vec![session_id]);
+        // Still understood without a handshake, and answered on the new connection
+        let echo = client.send_packet(data_packet()).await.unwrap();
+        assert_eq!(echo.payload, data_packet().payload);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_session_dropped() {
+        let (_service, mut client) = connect("client-test-unknown-session").await;
+
+        let mut forged = client.protocol_handler.pack(data_packet()).unwrap();
+        forged[4..8].copy_from_slice(&client.session_id().wrapping_add(1).to_be_bytes());
+        client.client.send_frame(&forged).await.unwrap();
+
+        // Nothing echoed, and the connection carries on
+        client.ping().await.unwrap();
+        assert!(client.received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unauthenticated_header_errors_dropped() {
+        let (_service, mut client) = connect("client-test-bad-header").await;
+
+        // Anyone could send these, so they must not cost the session its link
+        let mut unreadable = [0; HEADER_LEN];
+        unreadable[1] = 9;
+        client.client.send_frame(&unreadable).await.unwrap();
+        let response = PacketHeader::handshake(MessageType::HandshakeResponse, client.session_id());
+        client
+            .client
+            .send_frame(&response.frame(&[]))
+            .await
+            .unwrap();
+        let mut other_version = client.protocol_handler.pack(data_packet()).unwrap();
+        other_version[0] = PROTOCOL_VERSION + 1;
+        client.client.send_frame(&other_version).await.unwrap();
+
+        client.ping().await.unwrap();
+        assert!(client.received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_transaction_rejected() {
+        let (_service, mut client) = connect("client-test-unknown-transaction").await;
+
+        assert!(matches!(
+            client.wait_response(42).await,
+            Err(VpnError::Protocol(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mtu_update_capped_without_config() {
+        let name = "client-test-mtu-update";
+        let server_key = SecretKey::generate();
+        let server_public_key = server_key.public_key();
+        let client_key = SecretKey::generate();
+        let mut service = VpnService::with_listener(
+            MemoryListener::bind(name).unwrap(),
+            server_key,
+            &[client_key.public_key()],
+            None,
+        )
+        .unwrap();
+        service.start().unwrap();
+
+        // A session that never asked for its config
+        let link = MemoryTransport::connect(name).await.unwrap();
+        let (handler, _) = VpnClient::key_exchange(
+            &link,
+            &client_key,
+            server_public_key,
+            &HandshakeOptions::default(),
+        )
+        .await
+        .unwrap();
+        let update = VpnPacket::from_control(&MtuUpdate { mtu: u32::MAX })
+            .unwrap()
+            .with_transaction_id(1)
+            .unwrap();
+        link.send_frame(&handler.pack(update).unwrap())
+            .await
+            .unwrap();
+
+        let applied: MtuUpdate = handler
+            .unpack(&link.receive_frame().await.unwrap())
+            .unwrap()
+            .to_control()
+            .unwrap();
+        let mtu = VpnConfig::default().mtu;
+        assert_eq!(applied.mtu as usize, mtu);
+        assert_eq!(service.path_mtu(handler.session_id()), Some(mtu));
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_response_dropped() {
+        let (_service, mut client) = connect("client-test-duplicate-transaction").await;
+
+        // The same request twice, so the server answers it twice
+        let transaction_id = client.new_transaction_id();
+        let request = client
+            .control(&ConfigRequest)
+            .unwrap()
+            .with_transaction_id(transaction_id)
+            .unwrap();
+        client.in_flight.push((transaction_id, None));
+        client.send_frames(request.clone()).await.unwrap();
+        client.send_frames(request).await.unwrap();
+
+        let response = client.wait_response(transaction_id).await.unwrap();
+        assert!(response.to_control::<VpnConfig>().is_ok());
+        // Answered once, the second copy answers nothing in flight
+        assert!(client.wait_response(transaction_id).await.is_err());
+        client.update_routes(&[]).await.unwrap();
+        assert!(client.take_pushes().is_empty());
     }
 }
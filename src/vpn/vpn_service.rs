@@ -1,89 +1,358 @@
 use crate::{
-    crypto::EncryptionManager, error::VpnError, network::tcp_server::TcpServer,
-    protocol::ProtocolHandler, vpn::vpn_worker::VpnWorker,
+    config::settings::PeerConfig,
+    crypto::{handshake::TIMESTAMP_LEN, keys, CipherSuite, KeyExchangeMode, SecretKey},
+    error::VpnError,
+    network::{
+        tcp_server::TcpServer,
+        transport::{Links, Listener},
+    },
+    protocol::{
+        Capabilities, CompressionStats, ControlPayload, ErrorCode, ErrorMessage, PaddingPolicy,
+        MAX_MTU, MIN_MTU, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    },
+    vpn::{session::Session, vpn_worker::VpnWorker},
 };
 use std::collections::HashMap;
-use std::sync::{atomic::AtomicBool, Arc, Mutex};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use std::{thread, vec};
+use tokio::{sync::watch, task::JoinHandle, task::JoinSet};
 
-pub struct VpnService {
-    server: Arc<Mutex<TcpServer>>,
-    routes: Arc<Mutex<HashMap<String, Vec<RouteEntry>>>>,
-    protocol_handler: Arc<Mutex<ProtocolHandler>>,
+pub use crate::protocol::{RouteEntry, RouteUpdate, RouteUpdateAck, VpnConfig};
+pub use crate::vpn::session::ConnectionInfo;
+
+// Pause after a failed accept, e.g. when out of file descriptors
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+pub struct VpnService<L: Listener = TcpServer> {
+    listener: Arc<L>,
+    // Connections currently served, by connection id
+    links: Arc<Links<L::Link>>,
+    routes: Arc<Mutex<HashMap<u32, Vec<RouteEntry>>>>,
+    sessions: Arc<Mutex<HashMap<u32, Session>>>,
     server_config: Arc<Mutex<VpnConfig>>,
-    client_configs: Arc<Mutex<HashMap<String, VpnConfig>>>,
+    client_configs: Arc<Mutex<HashMap<u32, VpnConfig>>>,
+    handshake: Arc<HandshakeSettings>,
 
-    keep_alive_thread: Option<thread::JoinHandle<()>>,
-    worker_threads: Vec<thread::JoinHandle<()>>,
-    shutdown_flag: Arc<AtomicBool>,
+    keep_alive_task: Option<JoinHandle<()>>,
+    accept_task: Option<JoinHandle<()>>,
+    shutdown: watch::Sender<bool>,
 }
 
-#[derive(Clone, Debug)]
-pub struct VpnConfig {
-    pub mtu: usize,
-    pub keepalive_interval: Duration,
-    pub reconnect_attempts: u32,
+/// What the server needs to answer handshakes.
+#[derive(Clone)]
+pub(crate) struct HandshakeSettings {
+    // Shared rather than cloned so there is one copy to wipe
+    pub private_key: Arc<SecretKey>,
+    /// Allowed client static keys and their optional pre-shared keys.
+    pub allowed_peers: HashMap<[u8; 32], Option<Arc<SecretKey>>>,
+    pub cipher_suites: Vec<CipherSuite>,
+    pub key_exchange_modes: Vec<KeyExchangeMode>,
+    /// Clients that can't speak at least this version are refused.
+    pub min_protocol_version: u8,
+    pub capabilities: Capabilities,
+    /// Applied to the frames of every session that agrees to padding.
+    pub padding: PaddingPolicy,
+    /// Newest initiation timestamp accepted from each client static key.
+    pub initiations: Arc<Mutex<HashMap<[u8; 32], [u8; TIMESTAMP_LEN]>>>,
 }
 
-impl Default for VpnConfig {
-    fn default() -> Self {
-        Self {
-            mtu: 1500,
-            keepalive_interval: Duration::from_secs(30),
-            reconnect_attempts: 3,
-        }
+impl VpnService {
+    /// Creates a service listening over TCP, identified by `private_key`,
+    /// that accepts handshakes only from clients whose static public key is
+    /// in `allowed_peers`.
+    pub async fn new(
+        bind_addr: &str,
+        private_key: SecretKey,
+        allowed_peers: &[[u8; 32]],
+        config: Option<VpnConfig>,
+    ) -> Result<Self, VpnError> {
+        Self::with_listener(
+            TcpServer::new(bind_addr).await?,
+            private_key,
+            allowed_peers,
+            config,
+        )
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct RouteEntry {
-    pub target_network: [u8; 4],
-    pub network_mask: [u8; 4],
-    pub next_hop: [u8; 4],
-    pub metric: u32,
+    /// Like `new`, but reads the private key from `private_key_file` and the
+    /// allowed client keys from the JSON peer list in `peers_file`.
+    pub async fn from_key_files(
+        bind_addr: &str,
+        private_key_file: impl AsRef<Path>,
+        peers_file: impl AsRef<Path>,
+        config: Option<VpnConfig>,
+    ) -> Result<Self, VpnError> {
+        let private_key = keys::read_private_key(private_key_file)?;
+        let peers = PeerConfig::load_all(peers_file)?;
+        let allowed_peers = peers
+            .iter()
+            .map(PeerConfig::public_key_bytes)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut service = Self::new(bind_addr, private_key, &allowed_peers, config).await?;
+        for (peer, public_key) in peers.iter().zip(allowed_peers) {
+            if let Some(preshared_key) = peer.preshared_key_bytes()? {
+                service.set_preshared_key(public_key, preshared_key)?;
+            }
+        }
+        Ok(service)
+    }
 }
 
-impl VpnService {
-    pub fn new(
-        bind_addr: &str,
-        encryption_key: [u8; 32],
+impl<L: Listener> VpnService<L> {
+    /// Like `new`, serving the clients of `listener`, such as a `UdpServer`.
+    /// Clients must connect over the matching transport.
+    pub fn with_listener(
+        listener: L,
+        private_key: SecretKey,
+        allowed_peers: &[[u8; 32]],
         config: Option<VpnConfig>,
     ) -> Result<Self, VpnError> {
-        // Initialize TCP server
-        let server = Arc::new(Mutex::new(TcpServer::new(bind_addr)?));
+        let listener = Arc::new(listener);
 
-        // Initialize encryption and protocol handler
-        let encryption = EncryptionManager::new(&encryption_key);
-        let protocol_handler = Arc::new(Mutex::new(ProtocolHandler::new(encryption)));
+        // Session keys are negotiated per client during the handshake
+        let sessions = Arc::new(Mutex::new(HashMap::new()));
 
         // Initialize shared data structures
         let routes = Arc::new(Mutex::new(HashMap::new()));
         let client_configs = Arc::new(Mutex::new(HashMap::new()));
 
         // Use provided config or default
-        let server_config = Arc::new(Mutex::new(config.unwrap_or_default()));
-
-        let shutdown_flag = Arc::new(AtomicBool::new(false));
+        let config = config.unwrap_or_default();
+        if !(MIN_MTU..=MAX_MTU).contains(&config.mtu) {
+            return Err(VpnError::Config(format!(
+                "MTU {} is outside {}-{}",
+                config.mtu, MIN_MTU, MAX_MTU
+            )));
+        }
+        // Every frame must fit what the transport carries
+        if config.mtu > L::MAX_FRAME_LEN {
+            return Err(VpnError::Config(format!(
+                "MTU {} exceeds the transport's largest frame of {} bytes",
+                config.mtu,
+                L::MAX_FRAME_LEN
+            )));
+        }
+        let server_config = Arc::new(Mutex::new(config));
 
         Ok(Self {
-            server,
-            protocol_handler,
+            listener,
+            links: Arc::new(Links::new()),
+            sessions,
             routes,
             client_configs,
+            handshake: Arc::new(HandshakeSettings {
+                private_key: Arc::new(private_key),
+                allowed_peers: allowed_peers.iter().map(|peer| (*peer, None)).collect(),
+                cipher_suites: CipherSuite::ALL.to_vec(),
+                key_exchange_modes: KeyExchangeMode::ALL.to_vec(),
+                min_protocol_version: MIN_PROTOCOL_VERSION,
+                capabilities: Capabilities::DEFAULT,
+                padding: PaddingPolicy::None,
+                initiations: Arc::new(Mutex::new(HashMap::new())),
+            }),
             server_config,
-            keep_alive_thread: None,
-            worker_threads: vec![],
-            shutdown_flag,
+            keep_alive_task: None,
+            accept_task: None,
+            shutdown: watch::Sender::new(false),
         })
     }
 
-    pub fn start(&mut self) -> Result<(), VpnError> {
-        // Start accepting connections
-        self.server
+    /// Static public key clients must pin to reach this service.
+    pub fn public_key(&self) -> [u8; 32] {
+        self.handshake.private_key.public_key()
+    }
+
+    /// Restricts the cipher suites clients may negotiate. Call before `start`.
+    pub fn set_cipher_suites(&mut self, suites: &[CipherSuite]) {
+        Arc::make_mut(&mut self.handshake).cipher_suites = suites.to_vec();
+    }
+
+    /// Restricts the key exchange modes clients may negotiate, e.g. to require
+    /// the hybrid post-quantum mode. Call before `start`.
+    pub fn set_key_exchange_modes(&mut self, modes: &[KeyExchangeMode]) {
+        Arc::make_mut(&mut self.handshake).key_exchange_modes = modes.to_vec();
+    }
+
+    /// Refuses clients that can't speak at least `version`, e.g. to retire
+    /// an old wire format. Call before `start`.
+    pub fn set_min_protocol_version(&mut self, version: u8) -> Result<(), VpnError> {
+        if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            return Err(VpnError::Config(format!(
+                "Protocol version {} is not supported, expected {}-{}",
+                version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            )));
+        }
+        Arc::make_mut(&mut self.handshake).min_protocol_version = version;
+        Ok(())
+    }
+
+    /// Sets the optional features sessions may negotiate, `Capabilities::DEFAULT`
+    /// unless changed. Call before `start`.
+    pub fn set_capabilities(&mut self, capabilities: Capabilities) {
+        Arc::make_mut(&mut self.handshake).capabilities =
+            capabilities.intersection(Capabilities::SUPPORTED);
+    }
+
+    /// Pads the frames the server sends to hide their size. Clients choose
+    /// their own policy. Call before `start`.
+    pub fn set_padding_policy(&mut self, policy: PaddingPolicy) -> Result<(), VpnError> {
+        policy.check()?;
+        Arc::make_mut(&mut self.handshake).padding = policy;
+        Ok(())
+    }
+
+    /// Requires `peer_public_key` to also prove knowledge of `preshared_key`
+    /// during the handshake. Call before `start`.
+    pub fn set_preshared_key(
+        &mut self,
+        peer_public_key: [u8; 32],
+        preshared_key: SecretKey,
+    ) -> Result<(), VpnError> {
+        let handshake = Arc::make_mut(&mut self.handshake);
+        let peer = handshake
+            .allowed_peers
+            .get_mut(&peer_public_key)
+            .ok_or_else(|| VpnError::Config("Pre-shared key for an unknown peer".into()))?;
+        *peer = Some(Arc::new(preshared_key));
+        Ok(())
+    }
+
+    /// Ids of the sessions currently established.
+    pub fn session_ids(&self) -> Vec<u32> {
+        self.sessions
+            .lock()
+            .expect("Sessions in use")
+            .keys()
+            .copied()
+            .collect()
+    }
+
+    /// Traffic and replay statistics for an established session.
+    pub fn connection_info(&self, session_id: u32) -> Option<ConnectionInfo> {
+        self.sessions
+            .lock()
+            .expect("Sessions in use")
+            .get(&session_id)
+            .map(|session| session.info.clone())
+    }
+
+    /// MTU the session currently sends with, lowered by path MTU discovery.
+    pub fn path_mtu(&self, session_id: u32) -> Option<usize> {
+        self.sessions
+            .lock()
+            .expect("Sessions in use")
+            .get(&session_id)
+            .map(|session| session.protocol_handler.mtu())
+    }
+
+    /// How well the session's data packets compressed in each direction.
+    pub fn compression_stats(&self, session_id: u32) -> Option<CompressionStats> {
+        self.sessions
+            .lock()
+            .expect("Sessions in use")
+            .get(&session_id)
+            .map(|session| session.protocol_handler.compression_stats())
+    }
+
+    /// Sends `message` to the session unprompted. It carries no transaction
+    /// id, so the client sets it aside as a push instead of taking it for the
+    /// response to one of its requests.
+    pub async fn push<T: ControlPayload>(
+        &self,
+        session_id: u32,
+        message: &T,
+    ) -> Result<(), VpnError> {
+        self.send_control(session_id, message).await
+    }
+
+    /// Ends the session, telling the client why. `ErrorCode::QuotaExceeded`
+    /// is the code for limits enforced by the application.
+    pub async fn terminate(
+        &self,
+        session_id: u32,
+        code: ErrorCode,
+        reason: &str,
+    ) -> Result<(), VpnError> {
+        let worker = self.worker();
+        let sent = worker
+            .reply(session_id, None, &ErrorMessage::new(code, reason))
+            .await;
+        worker.remove_session(session_id);
+        sent
+    }
+
+    async fn send_control<T: ControlPayload>(
+        &self,
+        session_id: u32,
+        message: &T,
+    ) -> Result<(), VpnError> {
+        self.worker().reply(session_id, None, message).await
+    }
+
+    // Shares the service's state, so it sees the sessions the connection
+    // tasks see
+    fn worker(&self) -> VpnWorker<L> {
+        VpnWorker::new(
+            self.links.clone(),
+            self.routes.clone(),
+            self.sessions.clone(),
+            self.client_configs.clone(),
+            self.server_config.clone(),
+            self.handshake.clone(),
+            self.shutdown.subscribe(),
+        )
+    }
+
+    /// Pings the session. Its pong updates the round trip estimates in the
+    /// session's `ConnectionInfo`; the client answers when it next reads.
+    pub async fn ping(&self, session_id: u32) -> Result<(), VpnError> {
+        let ping = {
+            let mut sessions = self.sessions.lock().expect("Sessions in use");
+            let session = sessions
+                .get_mut(&session_id)
+                .ok_or(VpnError::ClientNotFound)?;
+            if !session
+                .protocol_handler
+                .capabilities()
+                .contains(Capabilities::PING)
+            {
+                return Err(VpnError::Protocol(format!(
+                    "Session {} did not negotiate pings",
+                    session_id
+                )));
+            }
+            session.info.record_ping()
+        };
+        self.push(session_id, &ping).await
+    }
+
+    /// Most specific route the session announced for `ip`, preferring the
+    /// lowest metric between equally specific ones.
+    pub fn route_for(&self, session_id: u32, ip: IpAddr) -> Option<RouteEntry> {
+        self.routes
             .lock()
-            .expect("Unable to access server")
-            .start_accept_loop()?;
+            .expect("Routes in use")
+            .get(&session_id)?
+            .iter()
+            .filter(|route| route.contains(ip))
+            .max_by(|a, b| {
+                a.prefix_len
+                    .cmp(&b.prefix_len)
+                    .then(b.metric.cmp(&a.metric))
+            })
+            .cloned()
+    }
+
+    /// Starts accepting connections, each served by a task of its own.
+    pub fn start(&mut self) -> Result<(), VpnError> {
+        if self.accept_task.is_some() {
+            return Err(VpnError::Config("Service already started".into()));
+        }
+
+        let worker = Arc::new(self.worker());
 
         // Start keepalive monitoring
         let keepalive_interval = self
@@ -91,109 +360,261 @@ impl VpnService {
             .lock()
             .expect("Config in use")
             .keepalive_interval;
-        let server = self.server.clone();
-        let shutdown_flag = Arc::clone(&self.shutdown_flag);
-        self.keep_alive_thread = Some(thread::spawn(move || {
-            while !shutdown_flag.load(std::sync::atomic::Ordering::Relaxed) {
-                {
-                    Self::check_client_keepalive(&server.lock().expect("Server in use"));
+        let expiry = worker.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        self.keep_alive_task = Some(tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(keepalive_interval) => {}
+                    _ = shutdown.wait_for(|&stop| stop) => break,
                 }
-                thread::sleep(keepalive_interval);
+                expiry.expire_sessions();
             }
         }));
 
-        self.spawn_worker();
+        let listener = self.listener.clone();
+        let mut shutdown = self.shutdown.subscribe();
+        self.accept_task = Some(tokio::spawn(async move {
+            let mut connections = JoinSet::new();
+            loop {
+                let link = tokio::select! {
+                    link = listener.accept() => link,
+                    // Reap the tasks of closed connections as they end
+                    Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+                    _ = shutdown.wait_for(|&stop| stop) => break,
+                };
+                match link {
+                    Ok(link) => {
+                        connections.spawn(worker.clone().serve(Arc::new(link)));
+                    }
+                    Err(e) => {
+                        eprintln!("Accept error: {:?}", e);
+                        tokio::time::sleep(ACCEPT_RETRY_DELAY).await;
+                    }
+                }
+            }
+
+            // Each task stops once done with the frame in hand
+            while connections.join_next().await.is_some() {}
+            println!("Accept task exiting");
+        }));
 
         Ok(())
     }
 
-    fn spawn_worker(&mut self) {
-        let server = self.server.clone();
-        let routes = self.routes.clone();
-        let client_configs = self.client_configs.clone();
-        let protocol_handler = self.protocol_handler.clone();
-        let shutdown_flag = self.shutdown_flag.clone();
-
-        self.worker_threads.push(thread::spawn(move || {
-            let worker = VpnWorker::new(
-                server,
-                routes,
-                protocol_handler,
-                client_configs,
-                shutdown_flag,
-            );
-
-            let _ = match worker {
-                Ok(w) => w.main_loop(),
-                Err(e) => {
-                    eprintln!("Worker error: {:?}", e);
-                    Ok(())
-                }
-            };
+    /// Tells every session the server is going away, then stops accepting
+    /// and waits for the connection tasks to finish.
+    pub async fn shutdown(&mut self) -> Result<(), VpnError> {
+        // Rather than leave clients with a dead connection
+        for session_id in self.session_ids() {
+            let code = ErrorCode::ShuttingDown;
+            let error = ErrorMessage::new(code, code.description());
+            if let Err(e) = self.send_control(session_id, &error).await {
+                eprintln!(
+                    "Failed to notify session {} of shutdown: {:?}",
+                    session_id, e
+                );
+            }
+        }
 
-            println!("Worker thread exiting")
-        }));
+        self.shutdown.send_replace(true);
+        let tasks = [self.accept_task.take(), self.keep_alive_task.take()];
+        if tasks.iter().any(Option::is_none) {
+            return Err(VpnError::GenericError(
+                "Shutdown failed to find the service's tasks".to_string(),
+            ));
+        }
+        for task in tasks.into_iter().flatten() {
+            task.await
+                .map_err(|e| VpnError::GenericError(format!("Join error: {:?}", e)))?;
+        }
+        println!("server shut");
+        Ok(())
+    }
+}
+
+impl<L: Listener> Drop for VpnService<L> {
+    fn drop(&mut self) {
+        // The tasks stop on their own once told to
+        self.shutdown.send_replace(true);
     }
+}
 
-    pub fn shutdown(&mut self) -> Result<(), VpnError> {
-        self.shutdown_flag
-            .store(true, std::sync::atomic::Ordering::Relaxed);
-        // Shutdown main thread
-        for t in std::mem::take(&mut self.worker_threads) {
-            t.join().unwrap();
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{Handshake, KeyExchange};
+    use crate::network::memory::{MemoryListener, MemoryTransport};
+    use crate::network::transport::{Connect, Transport};
+    use crate::protocol::{HandshakeOffer, MessageType, PacketHeader, VpnPacket};
+    use crate::vpn_client::{HandshakeOptions, VpnClient};
 
-        // Shutdown keepalive thread
-        let res1 = match self.keep_alive_thread.take().ok_or(VpnError::GenericError(
-            "Shutdown failed to keep alive thread".to_string(),
-        )) {
-            Ok(handle) => {
-                handle
-                    .join()
-                    .map_err(|e| VpnError::GenericError(format!("Join error: {:?}", e)))?;
-                Ok(())
-            }
-            Err(e) => Err(e),
+    #[tokio::test]
+    async fn test_session_over_memory_transport() {
+        let server_key = SecretKey::generate();
+        let server_public_key = server_key.public_key();
+        let client_key = SecretKey::generate();
+        // The shortest interval the config carries, shorter ones round to none
+        let config = VpnConfig {
+            keepalive_interval: Duration::from_secs(1),
+            ..VpnConfig::default()
         };
+        let mut service = VpnService::with_listener(
+            MemoryListener::bind("vpn-service-test").unwrap(),
+            server_key,
+            &[client_key.public_key()],
+            Some(config),
+        )
+        .unwrap();
+        service.start().unwrap();
 
-        // Shutdown server
-        let res2 = self
-            .server
-            .lock()
-            .expect("Unable to access server")
-            .server_shutdown();
+        let mut client = VpnClient::<MemoryTransport>::connect(
+            "vpn-service-test",
+            client_key,
+            server_public_key,
+            HandshakeOptions::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        let packet = VpnPacket::new_data([10, 0, 0, 1], [10, 0, 0, 2], vec![7; 4000]);
+        let echo = client.send_packet(packet).await.unwrap();
+        assert_eq!(echo.payload, vec![7; 4000]);
+        assert_eq!(service.session_ids(), vec![client.session_id()]);
 
-        // Combine results
-        res1.and(res2)
+        client.reconnect().await.unwrap();
+        client.ping().await.unwrap();
+        client.disconnect().await.unwrap();
+        service.shutdown().await.unwrap();
     }
 
-    fn check_client_keepalive(server: &TcpServer) {
-        let stale_clients = server.get_stale_clients();
-        for client_id in stale_clients {
-            println!("Removing stale client: {}", client_id);
-            server.remove_client(&client_id);
-        }
+    #[tokio::test]
+    async fn test_shutdown_stops_connection_tasks() {
+        let server_key = SecretKey::generate();
+        let server_public_key = server_key.public_key();
+        let client_key = SecretKey::generate();
+        let mut service = VpnService::with_listener(
+            MemoryListener::bind("vpn-service-test-shutdown").unwrap(),
+            server_key,
+            &[client_key.public_key()],
+            None,
+        )
+        .unwrap();
+        service.start().unwrap();
+
+        // A peer that never says a word, whose task would otherwise idle on.
+        // Accepted by the time the client behind it is.
+        let silent = MemoryTransport::connect("vpn-service-test-shutdown")
+            .await
+            .unwrap();
+        let mut client = VpnClient::<MemoryTransport>::connect(
+            "vpn-service-test-shutdown",
+            client_key,
+            server_public_key,
+            HandshakeOptions::default(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        tokio::time::timeout(Duration::from_secs(1), service.shutdown())
+            .await
+            .expect("Connection tasks outlived the shutdown")
+            .unwrap();
+        assert!(silent.receive_frame().await.is_err());
+        assert!(matches!(
+            client.ping().await,
+            Err(VpnError::Remote(ErrorCode::ShuttingDown, _))
+        ));
     }
-}
 
-impl VpnConfig {
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self, VpnError> {
-        if bytes.len() < 12 {
-            return Err(VpnError::Protocol("Config data too short".into()));
-        }
+    #[tokio::test]
+    async fn test_only_allowed_keys_accepted() {
+        let server_key = SecretKey::generate();
+        let server_public_key = server_key.public_key();
+        let allowed = SecretKey::generate();
+        let mut service = VpnService::with_listener(
+            MemoryListener::bind("vpn-service-test-allowed").unwrap(),
+            server_key,
+            &[allowed.public_key()],
+            None,
+        )
+        .unwrap();
+        service.start().unwrap();
+
+        let stranger = VpnClient::<MemoryTransport>::connect(
+            "vpn-service-test-allowed",
+            SecretKey::generate(),
+            server_public_key,
+            HandshakeOptions::default(),
+            None,
+        )
+        .await;
+        assert!(stranger.is_err());
+        assert!(service.session_ids().is_empty());
 
-        let mut mtu_bytes = [0u8; 4];
-        let mut keepalive_bytes = [0u8; 4];
-        let mut reconnect_bytes = [0u8; 4];
+        let client = VpnClient::<MemoryTransport>::connect(
+            "vpn-service-test-allowed",
+            allowed,
+            server_public_key,
+            HandshakeOptions::default(),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(service.session_ids(), vec![client.session_id()]);
+    }
 
-        mtu_bytes.copy_from_slice(&bytes[0..4]);
-        keepalive_bytes.copy_from_slice(&bytes[4..8]);
-        reconnect_bytes.copy_from_slice(&bytes[8..12]);
+    #[tokio::test]
+    async fn test_replayed_initiation_dropped() {
+        let server_key = SecretKey::generate();
+        let server_public_key = server_key.public_key();
+        let client_key = SecretKey::generate();
+        let mut service = VpnService::with_listener(
+            MemoryListener::bind("vpn-service-test-replay").unwrap(),
+            server_key,
+            &[client_key.public_key()],
+            None,
+        )
+        .unwrap();
+        service.start().unwrap();
 
-        Ok(Self {
-            mtu: u32::from_be_bytes(mtu_bytes) as usize,
-            keepalive_interval: Duration::from_secs(u32::from_be_bytes(keepalive_bytes) as u64),
-            reconnect_attempts: u32::from_be_bytes(reconnect_bytes),
-        })
+        let mut handshake = Handshake::new_initiator(
+            KeyExchange::from_private_key(&client_key),
+            server_public_key,
+        );
+        let offer = HandshakeOffer {
+            min_version: MIN_PROTOCOL_VERSION,
+            max_version: PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+            cipher_suites: CipherSuite::preferred(),
+            key_exchange_modes: vec![KeyExchangeMode::X25519],
+            kem_encapsulation_key: None,
+        };
+        let initiation = PacketHeader::handshake(MessageType::HandshakeInitiation, 0).frame(
+            &handshake
+                .write_initiation(&offer.to_bytes().unwrap())
+                .unwrap(),
+        );
+
+        let client = MemoryTransport::connect("vpn-service-test-replay")
+            .await
+            .unwrap();
+        client.send_frame(&initiation).await.unwrap();
+        let response = client.receive_frame().await.unwrap();
+        let header = PacketHeader::from_bytes(&response).unwrap();
+        assert_eq!(header.message_type, MessageType::HandshakeResponse);
+
+        // Someone who recorded the initiation gets no session out of it
+        let attacker = MemoryTransport::connect("vpn-service-test-replay")
+            .await
+            .unwrap();
+        attacker.send_frame(&initiation).await.unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), attacker.receive_frame())
+                .await
+                .is_err()
+        );
+        assert_eq!(service.session_ids(), vec![header.session_id]);
     }
 }
@@ -1,293 +1,771 @@
 use crate::{
+    crypto::{key_exchange::kem_encapsulate, CipherSuite, Handshake, KeyExchange, KeyExchangeMode},
     error::VpnError,
-    network::tcp_server::TcpServer,
-    protocol::{packet::VpnPacket, ControlType, PacketType, ProtocolHandler},
-    vpn_service::{RouteEntry, VpnConfig},
+    network::transport::{Links, Listener, Transport, IDLE_TIMEOUT},
+    protocol::{
+        negotiation::negotiate_version, packet::VpnPacket, Capabilities, Compression,
+        ConfigRequest, ControlPayload, ControlType, Disconnect, ErrorMessage, HandshakeOffer,
+        HandshakeRefusal, HandshakeReply, HandshakeSelection, MessageType, MtuProbe, MtuProbeAck,
+        MtuUpdate, PacketHeader, PacketType, Ping, Pong, ProtocolHandler, Rekey, RouteEntry,
+        RouteUpdate, RouteUpdateAck, VpnConfig, HEADER_LEN, MIN_MTU, PROTOCOL_VERSION,
+    },
+    vpn::session::{ConnectionInfo, Session},
+    vpn_service::HandshakeSettings,
 };
 
 use std::{
     collections::HashMap,
-    sync::{atomic::AtomicBool, atomic::Ordering, Arc, Mutex},
-    thread,
-    time::Duration,
-    vec,
+    sync::{Arc, Mutex},
 };
-
-pub struct VpnWorker {
-    server: Arc<Mutex<TcpServer>>,
-    routes: Arc<Mutex<HashMap<String, Vec<RouteEntry>>>>,
-    protocol_handler: Arc<Mutex<ProtocolHandler>>,
-    client_configs: Arc<Mutex<HashMap<String, VpnConfig>>>,
-    shutdown_flag: Arc<AtomicBool>,
+use tokio::{sync::watch, time::timeout};
+
+/// Serves the connections of one listener, each from a task of its own.
+pub struct VpnWorker<L: Listener> {
+    links: Arc<Links<L::Link>>,
+    routes: Arc<Mutex<HashMap<u32, Vec<RouteEntry>>>>,
+    sessions: Arc<Mutex<HashMap<u32, Session>>>,
+    client_configs: Arc<Mutex<HashMap<u32, VpnConfig>>>,
+    server_config: Arc<Mutex<VpnConfig>>,
+    handshake: Arc<HandshakeSettings>,
+    shutdown: watch::Receiver<bool>,
 }
 
-impl VpnWorker {
-    pub fn new(
-        server: Arc<Mutex<TcpServer>>,
-        routes: Arc<Mutex<HashMap<String, Vec<RouteEntry>>>>,
-        protocol_handler: Arc<Mutex<ProtocolHandler>>,
-        client_configs: Arc<Mutex<HashMap<String, VpnConfig>>>,
-        shutdown_flag: Arc<AtomicBool>,
-    ) -> Result<Self, VpnError> {
-        // Initialize TCP server
-
-        Ok(Self {
-            server,
-            protocol_handler,
+impl<L: Listener> VpnWorker<L> {
+    pub(crate) fn new(
+        links: Arc<Links<L::Link>>,
+        routes: Arc<Mutex<HashMap<u32, Vec<RouteEntry>>>>,
+        sessions: Arc<Mutex<HashMap<u32, Session>>>,
+        client_configs: Arc<Mutex<HashMap<u32, VpnConfig>>>,
+        server_config: Arc<Mutex<VpnConfig>>,
+        handshake: Arc<HandshakeSettings>,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        Self {
+            links,
+            sessions,
             routes,
             client_configs,
-            shutdown_flag,
-        })
+            server_config,
+            handshake,
+            shutdown,
+        }
     }
-    pub fn main_loop(&self) -> Result<(), VpnError> {
-        while !self.shutdown_flag.load(Ordering::Relaxed) {
-            let mut client_ids: Vec<String>;
-            {
-                client_ids = self.server.lock().expect("Server in use").get_client_ids();
-            }
-            for client_id in client_ids {
-                match self.handle_client_packet(&client_id) {
-                    Ok(_) => continue,
-                    Err(VpnError::ClientNotFound) => {
-                        self.server
-                            .lock()
-                            .expect("Server in use")
-                            .remove_client(&client_id);
+
+    /// Handles the frames arriving on `link` until it hangs up, goes quiet,
+    /// gets dropped or the service shuts down. A frame being handled is
+    /// always finished first.
+    pub async fn serve(self: Arc<Self>, link: Arc<L::Link>) {
+        let connection_id = link.peer();
+        let closed = self.links.insert(link.clone());
+        let mut shutdown = self.shutdown.clone();
+
+        loop {
+            let frame = tokio::select! {
+                frame = timeout(IDLE_TIMEOUT, link.receive_frame()) => frame,
+                _ = closed.notified() => break,
+                _ = shutdown.wait_for(|&stop| stop) => break,
+            };
+            let frame = match frame {
+                Ok(Ok(frame)) => frame,
+                Ok(Err(e)) => {
+                    println!("Connection {} closed: {:?}", connection_id, e);
+                    break;
+                }
+                Err(_) => {
+                    println!("Removing stale client: {}", connection_id);
+                    break;
+                }
+            };
+
+            match self.handle_client_packet(&connection_id, &frame).await {
+                Ok(_) => continue,
+                Err(VpnError::ClientNotFound) => break,
+                Err(e) => {
+                    eprintln!("Error handling connection {}: {:?}", connection_id, e);
+                    // Decide whether to drop the connection based on error type,
+                    // its session stays until it goes stale so the client can return
+                    if Self::is_fatal_error(&e) {
+                        self.send_error(&connection_id, &e).await;
+                        break;
                     }
-                    Err(e) => {
-                        eprintln!("Error handling client {}: {:?}", client_id, e);
-                        // Decide whether to remove client based on error type
-                        if Self::is_fatal_error(&e) {
-                            self.server
-                                .lock()
-                                .expect("Server in use")
-                                .remove_client(&client_id);
-                        }
+                    // Nothing on it authenticated yet, so it was most likely
+                    // opened by a forgery and only holds a slot
+                    if matches!(e, VpnError::Encryption(_))
+                        && self.session_on(&connection_id).is_none()
+                    {
+                        break;
                     }
                 }
             }
-            thread::sleep(Duration::from_millis(10));
         }
 
-        Ok(())
+        // The only place a link is closed, whoever dropped it
+        self.links.remove(&link);
+        if let Err(e) = link.close().await {
+            eprintln!("Failed to close connection {}: {:?}", connection_id, e);
+        }
     }
 
     fn is_fatal_error(error: &VpnError) -> bool {
-        matches!(error, VpnError::ClientNotFound | VpnError::Protocol(_))
+        matches!(
+            error,
+            VpnError::ClientNotFound | VpnError::Protocol(_) | VpnError::KeyExchange(_)
+        )
     }
 
-    fn handle_client_packet(&self, client_id: &str) -> Result<(), VpnError> {
-        let encrypted_packet = self
-            .server
+    // Tells the session on the connection, if it has one, why it is dropped
+    async fn send_error(&self, connection_id: &str, error: &VpnError) {
+        let Some(session_id) = self.session_on(connection_id) else {
+            return;
+        };
+        if let Err(e) = self
+            .reply(session_id, None, &ErrorMessage::from(error))
+            .await
+        {
+            eprintln!("Failed to send error to session {}: {:?}", session_id, e);
+        }
+    }
+
+    // The session last heard from on the connection
+    fn session_on(&self, connection_id: &str) -> Option<u32> {
+        self.sessions
             .lock()
-            .expect("Server_in_use")
-            .service_read_packet(client_id)?;
+            .expect("Sessions in use")
+            .iter()
+            .find(|(_, session)| session.connection_id == connection_id)
+            .map(|(session_id, _)| *session_id)
+    }
 
-        if encrypted_packet.len() < 4 {
-            return Ok(());
+    // Sessions outlive their connections so clients can reconnect, until they go quiet
+    pub(crate) fn expire_sessions(&self) {
+        let stale: Vec<u32> = self
+            .sessions
+            .lock()
+            .expect("Sessions in use")
+            .iter()
+            .filter(|(_, session)| session.is_stale())
+            .map(|(session_id, _)| *session_id)
+            .collect();
+        for session_id in stale {
+            println!("Removing stale session: {}", session_id);
+            self.remove_session(session_id);
+        }
+    }
+
+    /// Drops the session with its connection, keys, routes and config.
+    pub(crate) fn remove_session(&self, session_id: u32) {
+        let session = self
+            .sessions
+            .lock()
+            .expect("Sessions in use")
+            .remove(&session_id);
+        if let Some(session) = session {
+            self.links.close(&session.connection_id);
         }
+        self.routes
+            .lock()
+            .expect("Routes in use")
+            .remove(&session_id);
+        self.client_configs
+            .lock()
+            .expect("Configs in use")
+            .remove(&session_id);
+    }
 
-        println!("Received packet from client {}", client_id);
+    fn session(&self, session_id: u32) -> Result<Session, VpnError> {
+        self.sessions
+            .lock()
+            .expect("Sessions in use")
+            .get(&session_id)
+            .cloned()
+            .ok_or(VpnError::ClientNotFound)
+    }
 
-        // Process the packet
-        let packet = self
-            .protocol_handler
+    fn update_info(&self, session_id: u32, update: impl FnOnce(&mut ConnectionInfo)) {
+        if let Some(session) = self
+            .sessions
             .lock()
-            .expect("Protocol in use")
-            .unpack(&encrypted_packet)?;
+            .expect("Sessions in use")
+            .get_mut(&session_id)
+        {
+            update(&mut session.info);
+        }
+    }
+
+    async fn send_packet(&self, session_id: u32, packet: VpnPacket) -> Result<(), VpnError> {
+        let session = self.session(session_id)?;
+        for encrypted in session.protocol_handler.pack_fragments(packet)? {
+            self.links
+                .send_frame(&session.connection_id, &encrypted)
+                .await?;
+
+            self.update_info(session_id, |info| info.record_sent(encrypted.len() as u64));
+        }
+        Ok(())
+    }
+
+    // Answers a request, echoing its transaction id so the client can match
+    // the two
+    pub(crate) async fn reply<T: ControlPayload>(
+        &self,
+        session_id: u32,
+        transaction_id: Option<u32>,
+        message: &T,
+    ) -> Result<(), VpnError> {
+        let mut packet = VpnPacket::from_control(message)?;
+        if let Some(transaction_id) = transaction_id {
+            packet = packet.with_transaction_id(transaction_id)?;
+        }
+        self.send_packet(session_id, packet).await
+    }
+
+    // Only called once a packet authenticated, so a forged header can't steal a session;
+    // sessions that didn't negotiate roaming stay on their first connection
+    fn update_endpoint(&self, session_id: u32, connection_id: &str) {
+        if let Some(session) = self
+            .sessions
+            .lock()
+            .expect("Sessions in use")
+            .get_mut(&session_id)
+        {
+            if session.connection_id != connection_id
+                && session
+                    .protocol_handler
+                    .capabilities()
+                    .contains(Capabilities::ROAMING)
+            {
+                println!(
+                    "Session {} moved from {} to {}",
+                    session.session_id(),
+                    session.connection_id,
+                    connection_id
+                );
+                session.connection_id = connection_id.to_string();
+            }
+        }
+    }
+
+    async fn handle_client_packet(
+        &self,
+        connection_id: &str,
+        encrypted_packet: &[u8],
+    ) -> Result<(), VpnError> {
+        if encrypted_packet.len() < HEADER_LEN {
+            return Ok(());
+        }
+
+        // The cleartext header is enough to route or drop the packet. Nothing
+        // vouches for it yet, so a bad one is dropped rather than held against
+        // the link, which a spoofed datagram could otherwise tear down.
+        let header = match PacketHeader::from_bytes(encrypted_packet) {
+            Ok(header) => header,
+            Err(e) => {
+                eprintln!(
+                    "Dropping unreadable packet from connection {}: {:?}",
+                    connection_id, e
+                );
+                return Ok(());
+            }
+        };
+        match header.message_type {
+            MessageType::HandshakeInitiation => {
+                return self
+                    .handle_key_exchange(connection_id, &encrypted_packet[HEADER_LEN..])
+                    .await
+            }
+            MessageType::HandshakeResponse => {
+                eprintln!(
+                    "Dropping unexpected handshake response from connection {}",
+                    connection_id
+                );
+                return Ok(());
+            }
+            MessageType::Transport => {}
+        }
+
+        // The session id in the header, not the connection, identifies the client
+        let session_id = header.session_id;
+        let session = match self.session(session_id) {
+            Ok(session) => session,
+            Err(_) => {
+                println!(
+                    "Dropping packet for unknown session {} from connection {}",
+                    session_id, connection_id
+                );
+                return Ok(());
+            }
+        };
+        if header.version != session.protocol_handler.version() {
+            eprintln!(
+                "Dropping protocol version {} packet for session {} from connection {}",
+                header.version, session_id, connection_id
+            );
+            return Ok(());
+        }
+
+        // Process the packet, dropping anything already seen
+        let packet = match session.protocol_handler.receive(encrypted_packet) {
+            Ok(packet) => packet,
+            Err(e @ VpnError::ReplayedPacket(_)) => {
+                self.update_info(session_id, |info| info.record_replay());
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+        self.update_endpoint(session_id, connection_id);
+        self.update_info(session_id, |info| {
+            info.record_received(encrypted_packet.len() as u64)
+        });
+
+        // A fragment only counts once the rest of its packet arrives
+        let Some(packet) = packet else {
+            return Ok(());
+        };
 
         // Handle different packet types
         match packet.packet_type {
-            PacketType::Data => self.handle_data_packet(client_id, packet),
-            PacketType::Keepalive => self.handle_keepalive(client_id),
-            PacketType::Control => self.handle_control_packet(client_id, packet),
+            PacketType::Data => self.handle_data_packet(session_id, packet).await,
+            PacketType::Keepalive => self.handle_keepalive(session_id),
+            PacketType::Control => match self.handle_control_packet(session_id, packet).await {
+                // An authenticated peer gets away with a malformed or unknown
+                // request, which is dropped unanswered
+                Err(VpnError::Protocol(reason)) => {
+                    eprintln!(
+                        "Dropping control packet from session {}: {}",
+                        session_id, reason
+                    );
+                    Ok(())
+                }
+                result => result,
+            },
+            // The protocol handler undoes these, a peer must not get one past it
+            PacketType::Fragment | PacketType::Compressed | PacketType::Padded => Err(
+                VpnError::Protocol(format!("Unexpected {:?} packet", packet.packet_type)),
+            ),
         }
     }
 
-    fn handle_keepalive(&self, client_id: &str) -> Result<(), VpnError> {
-        // Update client's last seen timestamp
-        self.server
+    async fn handle_key_exchange(
+        &self,
+        connection_id: &str,
+        initiation: &[u8],
+    ) -> Result<(), VpnError> {
+        // The handshake messages are the only packets sent in the clear
+        let mut handshake =
+            Handshake::new_responder(KeyExchange::from_private_key(&self.handshake.private_key));
+        let offer = HandshakeOffer::from_bytes(&handshake.read_initiation(initiation)?)?;
+
+        // Only clients on the allow list may establish a session
+        let client_public = handshake
+            .remote_static()
+            .ok_or_else(|| VpnError::KeyExchange("Missing client static key".into()))?;
+        let preshared_key = self
+            .handshake
+            .allowed_peers
+            .get(&client_public)
+            .ok_or_else(|| {
+                VpnError::KeyExchange(format!(
+                    "Connection {} presented an unknown static key",
+                    connection_id
+                ))
+            })?;
+        if let Some(preshared_key) = preshared_key {
+            handshake.set_preshared_key(preshared_key);
+        }
+
+        // A recorded initiation replayed by anyone else must not start a session
+        let timestamp = handshake
+            .remote_timestamp()
+            .ok_or_else(|| VpnError::KeyExchange("Missing initiation timestamp".into()))?;
+        {
+            let mut initiations = self
+                .handshake
+                .initiations
+                .lock()
+                .expect("Initiations in use");
+            if initiations
+                .get(&client_public)
+                .is_some_and(|newest| timestamp <= *newest)
+            {
+                // Not fatal, a late copy must not cost the connection its session
+                eprintln!(
+                    "Dropping replayed handshake initiation from connection {}",
+                    connection_id
+                );
+                return Ok(());
+            }
+            initiations.insert(client_public, timestamp);
+        }
+
+        // Honour the client's preference among the suites and modes we allow
+        let version = negotiate_version(
+            offer.min_version,
+            offer.max_version,
+            self.handshake.min_protocol_version,
+        );
+        let suite = CipherSuite::negotiate(&offer.cipher_suites, &self.handshake.cipher_suites);
+        let mode = KeyExchangeMode::negotiate(
+            &offer.key_exchange_modes,
+            &self.handshake.key_exchange_modes,
+        );
+        let (version, suite, mode) = match (version, suite, mode) {
+            (Some(version), Some(suite), Some(mode)) => (version, suite, mode),
+            (None, _, _) => {
+                let reason = format!(
+                    "Unsupported protocol versions {}-{}",
+                    offer.min_version, offer.max_version
+                );
+                return self
+                    .refuse_handshake(connection_id, handshake, reason)
+                    .await;
+            }
+            (_, None, _) => {
+                let reason = "No common cipher suite".to_string();
+                return self
+                    .refuse_handshake(connection_id, handshake, reason)
+                    .await;
+            }
+            (_, _, None) => {
+                let reason = "No common key exchange mode".to_string();
+                return self
+                    .refuse_handshake(connection_id, handshake, reason)
+                    .await;
+            }
+        };
+        let capabilities = offer.capabilities.intersection(self.handshake.capabilities);
+
+        let (kem_ciphertext, kem_secret) = match mode {
+            KeyExchangeMode::HybridMlKem768 => {
+                let encapsulation_key =
+                    offer.kem_encapsulation_key.as_deref().ok_or_else(|| {
+                        VpnError::KeyExchange("Hybrid mode offered without an ML-KEM key".into())
+                    })?;
+                let (ciphertext, secret) = kem_encapsulate(encapsulation_key)?;
+                (Some(ciphertext), Some(secret))
+            }
+            KeyExchangeMode::X25519 => (None, None),
+        };
+
+        // The session id is sent encrypted too, so the client can trust the header
+        let session_id = self.allocate_session_id();
+        let selection = HandshakeSelection {
+            version,
+            capabilities,
+            cipher_suite: suite,
+            session_id,
+            key_exchange_mode: mode,
+            kem_ciphertext,
+        };
+
+        let response = PacketHeader::handshake(MessageType::HandshakeResponse, session_id)
+            .frame(&handshake.write_response(&HandshakeReply::Accepted(selection).to_bytes()?)?);
+        if let Some(kem_secret) = kem_secret {
+            handshake.mix_kem_secret(&kem_secret)?;
+        }
+        let session_keys = handshake.into_session_keys()?;
+        self.links.send_frame(connection_id, &response).await?;
+
+        let protocol_handler = ProtocolHandler::from_session_keys(suite, session_id, &session_keys)
+            .with_negotiated(version, capabilities);
+        protocol_handler.set_padding_policy(self.handshake.padding.clone())?;
+        self.sessions
             .lock()
-            .expect("Server in use")
-            .update_client_timestamp(client_id)?;
+            .expect("Sessions in use")
+            .insert(session_id, Session::new(protocol_handler, connection_id));
+
+        println!(
+            "Established session {} for connection {} using version {}, {:?}, {:?} and compression {:?}",
+            session_id,
+            connection_id,
+            version,
+            suite,
+            mode,
+            Compression::negotiated(capabilities)
+        );
         Ok(())
     }
 
-    fn handle_disconnect(&self, client_id: &str) -> Result<(), VpnError> {
-        println!("Client {} requesting disconnect", client_id);
+    // Answers in the authenticated handshake so the client can tell a refusal
+    // from a network failure, then drops the connection
+    async fn refuse_handshake(
+        &self,
+        connection_id: &str,
+        mut handshake: Handshake,
+        reason: String,
+    ) -> Result<(), VpnError> {
+        let refusal = HandshakeReply::Refused(HandshakeRefusal {
+            min_version: self.handshake.min_protocol_version,
+            max_version: PROTOCOL_VERSION,
+            reason: reason.clone(),
+        });
+        let response = PacketHeader::handshake(MessageType::HandshakeResponse, 0)
+            .frame(&handshake.write_response(&refusal.to_bytes()?)?);
+        self.links.send_frame(connection_id, &response).await?;
+
+        Err(VpnError::KeyExchange(format!(
+            "Refused handshake from connection {}: {}",
+            connection_id, reason
+        )))
+    }
 
-        // Send disconnect acknowledgment
-        let disconnect_ack = VpnPacket::new_control(ControlType::Disconnect);
-        let encrypted_ack = self
-            .protocol_handler
-            .lock()
-            .expect("Protocol in use")
-            .pack(disconnect_ack)?;
-        self.server
-            .lock()
-            .expect("Server in use")
-            .write_packet(client_id, &encrypted_ack)?;
+    // Random, non-zero and unused; zero marks "no session yet" in headers
+    fn allocate_session_id(&self) -> u32 {
+        let sessions = self.sessions.lock().expect("Sessions in use");
+        loop {
+            let session_id: u32 = rand::random();
+            if session_id != 0 && !sessions.contains_key(&session_id) {
+                return session_id;
+            }
+        }
+    }
 
-        // Remove client from server
-        self.server
-            .lock()
-            .expect("Server in use")
-            .remove_client(client_id);
+    fn handle_keepalive(&self, _session_id: u32) -> Result<(), VpnError> {
+        // Receiving it already refreshed the session's last seen timestamp
+        Ok(())
+    }
+
+    async fn handle_disconnect(
+        &self,
+        session_id: u32,
+        transaction_id: Option<u32>,
+    ) -> Result<(), VpnError> {
+        println!("Session {} requesting disconnect", session_id);
 
-        // Clean up client routes
-        let mut routes = self.routes.lock().unwrap();
-        routes.remove(client_id);
+        // Send disconnect acknowledgment
+        self.reply(session_id, transaction_id, &Disconnect).await?;
 
-        // Clean up client config
-        let mut configs = self.client_configs.lock().unwrap();
-        configs.remove(client_id);
+        // Drop the connection, session keys, routes and config
+        self.remove_session(session_id);
 
-        println!("Client {} disconnected", client_id);
+        println!("Session {} disconnected", session_id);
         Ok(())
     }
 
-    fn handle_control_packet(&self, client_id: &str, packet: VpnPacket) -> Result<(), VpnError> {
+    async fn handle_control_packet(
+        &self,
+        session_id: u32,
+        packet: VpnPacket,
+    ) -> Result<(), VpnError> {
         // Handle control messages (configuration, routing updates, etc.)
+        let transaction_id = packet.transaction_id()?;
         match packet.control_type() {
-            Some(c_type) => match c_type {
-                ControlType::ConfigRequest => self.send_config(client_id),
-                ControlType::RouteUpdate => self.update_routes(client_id, &packet),
-                ControlType::Disconnect => self.handle_disconnect(client_id),
-                _ => Err(VpnError::Protocol("Unknown control packet".into())),
-            },
+            Some(ControlType::ConfigRequest) => {
+                packet.to_control::<ConfigRequest>()?;
+                self.send_config(session_id, transaction_id).await
+            }
+            Some(ControlType::RouteUpdate) => {
+                self.update_routes(session_id, transaction_id, packet.to_control()?)
+                    .await
+            }
+            Some(ControlType::Disconnect) => {
+                packet.to_control::<Disconnect>()?;
+                self.handle_disconnect(session_id, transaction_id).await
+            }
+            Some(ControlType::Rekey) => {
+                self.handle_rekey(session_id, transaction_id, packet.to_control()?)
+                    .await
+            }
+            Some(ControlType::MtuProbe) => {
+                self.handle_mtu_probe(session_id, transaction_id, packet.to_control()?)
+                    .await
+            }
+            Some(ControlType::MtuUpdate) => {
+                self.handle_mtu_update(session_id, transaction_id, packet.to_control()?)
+                    .await
+            }
+            Some(ControlType::Ping) => {
+                self.handle_ping(session_id, transaction_id, packet.to_control()?)
+                    .await
+            }
+            Some(ControlType::Pong) => self.handle_pong(session_id, packet.to_control()?),
             _ => Err(VpnError::Protocol("Unknown control packet".into())),
         }
     }
 
-    fn handle_data_packet(&self, client_id: &str, packet: VpnPacket) -> Result<(), VpnError> {
-        // Process and route the data packet
-        let response_packet = self.process_data_packet(packet)?;
-
-        // Send response back to client
-        let encrypted_response = self
+    async fn handle_rekey(
+        &self,
+        session_id: u32,
+        transaction_id: Option<u32>,
+        request: Rekey,
+    ) -> Result<(), VpnError> {
+        let session = self.session(session_id)?;
+        if !session
             .protocol_handler
-            .lock()
-            .expect("Protocol in use")
-            .pack(response_packet)?;
-        self.server
-            .lock()
-            .expect("Server in use")
-            .write_packet(client_id, &encrypted_response)
-    }
+            .capabilities()
+            .contains(Capabilities::REKEY)
+        {
+            return Err(VpnError::Protocol(format!(
+                "Session {} did not negotiate rekeying",
+                session_id
+            )));
+        }
 
-    fn update_routes(&self, client_id: &str, packet: &VpnPacket) -> Result<(), VpnError> {
-        // Extract route updates from payload
-        let route_updates = self.parse_route_updates(&packet.payload)?;
+        // The reply was lost, the keys behind it are still set aside
+        if let Some((client_key, response)) = &session.last_rekey {
+            if *client_key == request.public_key {
+                return self.reply(session_id, transaction_id, response).await;
+            }
+        }
 
-        // Update routing table for this client
-        let mut routes = self.routes.lock().unwrap();
-        routes.insert(client_id.to_string(), route_updates.clone());
+        let ephemeral = KeyExchange::new();
+        let shared_secret = ephemeral.diffie_hellman(&request.public_key)?;
 
-        // Create acknowledgment packet
-        let mut ack_packet = VpnPacket::new_control(ControlType::RouteUpdate);
-        ack_packet.set_payload(vec![1]); // Simple ACK
+        // Reply under the old keys, which stay in use until the client sends
+        // under the new ones
+        let response = Rekey {
+            public_key: ephemeral.public_key_bytes(),
+        };
+        session.protocol_handler.rekey(&shared_secret, false)?;
+        if let Some(session) = self
+            .sessions
+            .lock()
+            .expect("Sessions in use")
+            .get_mut(&session_id)
+        {
+            session.last_rekey = Some((request.public_key, response));
+        }
+        self.reply(session_id, transaction_id, &response).await?;
 
-        // Send acknowledgment
-        let encrypted_ack = self
+        println!("Rekeyed session {}", session_id);
+        Ok(())
+    }
+
+    async fn handle_mtu_probe(
+        &self,
+        session_id: u32,
+        transaction_id: Option<u32>,
+        probe: MtuProbe,
+    ) -> Result<(), VpnError> {
+        // Name the probe by the size it claims, the padding is irrelevant
+        let ack = MtuProbeAck {
+            frame_len: probe.frame_len,
+        };
+        self.reply(session_id, transaction_id, &ack).await
+    }
+
+    async fn handle_mtu_update(
+        &self,
+        session_id: u32,
+        transaction_id: Option<u32>,
+        update: MtuUpdate,
+    ) -> Result<(), VpnError> {
+        let session = self.session(session_id)?;
+        if !session
             .protocol_handler
+            .capabilities()
+            .contains(Capabilities::PATH_MTU_DISCOVERY)
+        {
+            return Err(VpnError::Protocol(format!(
+                "Session {} did not negotiate path MTU discovery",
+                session_id
+            )));
+        }
+        // Never above what the config announced, the probes only search below
+        // it, nor above what the transport carries
+        let announced = self
+            .client_configs
             .lock()
-            .expect("Protocol in use")
-            .pack(ack_packet)?;
-        self.server
-            .lock()
-            .expect("Server in use")
-            .write_packet(client_id, &encrypted_ack)?;
-
+            .expect("Configs in use")
+            .get(&session_id)
+            .map(|config| config.mtu);
+        let announced = announced
+            .unwrap_or_else(|| self.server_config.lock().expect("Config in use").mtu)
+            .min(L::MAX_FRAME_LEN);
+        let mtu = (update.mtu as usize).clamp(MIN_MTU, announced);
+        session.protocol_handler.set_mtu(mtu)?;
+        self.reply(session_id, transaction_id, &MtuUpdate { mtu: mtu as u32 })
+            .await?;
+
+        println!("Path MTU of session {} is {}", session_id, mtu);
         Ok(())
     }
 
-    // Helper function to parse route updates from binary data
-    fn parse_route_updates(&self, payload: &[u8]) -> Result<Vec<RouteEntry>, VpnError> {
-        if payload.len() % 16 != 0 {
-            return Err(VpnError::Protocol(
-                "Invalid route update payload length".into(),
-            ));
+    async fn handle_ping(
+        &self,
+        session_id: u32,
+        transaction_id: Option<u32>,
+        ping: Ping,
+    ) -> Result<(), VpnError> {
+        let session = self.session(session_id)?;
+        if !session
+            .protocol_handler
+            .capabilities()
+            .contains(Capabilities::PING)
+        {
+            return Err(VpnError::Protocol(format!(
+                "Session {} did not negotiate pings",
+                session_id
+            )));
         }
+        self.reply(session_id, transaction_id, &ping.pong()).await
+    }
 
-        let mut routes = Vec::new();
-        let mut offset = 0;
+    // Answers a ping `VpnService::ping` sent
+    fn handle_pong(&self, session_id: u32, pong: Pong) -> Result<(), VpnError> {
+        self.update_info(session_id, |info| {
+            if info.record_pong(&pong).is_none() {
+                eprintln!(
+                    "Session {} answered ping {}, already lost or never sent",
+                    session_id, pong.sequence
+                );
+            }
+        });
+        Ok(())
+    }
 
-        while offset < payload.len() {
-            let mut network = [0u8; 4];
-            let mut mask = [0u8; 4];
-            let mut next_hop = [0u8; 4];
-            let mut metric_bytes = [0u8; 4];
+    async fn handle_data_packet(&self, session_id: u32, packet: VpnPacket) -> Result<(), VpnError> {
+        // Process and route the data packet
+        let response_packet = self.process_data_packet(packet)?;
 
-            network.copy_from_slice(&payload[offset..offset + 4]);
-            mask.copy_from_slice(&payload[offset + 4..offset + 8]);
-            next_hop.copy_from_slice(&payload[offset + 8..offset + 12]);
-            metric_bytes.copy_from_slice(&payload[offset + 12..offset + 16]);
+        // Send response back to client
+        self.send_packet(session_id, response_packet).await
+    }
 
-            routes.push(RouteEntry {
-                target_network: network,
-                network_mask: mask,
-                next_hop,
-                metric: u32::from_be_bytes(metric_bytes),
-            });
+    async fn update_routes(
+        &self,
+        session_id: u32,
+        transaction_id: Option<u32>,
+        update: RouteUpdate,
+    ) -> Result<(), VpnError> {
+        // Update routing table for this session
+        self.routes
+            .lock()
+            .unwrap()
+            .insert(session_id, update.routes);
 
-            offset += 16;
-        }
+        // Send acknowledgment
+        self.reply(session_id, transaction_id, &RouteUpdateAck)
+            .await?;
 
-        Ok(routes)
+        Ok(())
     }
 
-    fn send_config(&self, client_id: &str) -> Result<(), VpnError> {
-        // Create default config if none exists
+    async fn send_config(
+        &self,
+        session_id: u32,
+        transaction_id: Option<u32>,
+    ) -> Result<(), VpnError> {
+        // Start from the server's config if the session has none yet
         let config = {
             let mut configs = self.client_configs.lock().unwrap();
             configs
-                .entry(client_id.to_string())
-                .or_insert_with(|| VpnConfig {
-                    mtu: 1500,
-                    keepalive_interval: Duration::from_secs(30),
-                    reconnect_attempts: 3,
-                })
+                .entry(session_id)
+                .or_insert_with(|| self.server_config.lock().expect("Config in use").clone())
                 .clone()
         };
 
-        // Serialize config
-        let config_data = self.serialize_config(&config)?;
-
-        // Create config response packet
-        let mut config_packet = VpnPacket::new_control(ControlType::ConfigResponse);
-        config_packet.set_payload(config_data);
+        // Both sides send frames no larger than the MTU announced here
+        self.session(session_id)?
+            .protocol_handler
+            .set_mtu(config.mtu)?;
 
         // Send config
-        let encrypted_config = self
-            .protocol_handler
-            .lock()
-            .expect("Protocol in use")
-            .pack(config_packet)?;
-        self.server
-            .lock()
-            .expect("Server in use")
-            .write_packet(client_id, &encrypted_config)?;
+        self.reply(session_id, transaction_id, &config).await?;
 
-        println!("Sent config to client {}", client_id);
+        println!("Sent config to session {}", session_id);
         Ok(())
     }
 
-    // Helper function to serialize config to binary data
-    fn serialize_config(&self, config: &VpnConfig) -> Result<Vec<u8>, VpnError> {
-        let mut data = Vec::new();
-
-        // MTU (4 bytes)
-        data.extend_from_slice(&(config.mtu as u32).to_be_bytes());
-
-        // Keepalive interval in seconds (4 bytes)
-        data.extend_from_slice(&(config.keepalive_interval.as_secs() as u32).to_be_bytes());
-
-        // Reconnect attempts (4 bytes)
-        data.extend_from_slice(&config.reconnect_attempts.to_be_bytes());
-
-        Ok(data)
-    }
-
     fn process_data_packet(&self, packet: VpnPacket) -> Result<VpnPacket, VpnError> {
         // Here you would implement routing logic
         // For now, we'll just echo back